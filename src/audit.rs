@@ -0,0 +1,123 @@
+//! Append-only audit log of secret access.
+//!
+//! When `ENVCHAIN_AUDIT_LOG` is set, every exec appends one line recording
+//! the time, the backend, the namespaces, the injected key names and the
+//! command name.  Fields are written as quoted strings, so a name containing
+//! spaces, commas or newlines cannot split or forge a record.
+//! Secret values are never written.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::time::SystemTime;
+
+/// Append an exec record to the audit log, if one is configured.
+pub fn record_exec(
+    backend: &str,
+    namespaces: &[&str],
+    keys: &[String],
+    command: &str,
+) -> Result<(), String> {
+    let path = env::var_os("ENVCHAIN_AUDIT_LOG");
+    append_exec(path.as_deref(), backend, namespaces, keys, command)
+}
+
+fn append_exec(
+    path: Option<&OsStr>,
+    backend: &str,
+    namespaces: &[&str],
+    keys: &[String],
+    command: &str,
+) -> Result<(), String> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+
+    let line = format!(
+        "{} exec backend={:?} namespaces={:?} keys={:?} command={:?}\n",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        backend,
+        namespaces,
+        keys,
+        command
+    );
+
+    let mut options = fs::OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("Failed to open audit log: {e}"))?;
+
+    // Hold an exclusive lock so concurrent envchain processes never
+    // interleave partial lines.  The lock is released when `file` is dropped.
+    file.lock()
+        .map_err(|e| format!("Failed to lock audit log: {e}"))?;
+    file.write_all(line.as_bytes())
+        .map_err(|e| format!("Failed to write audit log: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_written_without_a_log_path() {
+        let dir = tempfile::tempdir().unwrap();
+        append_exec(None, "age", &["aws"], &["KEY".to_string()], "ls").unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn records_are_appended_quoted_and_without_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.log");
+        fs::write(&log, "earlier record\n").unwrap();
+        let keys = ["AWS_SECRET_ACCESS_KEY".to_string()];
+        append_exec(Some(log.as_os_str()), "age", &["aws"], &keys, "aws").unwrap();
+        append_exec(
+            Some(log.as_os_str()),
+            "age",
+            &["my ns", "a,b"],
+            &keys,
+            "x\n2000-01-01T00:00:00Z exec backend=forged",
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3, "{content}");
+        assert_eq!(lines[0], "earlier record");
+        assert!(
+            lines[1].ends_with(
+                r#" exec backend="age" namespaces=["aws"] keys=["AWS_SECRET_ACCESS_KEY"] command="aws""#
+            ),
+            "{}",
+            lines[1]
+        );
+        assert!(
+            lines[2].ends_with(
+                r#" namespaces=["my ns", "a,b"] keys=["AWS_SECRET_ACCESS_KEY"] command="x\n2000-01-01T00:00:00Z exec backend=forged""#
+            ),
+            "{}",
+            lines[2]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_logs_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.log");
+        append_exec(Some(log.as_os_str()), "age", &["aws"], &[], "true").unwrap();
+        let mode = fs::metadata(&log).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
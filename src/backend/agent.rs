@@ -0,0 +1,799 @@
+//! Agent mode: keep one unlocked backend alive and serve it over a Unix
+//! socket, so repeated envchain invocations reuse the same session instead
+//! of unlocking the keyring (or decrypting the age file) every time.
+//!
+//! The agent is started with `envchain --agent` and clients select it with
+//! `--backend agent`.  The socket is created with mode 0600 inside a
+//! directory only its owner can enter: one the agent creates with mode 0700,
+//! or an existing one that is already private, so only the owning user can
+//! connect.  Requests and responses
+//! are newline-delimited JSON.
+//!
+//! Each connection is read on its own thread, so an idle client does not
+//! hold up the others, and the backend itself stays on the agent's main
+//! thread, which runs one request at a time.  A rotation is the one
+//! exchange of several messages: the agent answers `rotate_value` with the
+//! current value, the client replies with the new one (or an error), and
+//! only then does the agent save and send the final response.
+
+use super::{Backend, Capabilities, EnvKey, EnvValue, Namespace, RotateFn, SecretMetadata};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Capabilities,
+    ListNamespaces,
+    /// `for_each_namespace`, answered with the names in the backend's order
+    ForEachNamespace,
+    ListAll,
+    CountKeys,
+    ListKeys {
+        namespace: String,
+    },
+    ListSecrets {
+        namespace: String,
+    },
+    ListMetadata {
+        namespace: String,
+    },
+    SetSecret {
+        namespace: String,
+        key: String,
+        value: String,
+    },
+    SetSecrets {
+        namespace: String,
+        entries: Vec<(EnvKey, EnvValue)>,
+    },
+    /// Answered with `Current`, after which the client sends
+    /// `RotatedValue` or `RotateAbort`
+    RotateValue {
+        namespace: String,
+        key: String,
+    },
+    RotatedValue {
+        value: String,
+    },
+    RotateAbort {
+        error: String,
+    },
+    DeleteSecret {
+        namespace: String,
+        key: String,
+    },
+    DeleteSecrets {
+        namespace: String,
+        keys: Vec<String>,
+    },
+    DeleteNamespace {
+        namespace: String,
+    },
+    CloneNamespace {
+        src: String,
+        dst: String,
+    },
+    RenameNamespace {
+        src: String,
+        dst: String,
+    },
+    Lock,
+    Unlock,
+    IsLocked,
+    HealthCheck,
+    OutdatedItems,
+    UpgradeItems,
+    Flush,
+}
+
+/// Metadata timestamps as seconds since the Unix epoch.
+#[derive(Serialize, Deserialize)]
+struct WireMetadata {
+    created_at: Option<u64>,
+    updated_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Capabilities(Capabilities),
+    Namespaces(Vec<Namespace>),
+    Keys(Vec<EnvKey>),
+    Secrets(HashMap<EnvKey, EnvValue>),
+    All(HashMap<Namespace, HashMap<EnvKey, EnvValue>>),
+    Counts(HashMap<Namespace, usize>),
+    Metadata(HashMap<EnvKey, WireMetadata>),
+    Done,
+    /// Whether a set request wrote anything
+    Stored(bool),
+    /// Whether each entry of a `SetSecrets` request was written
+    StoredEach(Vec<bool>),
+    /// The value being rotated, for the client to compute a replacement
+    Current(String),
+    Deleted(usize),
+    /// The outdated items, as `namespace.key`
+    Outdated(Vec<String>),
+    /// How many items were upgraded
+    Items(usize),
+    /// Whether a lock or unlock request applied to the backend
+    Applied(bool),
+    Locked(Option<bool>),
+    Error(String),
+}
+
+fn to_secs(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+fn from_secs(secs: Option<u64>) -> Option<SystemTime> {
+    secs.map(|s| UNIX_EPOCH + Duration::from_secs(s))
+}
+
+/// Default socket location: `$XDG_RUNTIME_DIR/envchain/agent.sock`, falling
+/// back to the envchain config directory.
+pub fn default_socket_path() -> Result<PathBuf, String> {
+    let base = dirs::runtime_dir()
+        .or_else(dirs::config_dir)
+        .ok_or("Could not determine runtime directory")?;
+    Ok(base.join("envchain").join("agent.sock"))
+}
+
+fn handle(backend: &mut dyn Backend, request: Request) -> Response {
+    let result = match request {
+        Request::Capabilities => Ok(Response::Capabilities(backend.capabilities())),
+        Request::ListNamespaces => backend.list_namespaces().map(Response::Namespaces),
+        Request::ForEachNamespace => {
+            let mut namespaces = Vec::new();
+            backend
+                .for_each_namespace(&mut |namespace| {
+                    namespaces.push(namespace.to_string());
+                    Ok(())
+                })
+                .map(|()| Response::Namespaces(namespaces))
+        }
+        Request::ListAll => backend.list_all().map(Response::All),
+        Request::CountKeys => backend.count_keys().map(Response::Counts),
+        Request::ListKeys { namespace } => backend.list_keys(&namespace).map(Response::Keys),
+        Request::ListSecrets { namespace } => {
+            backend.list_secrets(&namespace).map(Response::Secrets)
+        }
+        Request::ListMetadata { namespace } => backend.list_metadata(&namespace).map(|m| {
+            Response::Metadata(
+                m.into_iter()
+                    .map(|(k, v)| {
+                        let wire = WireMetadata {
+                            created_at: to_secs(v.created_at),
+                            updated_at: to_secs(v.updated_at),
+                        };
+                        (k, wire)
+                    })
+                    .collect(),
+            )
+        }),
+        Request::SetSecret {
+            namespace,
+            key,
+            value,
+        } => {
+            let value = Zeroizing::new(value);
+            backend
+                .set_secret(&namespace, &key, &value)
+                .map(Response::Stored)
+        }
+        Request::SetSecrets { namespace, entries } => {
+            let entries: Vec<_> = entries
+                .into_iter()
+                .map(|(key, value)| (key, Zeroizing::new(value)))
+                .collect();
+            backend
+                .set_secrets(&namespace, &entries)
+                .map(Response::StoredEach)
+        }
+        Request::RotateValue { .. } => {
+            unreachable!("rotations are run by run_job")
+        }
+        Request::RotatedValue { value } => {
+            drop(Zeroizing::new(value));
+            Err("No rotation is in progress".to_string())
+        }
+        Request::RotateAbort { .. } => Err("No rotation is in progress".to_string()),
+        Request::DeleteSecret { namespace, key } => backend
+            .delete_secret(&namespace, &key)
+            .map(Response::Deleted),
+        Request::DeleteSecrets { namespace, keys } => backend
+            .delete_secrets(&namespace, &keys)
+            .map(Response::Deleted),
+        Request::DeleteNamespace { namespace } => {
+            backend.delete_namespace(&namespace).map(Response::Deleted)
+        }
+        Request::CloneNamespace { src, dst } => {
+            backend.clone_namespace(&src, &dst).map(|()| Response::Done)
+        }
+        Request::RenameNamespace { src, dst } => backend
+            .rename_namespace(&src, &dst)
+            .map(|()| Response::Done),
+        Request::Lock => backend.lock().map(Response::Applied),
+        Request::Unlock => backend.unlock().map(Response::Applied),
+        Request::IsLocked => backend.is_locked().map(Response::Locked),
+        Request::HealthCheck => backend.health_check().map(|()| Response::Done),
+        Request::OutdatedItems => backend.outdated_items().map(Response::Outdated),
+        Request::UpgradeItems => backend.upgrade_items().map(Response::Items),
+        Request::Flush => backend.flush().map(|()| Response::Done),
+    };
+    result.unwrap_or_else(Response::Error)
+}
+
+/// A request handed from a connection's thread to the main thread, which
+/// owns the backend.  `reply` carries the responses back; `follow_up`
+/// carries the client's answer to a `Current` response during a rotation.
+struct Job {
+    request: Request,
+    reply: mpsc::Sender<Response>,
+    follow_up: mpsc::Receiver<Request>,
+}
+
+/// Run one job on the backend and send its response.  A rotation sends the
+/// current value first and waits up to `timeout` for the client's answer.
+fn run_job(backend: &mut dyn Backend, job: Job, timeout: Duration) {
+    let Job {
+        request,
+        reply,
+        follow_up,
+    } = job;
+    let response = match request {
+        Request::RotateValue { namespace, key } => {
+            let rotate: RotateFn<'_> = Box::new(|current| {
+                reply
+                    .send(Response::Current(current.to_string()))
+                    .map_err(|_| "The client went away during the rotation".to_string())?;
+                match follow_up.recv_timeout(timeout) {
+                    Ok(Request::RotatedValue { value }) => Ok(value),
+                    Ok(Request::RotateAbort { error }) => Err(error),
+                    Ok(_) => Err("Unexpected request during a rotation".to_string()),
+                    Err(_) => Err("The client went away during the rotation".to_string()),
+                }
+            });
+            backend
+                .rotate_value(&namespace, &key, rotate)
+                .map(|()| Response::Done)
+                .unwrap_or_else(Response::Error)
+        }
+        request => handle(backend, request),
+    };
+    let _ = reply.send(response);
+}
+
+/// Read the next request line, or `None` once the client disconnects.
+fn read_request(reader: &mut impl BufRead) -> std::io::Result<Option<Result<Request, String>>> {
+    let mut line = Zeroizing::new(String::new());
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::from_str(&line).map_err(|e| format!("Invalid agent request: {e}")),
+    ))
+}
+
+/// Read one client's requests until it disconnects, or stays silent for
+/// `idle_timeout`, passing each to the main thread through `jobs`.
+fn serve_client(
+    stream: UnixStream,
+    idle_timeout: Duration,
+    jobs: mpsc::Sender<Job>,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut send = |response: &Response| {
+        let mut encoded = Zeroizing::new(serde_json::to_string(response)?);
+        encoded.push('\n');
+        writer.write_all(encoded.as_bytes())
+    };
+    while let Some(request) = read_request(&mut reader)? {
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                send(&Response::Error(e))?;
+                continue;
+            }
+        };
+        let (reply, replies) = mpsc::channel();
+        let (answer, follow_up) = mpsc::channel();
+        let job = Job {
+            request,
+            reply,
+            follow_up,
+        };
+        if jobs.send(job).is_err() {
+            // The agent is shutting down.
+            return Ok(());
+        }
+        // The main thread drops `reply` once the job is done.
+        for response in replies {
+            send(&response)?;
+            if let Response::Current(_) = response {
+                match read_request(&mut reader)? {
+                    Some(Ok(request)) => {
+                        let _ = answer.send(request);
+                    }
+                    // Dropping `answer` aborts the rotation.
+                    Some(Err(_)) | None => break,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create the socket's directory with mode 0700, or check that an existing
+/// one is owned by the current user and closed to everyone else.  An
+/// existing directory is never chmodded: it may be `$HOME` or `/tmp`.
+fn prepare_socket_dir(dir: &Path) -> Result<(), String> {
+    let metadata = match fs::metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .map_err(|e| format!("Failed to create {}: {e}", dir.display()));
+        }
+        Err(e) => return Err(format!("Failed to inspect {}: {e}", dir.display())),
+    };
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "Refusing to put the agent socket in {}: it must be a directory owned by you \
+             that other users cannot access (mode 0700).\n\
+             Choose a private directory with --agent-socket, e.g. $XDG_RUNTIME_DIR/envchain/agent.sock.",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+fn bind(socket_path: &Path) -> Result<UnixListener, String> {
+    let dir = match socket_path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Err("Could not determine agent socket directory".to_string()),
+    };
+    prepare_socket_dir(dir)?;
+
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(format!(
+                "An envchain agent is already listening on {}",
+                socket_path.display()
+            ));
+        }
+        // Stale socket left behind by an agent that did not exit cleanly.
+        fs::remove_file(socket_path).map_err(|e| format!("Failed to remove stale socket: {e}"))?;
+    }
+
+    let listener = UnixListener::bind(socket_path)
+        .map_err(|e| format!("Failed to bind {}: {e}", socket_path.display()))?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to set socket permissions: {e}"))?;
+    Ok(listener)
+}
+
+/// Run the agent until no client has connected for `idle_timeout`.
+pub fn serve(
+    mut backend: Box<dyn Backend>,
+    socket_path: &Path,
+    idle_timeout: Duration,
+) -> Result<(), String> {
+    let listener = bind(socket_path)?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("Failed to configure agent socket: {e}"))?;
+    eprintln!(
+        "envchain agent listening on {} (idle timeout {})",
+        socket_path.display(),
+        humantime::format_duration(idle_timeout)
+    );
+
+    let (jobs, pending) = mpsc::channel();
+    let mut last_activity = Instant::now();
+    let result = loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let jobs = jobs.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_client(stream, idle_timeout, jobs) {
+                        eprintln!("envchain agent: client error: {e}");
+                    }
+                });
+                last_activity = Instant::now();
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => break Err(format!("Agent accept failed: {e}")),
+        }
+        match pending.recv_timeout(ACCEPT_POLL_INTERVAL) {
+            Ok(job) => {
+                run_job(backend.as_mut(), job, idle_timeout);
+                last_activity = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if last_activity.elapsed() >= idle_timeout {
+                    eprintln!("envchain agent: idle timeout reached, exiting");
+                    break Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("`jobs` is still held"),
+        }
+    };
+
+    let _ = fs::remove_file(socket_path);
+    result.and(backend.flush())
+}
+
+/// Backend that forwards every operation to a running agent.
+pub struct AgentBackend {
+    stream: UnixStream,
+    /// Capabilities of the agent's backend
+    capabilities: Capabilities,
+}
+
+impl AgentBackend {
+    pub fn connect(socket_path: &Path) -> Result<Self, String> {
+        let stream = UnixStream::connect(socket_path).map_err(|e| {
+            format!(
+                "Failed to connect to envchain agent at {}: {e}\n\
+                 Start one with `envchain --agent`.",
+                socket_path.display()
+            )
+        })?;
+        let mut backend = Self {
+            stream,
+            capabilities: Capabilities::default(),
+        };
+        match backend.call(&Request::Capabilities)? {
+            Response::Capabilities(capabilities) => backend.capabilities = capabilities,
+            _ => return Err("Unexpected response from envchain agent".to_string()),
+        }
+        Ok(backend)
+    }
+
+    /// Send one request and wait for its response.
+    ///
+    /// Takes `&self` because the `Backend` read methods do; `&UnixStream`
+    /// implements `Read`/`Write`, so the stream itself needs no `&mut`.
+    /// The agent answers each request with exactly one line, so a fresh
+    /// reader per call never drops buffered data.
+    fn call(&self, request: &Request) -> Result<Response, String> {
+        let mut encoded = Zeroizing::new(
+            serde_json::to_string(request)
+                .map_err(|e| format!("Failed to encode agent request: {e}"))?,
+        );
+        encoded.push('\n');
+        (&self.stream)
+            .write_all(encoded.as_bytes())
+            .map_err(|e| format!("Failed to send request to agent: {e}"))?;
+
+        let mut line = Zeroizing::new(String::new());
+        let mut reader = BufReader::new(&self.stream);
+        reader
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read agent response: {e}"))?;
+        if line.is_empty() {
+            return Err("envchain agent closed the connection".to_string());
+        }
+        match serde_json::from_str(&line).map_err(|e| format!("Invalid agent response: {e}"))? {
+            Response::Error(e) => Err(e),
+            response => Ok(response),
+        }
+    }
+
+    fn call_applied(&self, request: &Request) -> Result<bool, String> {
+        match self.call(request)? {
+            Response::Applied(applied) => Ok(applied),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn call_done(&self, request: &Request) -> Result<(), String> {
+        match self.call(request)? {
+            Response::Done => Ok(()),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+}
+
+impl Backend for AgentBackend {
+    fn backend_name(&self) -> &'static str {
+        "agent"
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<Namespace>, String> {
+        match self.call(&Request::ListNamespaces)? {
+            Response::Namespaces(namespaces) => Ok(namespaces),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn for_each_namespace(
+        &self,
+        visit: &mut dyn FnMut(&str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        match self.call(&Request::ForEachNamespace)? {
+            Response::Namespaces(namespaces) => {
+                namespaces.iter().try_for_each(|namespace| visit(namespace))
+            }
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn list_secrets(&self, namespace: &str) -> Result<HashMap<EnvKey, EnvValue>, String> {
+        let request = Request::ListSecrets {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Secrets(secrets) => Ok(secrets),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn list_keys(&self, namespace: &str) -> Result<Vec<EnvKey>, String> {
+        let request = Request::ListKeys {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Keys(keys) => Ok(keys),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn list_all(&self) -> Result<HashMap<Namespace, HashMap<EnvKey, EnvValue>>, String> {
+        match self.call(&Request::ListAll)? {
+            Response::All(all) => Ok(all),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn count_keys(&self) -> Result<HashMap<Namespace, usize>, String> {
+        match self.call(&Request::CountKeys)? {
+            Response::Counts(counts) => Ok(counts),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn list_metadata(&self, namespace: &str) -> Result<HashMap<EnvKey, SecretMetadata>, String> {
+        let request = Request::ListMetadata {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Metadata(metadata) => Ok(metadata
+                .into_iter()
+                .map(|(k, v)| {
+                    let meta = SecretMetadata {
+                        created_at: from_secs(v.created_at),
+                        updated_at: from_secs(v.updated_at),
+                    };
+                    (k, meta)
+                })
+                .collect()),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<bool, String> {
+        let request = Request::SetSecret {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Stored(stored) => Ok(stored),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn set_secrets(
+        &mut self,
+        namespace: &str,
+        entries: &[(EnvKey, Zeroizing<EnvValue>)],
+    ) -> Result<Vec<bool>, String> {
+        let request = Request::SetSecrets {
+            namespace: namespace.to_string(),
+            entries: entries
+                .iter()
+                .map(|(key, value)| (key.clone(), value.to_string()))
+                .collect(),
+        };
+        match self.call(&request)? {
+            Response::StoredEach(stored) => Ok(stored),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn rotate_value(&mut self, namespace: &str, key: &str, f: RotateFn<'_>) -> Result<(), String> {
+        let request = Request::RotateValue {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+        };
+        let current = match self.call(&request)? {
+            Response::Current(current) => Zeroizing::new(current),
+            _ => return Err("Unexpected response from envchain agent".to_string()),
+        };
+        let answer = match f(&current) {
+            Ok(value) => Request::RotatedValue { value },
+            Err(error) => Request::RotateAbort { error },
+        };
+        self.call_done(&answer)
+    }
+
+    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<usize, String> {
+        let request = Request::DeleteSecret {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Deleted(count) => Ok(count),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn delete_secrets(&mut self, namespace: &str, keys: &[String]) -> Result<usize, String> {
+        let request = Request::DeleteSecrets {
+            namespace: namespace.to_string(),
+            keys: keys.to_vec(),
+        };
+        match self.call(&request)? {
+            Response::Deleted(count) => Ok(count),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn delete_namespace(&mut self, namespace: &str) -> Result<usize, String> {
+        let request = Request::DeleteNamespace {
+            namespace: namespace.to_string(),
+        };
+        match self.call(&request)? {
+            Response::Deleted(count) => Ok(count),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn clone_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        self.call_done(&Request::CloneNamespace {
+            src: src.to_string(),
+            dst: dst.to_string(),
+        })
+    }
+
+    fn rename_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        self.call_done(&Request::RenameNamespace {
+            src: src.to_string(),
+            dst: dst.to_string(),
+        })
+    }
+
+    fn lock(&mut self) -> Result<bool, String> {
+        self.call_applied(&Request::Lock)
+    }
+
+    fn unlock(&mut self) -> Result<bool, String> {
+        self.call_applied(&Request::Unlock)
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        self.call_done(&Request::HealthCheck)
+    }
+
+    fn is_locked(&self) -> Result<Option<bool>, String> {
+        match self.call(&Request::IsLocked)? {
+            Response::Locked(locked) => Ok(locked),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn outdated_items(&self) -> Result<Vec<String>, String> {
+        match self.call(&Request::OutdatedItems)? {
+            Response::Outdated(items) => Ok(items),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn upgrade_items(&mut self) -> Result<usize, String> {
+        match self.call(&Request::UpgradeItems)? {
+            Response::Items(count) => Ok(count),
+            _ => Err("Unexpected response from envchain agent".to_string()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.call_done(&Request::Flush)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_dir_is_created_private_and_existing_ones_are_not_chmodded() {
+        let root = tempfile::tempdir().unwrap();
+        let created = root.path().join("run").join("envchain");
+        prepare_socket_dir(&created).unwrap();
+        let mode = fs::metadata(&created).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        // A private directory that already exists is used as it is.
+        prepare_socket_dir(&created).unwrap();
+
+        let shared = root.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        let err = prepare_socket_dir(&shared).unwrap_err();
+        assert!(
+            err.starts_with("Refusing to put the agent socket in"),
+            "{err}"
+        );
+        let mode = fs::metadata(&shared).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn idle_clients_do_not_block_others_and_writes_are_forwarded() {
+        let root = tempfile::tempdir().unwrap();
+        let socket = root.path().join("agent").join("agent.sock");
+        let idle_timeout = Duration::from_secs(10);
+        let path = socket.clone();
+        thread::spawn(move || {
+            let backend = crate::backend::mock::MockBackend::with(&[("aws", "TOKEN", "old")]);
+            serve(Box::new(backend), &path, idle_timeout)
+        });
+        while !socket.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let started = Instant::now();
+        let _idle = UnixStream::connect(&socket).unwrap();
+        let mut agent = AgentBackend::connect(&socket).unwrap();
+        let entries = [("A".to_string(), Zeroizing::new("1".to_string()))];
+        assert_eq!(agent.set_secrets("aws", &entries).unwrap(), vec![true]);
+        agent
+            .rotate_value("aws", "TOKEN", Box::new(|old| Ok(format!("{old}-new"))))
+            .unwrap();
+        let err = agent
+            .rotate_value("aws", "TOKEN", Box::new(|_| Err("no".to_string())))
+            .unwrap_err();
+        assert_eq!(err, "no");
+        let secrets = agent.list_secrets("aws").unwrap();
+        assert_eq!(secrets["TOKEN"], "old-new");
+        assert_eq!(secrets["A"], "1");
+        assert_eq!(agent.delete_secrets("aws", &["A".to_string()]).unwrap(), 1);
+        let mut namespaces = Vec::new();
+        agent
+            .for_each_namespace(&mut |namespace| {
+                namespaces.push(namespace.to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(namespaces, ["aws"]);
+        assert!(agent.outdated_items().unwrap().is_empty());
+        assert_eq!(agent.upgrade_items().unwrap(), 0);
+        assert!(started.elapsed() < idle_timeout);
+    }
+}
@@ -0,0 +1,276 @@
+//! Shared file handling for backends that keep their store in the envchain
+//! config directory.
+//!
+//! Everything written here gets the same guarantees: the directory is owner
+//! only, files are created 0600 and replaced atomically, and the previous
+//! contents can optionally be zeroed after replacement.  Where the
+//! directory cannot rename over a file, as on some FUSE and synced
+//! folders, the file is rewritten in place instead.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tempfile::{NamedTempFile, PersistError};
+
+/// Return the envchain config directory, creating it with owner-only
+/// permissions if necessary.
+pub fn config_dir() -> Result<PathBuf, String> {
+    let config_dir = config_dir_path()?;
+    create_config_dir(&config_dir)?;
+    Ok(config_dir)
+}
+
+/// Location of the config directory, which may not exist yet.
+pub fn config_dir_path() -> Result<PathBuf, String> {
+    Ok(dirs::config_dir()
+        .ok_or("Could not determine config directory")?
+        .join("envchain"))
+}
+
+/// Create the config directory if needed and restrict it to its owner.
+pub fn create_config_dir(config_dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(config_dir).map_err(|e| format!("Failed to create config dir: {e}"))?;
+
+    // Restrict config directory to owner only so others cannot list its contents.
+    // On a read-only filesystem the existing directory is used as it is.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        match fs::set_permissions(config_dir, fs::Permissions::from_mode(0o700)) {
+            Err(e) if e.kind() != std::io::ErrorKind::ReadOnlyFilesystem => {
+                return Err(format!("Failed to set config dir permissions: {e}"));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Overwrite the whole of `file` with zeros and sync it to disk.
+///
+/// This is best effort: copy-on-write and journaling filesystems, SSD wear
+/// levelling and snapshots may keep the old blocks regardless.
+pub fn overwrite_with_zeros(mut file: fs::File) -> std::io::Result<()> {
+    let len = file.metadata()?.len();
+    write_zeros(&mut file, len)?;
+    file.sync_all()
+}
+
+/// Write `count` zeros at the current position of `file`.
+fn write_zeros(file: &mut fs::File, count: u64) -> std::io::Result<()> {
+    let zeros = [0u8; 8192];
+    let mut remaining = count;
+    while remaining > 0 {
+        let n = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Atomically replace `path` with `data`.  With `secure_delete`, the blocks
+/// of the previous file are zeroed once the new one is in place.
+pub fn write_atomic(path: &Path, data: &[u8], secure_delete: bool) -> Result<(), String> {
+    write_replacing(path, data, secure_delete, |temp, path| {
+        temp.persist(path).map(drop)
+    })
+}
+
+/// `write_atomic` with the rename step passed in, so that tests can make it
+/// fail.
+fn write_replacing(
+    path: &Path,
+    data: &[u8],
+    secure_delete: bool,
+    rename: impl FnOnce(NamedTempFile, &Path) -> Result<(), PersistError>,
+) -> Result<(), String> {
+    // Write via a unique temp file created in the same directory as `path`
+    // (same filesystem → rename is atomic).
+    // tempfile creates the file with O_CREAT | O_EXCL | mode 0o600 on Unix,
+    // so the permissions are correct from the start and survive the rename
+    // without a subsequent chmod call.
+    let parent = path
+        .parent()
+        .ok_or("Could not determine secrets file parent directory")?;
+    // With secure delete, hold a handle on the previous file so its
+    // blocks can be zeroed after the rename has safely replaced it.
+    let previous = if secure_delete {
+        fs::OpenOptions::new().write(true).open(path).ok()
+    } else {
+        None
+    };
+
+    let mut temp_file =
+        NamedTempFile::new_in(parent).map_err(|e| format!("Failed to create temp file: {e}"))?;
+    temp_file
+        .write_all(data)
+        .map_err(|e| format!("Failed to write temp file: {e}"))?;
+    temp_file
+        .flush()
+        .map_err(|e| format!("Failed to flush temp file: {e}"))?;
+    match rename(temp_file, path) {
+        Ok(()) => {}
+        Err(PersistError { error, file }) if rename_unsupported(&error) => {
+            eprintln!(
+                "Warning: cannot rename a file over {} ({error}); rewriting it in place instead",
+                path.display()
+            );
+            // The old contents are zeroed through the rewrite itself.
+            drop(previous);
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(false);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            return rewrite_in_place(file, path, options.open(path), data, secure_delete);
+        }
+        Err(e) => return Err(format!("Failed to rename secrets file: {e}")),
+    }
+
+    if let Some(previous) = previous
+        && let Err(e) = overwrite_with_zeros(previous)
+    {
+        eprintln!("Warning: could not overwrite previous secrets file: {e}");
+    }
+
+    Ok(())
+}
+
+/// Rename errors that mean the filesystem cannot replace a file this way,
+/// rather than that the write itself is not allowed.
+fn rename_unsupported(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::CrossesDevices | ErrorKind::Unsupported | ErrorKind::ResourceBusy
+    )
+}
+
+/// Fallback for `write_atomic` when renaming fails: sync `temp`, which
+/// already holds `data`, then write `data` over the start of `target`
+/// (`path` opened for writing) and sync it, before cutting off what is left
+/// of the old contents.  With `secure_delete` that remainder is zeroed
+/// first, but only once the new contents are safely on disk.  If anything
+/// fails, `temp` is kept and named in the error, so the new contents are
+/// never lost.
+fn rewrite_in_place(
+    temp: NamedTempFile,
+    path: &Path,
+    target: std::io::Result<fs::File>,
+    data: &[u8],
+    secure_delete: bool,
+) -> Result<(), String> {
+    temp.as_file()
+        .sync_all()
+        .map_err(|e| format!("Failed to sync temp file: {e}"))?;
+
+    let written = target.and_then(|mut file| {
+        let old_len = file.metadata()?.len();
+        file.write_all(data)?;
+        file.sync_all()?;
+        let new_len = data.len() as u64;
+        if secure_delete && old_len > new_len {
+            write_zeros(&mut file, old_len - new_len)?;
+            file.sync_all()?;
+        }
+        file.set_len(new_len)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let kept = match temp.keep() {
+            Ok((_, kept)) => format!("the new contents are in {}", kept.display()),
+            Err(_) => "the new contents could not be kept".to_string(),
+        };
+        return Err(format!("Failed to rewrite {}: {e}; {kept}", path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_rename_falls_back_to_rewriting_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.age");
+        fs::write(&path, "previous, longer contents").unwrap();
+
+        write_replacing(&path, b"new", true, |file, _| {
+            Err(PersistError {
+                error: ErrorKind::CrossesDevices.into(),
+                file,
+            })
+        })
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        // The temp file is gone and nothing else was left behind.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let err = write_replacing(&path, b"newer", false, |file, _| {
+            Err(PersistError {
+                error: ErrorKind::PermissionDenied.into(),
+                file,
+            })
+        })
+        .unwrap_err();
+        assert!(err.starts_with("Failed to rename secrets file"), "{err}");
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rewritten_files_are_created_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.age");
+        write_replacing(&path, b"new", false, |file, _| {
+            Err(PersistError {
+                error: ErrorKind::Unsupported.into(),
+                file,
+            })
+        })
+        .unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn failed_rewrite_keeps_the_old_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.age");
+        fs::write(&path, "previous, longer contents").unwrap();
+        let temp = NamedTempFile::new_in(dir.path()).unwrap();
+        fs::write(temp.path(), "new").unwrap();
+
+        // A handle opened read-only makes the overwrite fail.
+        let err = rewrite_in_place(temp, &path, fs::File::open(&path), b"new", true).unwrap_err();
+        assert!(err.starts_with("Failed to rewrite"), "{err}");
+        assert_eq!(fs::read(&path).unwrap(), b"previous, longer contents");
+        let kept = err.rsplit("the new contents are in ").next().unwrap();
+        assert_eq!(fs::read(kept).unwrap(), b"new");
+    }
+
+    // Windows cannot rename over a file that is still open.
+    #[cfg(unix)]
+    #[test]
+    fn secure_delete_zeroes_the_replaced_file() {
+        use std::io::Read;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.age");
+        fs::write(&path, "old secret contents").unwrap();
+        // Still refers to the old inode once the new file is renamed over it.
+        let mut old = fs::File::open(&path).unwrap();
+
+        write_replacing(&path, b"new", true, |file, path| {
+            file.persist(path).map(drop)
+        })
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let mut contents = Vec::new();
+        old.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![0; "old secret contents".len()]);
+    }
+}
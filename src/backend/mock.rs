@@ -0,0 +1,101 @@
+//! In-memory backend for unit tests of the command handlers.
+
+use super::{Backend, EnvKey, EnvValue, Namespace};
+use std::collections::HashMap;
+
+/// A store held in a map.  `failing` makes every operation return an error,
+/// standing in for a backend whose keyring is unavailable.
+#[derive(Default)]
+pub struct MockBackend {
+    store: HashMap<Namespace, HashMap<EnvKey, EnvValue>>,
+    /// Error every operation fails with
+    error: Option<String>,
+    /// Number of `flush` calls, i.e. completed write operations
+    pub flushes: usize,
+}
+
+impl MockBackend {
+    /// A store holding `(namespace, key, value)` entries.
+    pub fn with(entries: &[(&str, &str, &str)]) -> Self {
+        let mut backend = Self::default();
+        for (namespace, key, value) in entries {
+            backend
+                .store
+                .entry(namespace.to_string())
+                .or_default()
+                .insert(key.to_string(), value.to_string());
+        }
+        backend
+    }
+
+    /// Add a namespace that is listed but holds no keys, like keyring items
+    /// left without a `key` attribute.
+    pub fn with_empty(mut self, namespace: &str) -> Self {
+        self.store.entry(namespace.to_string()).or_default();
+        self
+    }
+
+    pub fn failing(error: &str) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn get(&self, namespace: &str, key: &str) -> Option<&str> {
+        self.store.get(namespace)?.get(key).map(String::as_str)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Backend for MockBackend {
+    fn backend_name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<Namespace>, String> {
+        self.check()?;
+        let mut names: Vec<_> = self.store.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn list_secrets(&self, namespace: &str) -> Result<HashMap<EnvKey, EnvValue>, String> {
+        self.check()?;
+        Ok(self.store.get(namespace).cloned().unwrap_or_default())
+    }
+
+    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<bool, String> {
+        self.check()?;
+        let previous = self
+            .store
+            .entry(namespace.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_string());
+        Ok(previous.as_deref() != Some(value))
+    }
+
+    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<usize, String> {
+        self.check()?;
+        let Some(secrets) = self.store.get_mut(namespace) else {
+            return Ok(0);
+        };
+        let removed = usize::from(secrets.remove(key).is_some());
+        if secrets.is_empty() {
+            self.store.remove(namespace);
+        }
+        Ok(removed)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.check()?;
+        self.flushes += 1;
+        Ok(())
+    }
+}
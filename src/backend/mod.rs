@@ -1,29 +1,289 @@
 use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
 
 pub type Namespace = String;
 pub type EnvKey = String;
+/// Values are UTF-8 text, whatever the store could hold: every value entered
+/// through the CLI is checked by `utf8_value` before it reaches a backend.
 pub type EnvValue = String;
 
+/// Computes a replacement value from the current one, for `rotate_value`.
+pub type RotateFn<'a> = Box<dyn FnOnce(&str) -> Result<EnvValue, String> + 'a>;
+
+/// Report on stderr how long a step took since `start` (`--timings`).
+pub fn log_timing(step: &str, start: Instant) {
+    eprintln!("envchain: timing: {step}: {:.1?}", start.elapsed());
+}
+
+/// Metadata about a stored secret, as far as the backend records it.
+#[derive(Debug, Clone, Default)]
+pub struct SecretMetadata {
+    /// When the secret was first stored
+    pub created_at: Option<SystemTime>,
+    /// When the value was last set
+    pub updated_at: Option<SystemTime>,
+}
+
+/// What a backend supports, so callers can adapt instead of getting
+/// empty or slow results.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Capabilities {
+    /// `list_namespaces` can enumerate the store
+    pub list_namespaces: bool,
+    /// `list_keys` reads names without fetching or decrypting values
+    pub list_keys_without_values: bool,
+    /// `clone_namespace` copies inside the store rather than value by value
+    pub server_side_copy: bool,
+    /// `list_metadata` reports modification times
+    pub timestamps: bool,
+    /// The store itself holds values as bytes, so a value that is not UTF-8
+    /// could be kept there.  envchain still only stores text; see
+    /// [`EnvValue`].
+    pub binary_values: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            list_namespaces: true,
+            list_keys_without_values: false,
+            server_side_copy: false,
+            timestamps: false,
+            binary_values: false,
+        }
+    }
+}
+
 /// Backend trait for secret storage
 pub trait Backend {
+    /// Stable identifier of the backend (e.g. `age`), for diagnostics
+    fn backend_name(&self) -> &'static str;
+
     /// List all namespaces
     fn list_namespaces(&self) -> Result<Vec<Namespace>, String>;
 
+    /// Call `visit` with each namespace, in no particular order, stopping at
+    /// the first error.
+    ///
+    /// Unlike `list_namespaces` this need not collect and sort the names
+    /// first.  The default goes through `list_namespaces`; backends that can
+    /// produce names one at a time should override this.
+    fn for_each_namespace(
+        &self,
+        visit: &mut dyn FnMut(&str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.list_namespaces()?
+            .iter()
+            .try_for_each(|namespace| visit(namespace))
+    }
+
     /// List all key-value pairs in a namespace
     fn list_secrets(&self, namespace: &str) -> Result<HashMap<EnvKey, EnvValue>, String>;
 
-    /// Set a secret value
-    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<(), String>;
+    /// List the key names in a namespace without fetching their values.
+    ///
+    /// The default goes through `list_secrets`; backends that can read
+    /// names alone should override this.
+    fn list_keys(&self, namespace: &str) -> Result<Vec<EnvKey>, String> {
+        Ok(self.list_secrets(namespace)?.into_keys().collect())
+    }
+
+    /// List metadata for each key in a namespace.
+    ///
+    /// Backends that do not track metadata return an empty map.
+    fn list_metadata(&self, _namespace: &str) -> Result<HashMap<EnvKey, SecretMetadata>, String> {
+        Ok(HashMap::new())
+    }
+
+    /// List every namespace with its key-value pairs.
+    ///
+    /// The default issues one `list_secrets` call per namespace; backends
+    /// that can fetch everything at once should override this.
+    fn list_all(&self) -> Result<HashMap<Namespace, HashMap<EnvKey, EnvValue>>, String> {
+        let mut all = HashMap::new();
+        self.for_each_namespace(&mut |namespace| {
+            let secrets = self.list_secrets(namespace)?;
+            all.insert(namespace.to_string(), secrets);
+            Ok(())
+        })?;
+        Ok(all)
+    }
+
+    /// Number of keys in each namespace.
+    ///
+    /// The default issues one `list_keys` call per namespace; backends that
+    /// can count everything in one read, without fetching values, should
+    /// override this.
+    fn count_keys(&self) -> Result<HashMap<Namespace, usize>, String> {
+        let mut counts = HashMap::new();
+        self.for_each_namespace(&mut |namespace| {
+            counts.insert(namespace.to_string(), self.list_keys(namespace)?.len());
+            Ok(())
+        })?;
+        Ok(counts)
+    }
+
+    /// What this backend supports.  The default matches the default
+    /// method implementations.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// The file every secret is kept in, for backends stored in a single
+    /// local file, so `watch` can notice changes.  `None` for keyrings.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    fn store_path(&self) -> Option<&std::path::Path> {
+        None
+    }
 
-    /// Delete a secret
-    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<(), String>;
+    /// Set a secret value, returning whether anything was written: `false`
+    /// when the key already held exactly `value`, so the write was skipped.
+    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<bool, String>;
+
+    /// Set several secrets in a namespace, returning for each entry whether
+    /// it was written (see `set_secret`).
+    ///
+    /// The default sets key by key; backends that rewrite the whole store
+    /// on every change should override this to save once.
+    fn set_secrets(
+        &mut self,
+        namespace: &str,
+        entries: &[(EnvKey, zeroize::Zeroizing<EnvValue>)],
+    ) -> Result<Vec<bool>, String> {
+        entries
+            .iter()
+            .map(|(key, value)| self.set_secret(namespace, key, value))
+            .collect()
+    }
+
+    /// Replace a secret with `f` applied to its current value.  Fails
+    /// without calling `f` if the secret is not set.
+    ///
+    /// The default reads the value and writes the result back; backends
+    /// that hold the whole store in memory should do both in one save.
+    fn rotate_value(&mut self, namespace: &str, key: &str, f: RotateFn<'_>) -> Result<(), String> {
+        let current = zeroize::Zeroizing::new(
+            self.list_secrets(namespace)?
+                .remove(key)
+                .ok_or_else(|| format!("{namespace}.{key} is not set"))?,
+        );
+        let value = zeroize::Zeroizing::new(f(&current)?);
+        self.set_secret(namespace, key, &value).map(drop)
+    }
+
+    /// Delete a secret, returning how many stored items were removed
+    /// (0 if it did not exist)
+    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<usize, String>;
+
+    /// Delete several secrets from a namespace, returning how many stored
+    /// items were removed.  Keys that do not exist are skipped.
+    ///
+    /// The default deletes key by key; backends that can delete many keys
+    /// in one operation should override this.
+    fn delete_secrets(&mut self, namespace: &str, keys: &[String]) -> Result<usize, String> {
+        let mut removed = 0;
+        for key in keys {
+            removed += self.delete_secret(namespace, key)?;
+        }
+        Ok(removed)
+    }
+
+    /// Delete every secret in a namespace, returning how many stored items
+    /// were removed.
+    ///
+    /// The default goes through `delete_secrets`; backends that can drop a
+    /// namespace in one operation should override this.
+    fn delete_namespace(&mut self, namespace: &str) -> Result<usize, String> {
+        let keys = self.list_keys(namespace)?;
+        self.delete_secrets(namespace, &keys)
+    }
+
+    /// Lock the store so that reading it needs the user to unlock it again.
+    ///
+    /// Returns `false` for backends without a notion of locking.
+    fn lock(&mut self) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// Unlock the store, prompting the user if the backend requires it.
+    ///
+    /// Returns `false` for backends without a notion of locking.
+    fn unlock(&mut self) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// Cheaply check that the backend is usable, without reading every
+    /// secret.  Defaults to listing the namespaces.
+    fn health_check(&self) -> Result<(), String> {
+        self.list_namespaces().map(drop)
+    }
+
+    /// The stored items, as `namespace.key`, that were written by an older
+    /// envchain and would be changed by `upgrade_items`.  Defaults to none.
+    fn outdated_items(&self) -> Result<Vec<String>, String> {
+        Ok(Vec::new())
+    }
+
+    /// Bring items written by an older envchain up to date, returning how
+    /// many were changed.
+    fn upgrade_items(&mut self) -> Result<usize, String> {
+        Ok(0)
+    }
+
+    /// Whether the store is currently locked, or `None` for backends
+    /// without a notion of locking.
+    fn is_locked(&self) -> Result<Option<bool>, String> {
+        Ok(None)
+    }
+
+    /// Persist any buffered changes.
+    ///
+    /// Backends that write through on every mutation need not override this.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Copy every secret in `src` into `dst`, overwriting keys that already
+    /// exist there.
+    ///
+    /// The default reads each value and writes it back; backends that can
+    /// duplicate entries more cheaply should override this.
+    fn clone_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        let secrets = self.list_secrets(src)?;
+        for (key, value) in secrets {
+            let value = zeroize::Zeroizing::new(value);
+            self.set_secret(dst, &key, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Move every secret in `src` into `dst`, overwriting keys that already
+    /// exist there, and remove `src`.
+    ///
+    /// The default copies with `clone_namespace` and then deletes `src`,
+    /// which keeps only the values.  Backends that can move entries in a
+    /// single write, or that keep metadata with them, should override this
+    /// so that timestamps and attributes move too.
+    fn rename_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        self.clone_namespace(src, dst)?;
+        self.delete_namespace(src).map(drop)
+    }
 }
 
+#[cfg(unix)]
+pub mod agent;
+#[cfg(test)]
+pub mod mock;
+pub mod read_only;
+pub mod retry;
+
 #[cfg(feature = "secret-service-backend")]
 pub mod secret_service;
 
 #[cfg(feature = "age-backend")]
 pub mod age;
+#[cfg(feature = "age-backend")]
+mod file;
 
 #[cfg(feature = "windows-credential-manager")]
 pub mod windows_credential_manager;
@@ -0,0 +1,136 @@
+//! Wrapper refusing every change to the store (`--read-only`), for
+//! inspecting secrets that must not be modified or re-encrypted.
+
+use super::{Backend, Capabilities, EnvKey, EnvValue, Namespace, RotateFn, SecretMetadata};
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+/// Backend decorator that forwards reads and fails every mutating call
+/// before it reaches the store.
+pub struct ReadOnlyBackend {
+    inner: Box<dyn Backend>,
+}
+
+impl ReadOnlyBackend {
+    pub fn new(inner: Box<dyn Backend>) -> Self {
+        Self { inner }
+    }
+
+    fn refuse<T>(&self, operation: &str) -> Result<T, String> {
+        Err(format!(
+            "Refusing to {operation}: the {} backend was opened with --read-only",
+            self.inner.backend_name()
+        ))
+    }
+}
+
+impl Backend for ReadOnlyBackend {
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<Namespace>, String> {
+        self.inner.list_namespaces()
+    }
+
+    fn for_each_namespace(
+        &self,
+        visit: &mut dyn FnMut(&str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.inner.for_each_namespace(visit)
+    }
+
+    fn list_secrets(&self, namespace: &str) -> Result<HashMap<EnvKey, EnvValue>, String> {
+        self.inner.list_secrets(namespace)
+    }
+
+    fn list_keys(&self, namespace: &str) -> Result<Vec<EnvKey>, String> {
+        self.inner.list_keys(namespace)
+    }
+
+    fn list_metadata(&self, namespace: &str) -> Result<HashMap<EnvKey, SecretMetadata>, String> {
+        self.inner.list_metadata(namespace)
+    }
+
+    fn list_all(&self) -> Result<HashMap<Namespace, HashMap<EnvKey, EnvValue>>, String> {
+        self.inner.list_all()
+    }
+
+    fn count_keys(&self) -> Result<HashMap<Namespace, usize>, String> {
+        self.inner.count_keys()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn store_path(&self) -> Option<&std::path::Path> {
+        self.inner.store_path()
+    }
+
+    fn set_secret(&mut self, namespace: &str, key: &str, _value: &str) -> Result<bool, String> {
+        self.refuse(&format!("set {namespace}.{key}"))
+    }
+
+    fn set_secrets(
+        &mut self,
+        namespace: &str,
+        _entries: &[(EnvKey, Zeroizing<EnvValue>)],
+    ) -> Result<Vec<bool>, String> {
+        self.refuse(&format!("set variables in {namespace}"))
+    }
+
+    fn rotate_value(&mut self, namespace: &str, key: &str, _f: RotateFn<'_>) -> Result<(), String> {
+        self.refuse(&format!("rotate {namespace}.{key}"))
+    }
+
+    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<usize, String> {
+        self.refuse(&format!("delete {namespace}.{key}"))
+    }
+
+    fn delete_secrets(&mut self, namespace: &str, _keys: &[String]) -> Result<usize, String> {
+        self.refuse(&format!("delete variables from {namespace}"))
+    }
+
+    fn delete_namespace(&mut self, namespace: &str) -> Result<usize, String> {
+        self.refuse(&format!("delete {namespace}"))
+    }
+
+    fn clone_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        self.refuse(&format!("copy {src} to {dst}"))
+    }
+
+    fn rename_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        self.refuse(&format!("rename {src} to {dst}"))
+    }
+
+    fn upgrade_items(&mut self) -> Result<usize, String> {
+        self.refuse("upgrade stored items")
+    }
+
+    // Locking changes whether the store can be read, not what it holds.
+    fn lock(&mut self) -> Result<bool, String> {
+        self.inner.lock()
+    }
+
+    fn unlock(&mut self) -> Result<bool, String> {
+        self.inner.unlock()
+    }
+
+    fn is_locked(&self) -> Result<Option<bool>, String> {
+        self.inner.is_locked()
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        self.inner.health_check()
+    }
+
+    fn outdated_items(&self) -> Result<Vec<String>, String> {
+        self.inner.outdated_items()
+    }
+
+    // Nothing can be pending, since every change was refused.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
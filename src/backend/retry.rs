@@ -0,0 +1,202 @@
+//! Retry wrapper for backends whose service may be briefly unavailable,
+//! e.g. a keyring daemon that is still starting right after login.
+
+use super::{Backend, Capabilities, EnvKey, EnvValue, Namespace, RotateFn, SecretMetadata};
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Error fragments that indicate the secret store is temporarily unreachable
+/// rather than that the request itself is wrong.
+const TRANSIENT_PATTERNS: &[&str] = &[
+    "connect failed",
+    "connection refused",
+    "no secret service provider",
+    "dbus session",
+    "service unknown",
+    "not activatable",
+    "timed out",
+    "timeout",
+    "platform failure",
+    "no storage access",
+];
+
+/// Whether an error message describes a transient failure worth retrying.
+pub fn is_transient(error: &str) -> bool {
+    let error = error.to_lowercase();
+    TRANSIENT_PATTERNS.iter().any(|p| error.contains(p))
+}
+
+/// Run `op`, retrying up to `retries` more times with exponential backoff
+/// while it fails with a transient error.
+pub fn with_retry<T>(retries: u32, mut op: impl FnMut() -> Result<T, String>) -> Result<T, String> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                eprintln!(
+                    "{e}\nRetrying in {}ms ({attempt}/{retries})...",
+                    backoff.as_millis()
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Backend decorator that retries every operation on transient errors.
+pub struct RetryBackend {
+    inner: Box<dyn Backend>,
+    retries: u32,
+}
+
+impl RetryBackend {
+    pub fn new(inner: Box<dyn Backend>, retries: u32) -> Self {
+        Self { inner, retries }
+    }
+}
+
+impl Backend for RetryBackend {
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<Namespace>, String> {
+        with_retry(self.retries, || self.inner.list_namespaces())
+    }
+
+    fn for_each_namespace(
+        &self,
+        visit: &mut dyn FnMut(&str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        // Retrying once `visit` has seen a namespace would repeat it, so a
+        // failure after that point is passed through untouched.
+        let mut visited = false;
+        let mut failed = None;
+        with_retry(self.retries, || {
+            match self.inner.for_each_namespace(&mut |namespace| {
+                visited = true;
+                visit(namespace)
+            }) {
+                Err(e) if visited => {
+                    failed = Some(e);
+                    Ok(())
+                }
+                result => result,
+            }
+        })?;
+        failed.map_or(Ok(()), Err)
+    }
+
+    fn list_secrets(&self, namespace: &str) -> Result<HashMap<EnvKey, EnvValue>, String> {
+        with_retry(self.retries, || self.inner.list_secrets(namespace))
+    }
+
+    fn list_keys(&self, namespace: &str) -> Result<Vec<EnvKey>, String> {
+        with_retry(self.retries, || self.inner.list_keys(namespace))
+    }
+
+    fn list_metadata(&self, namespace: &str) -> Result<HashMap<EnvKey, SecretMetadata>, String> {
+        with_retry(self.retries, || self.inner.list_metadata(namespace))
+    }
+
+    fn list_all(&self) -> Result<HashMap<Namespace, HashMap<EnvKey, EnvValue>>, String> {
+        with_retry(self.retries, || self.inner.list_all())
+    }
+
+    fn count_keys(&self) -> Result<HashMap<Namespace, usize>, String> {
+        with_retry(self.retries, || self.inner.count_keys())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn store_path(&self) -> Option<&std::path::Path> {
+        self.inner.store_path()
+    }
+
+    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<bool, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.set_secret(namespace, key, value))
+    }
+
+    fn set_secrets(
+        &mut self,
+        namespace: &str,
+        entries: &[(EnvKey, Zeroizing<EnvValue>)],
+    ) -> Result<Vec<bool>, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.set_secrets(namespace, entries))
+    }
+
+    fn rotate_value(&mut self, namespace: &str, key: &str, f: RotateFn<'_>) -> Result<(), String> {
+        // Not retried: `f` may have side effects, such as revoking the old
+        // token, that must not happen twice.
+        self.inner.rotate_value(namespace, key, f)
+    }
+
+    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<usize, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.delete_secret(namespace, key))
+    }
+
+    fn delete_secrets(&mut self, namespace: &str, keys: &[String]) -> Result<usize, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.delete_secrets(namespace, keys))
+    }
+
+    fn delete_namespace(&mut self, namespace: &str) -> Result<usize, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.delete_namespace(namespace))
+    }
+
+    fn clone_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.clone_namespace(src, dst))
+    }
+
+    fn rename_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.rename_namespace(src, dst))
+    }
+
+    fn lock(&mut self) -> Result<bool, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.lock())
+    }
+
+    fn unlock(&mut self) -> Result<bool, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.unlock())
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        with_retry(self.retries, || self.inner.health_check())
+    }
+
+    fn is_locked(&self) -> Result<Option<bool>, String> {
+        with_retry(self.retries, || self.inner.is_locked())
+    }
+
+    fn outdated_items(&self) -> Result<Vec<String>, String> {
+        with_retry(self.retries, || self.inner.outdated_items())
+    }
+
+    fn upgrade_items(&mut self) -> Result<usize, String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.upgrade_items())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        let inner = &mut self.inner;
+        with_retry(self.retries, || inner.flush())
+    }
+}
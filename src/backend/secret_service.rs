@@ -1,32 +1,193 @@
-use super::{Backend, EnvKey, EnvValue, Namespace};
+use super::{Backend, Capabilities, EnvKey, EnvValue, Namespace, SecretMetadata};
 use secret_service::EncryptionType;
 use secret_service::blocking::{Collection, Item, SecretService};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+/// Attribute marking items created by envchain, so that other
+/// applications' items are never mistaken for namespaces.
+const MARKER: (&str, &str) = ("application", "envchain");
+
+/// Schema attribute set by libsecret, which the original envchain used.
+const SCHEMA: (&str, &str) = ("xdg:schema", "envchain.EnvironmentVariable");
 
 pub struct SecretServiceBackend {
     ss: SecretService<'static>,
+    /// D-Bus object path of the collection to use instead of the default
+    collection_path: Option<String>,
+    /// Extra attributes stored on new items; never used for lookup
+    extra_attributes: Vec<(String, String)>,
+    /// Only read or change items carrying `MARKER`
+    label_filter: bool,
 }
 
 impl SecretServiceBackend {
-    pub fn new() -> Result<Self, String> {
+    /// Connect to the Secret Service.  With `create_collection`, a missing
+    /// default collection is created (and unlocked) instead of failing.
+    /// With `label_filter`, only items tagged as created by envchain are read,
+    /// changed or deleted.
+    ///
+    /// Connecting negotiates an encrypted session with a Diffie-Hellman
+    /// exchange, the bulk of the cost of opening this backend.  The session
+    /// lives as long as the backend, so every operation of a process (or of
+    /// an `--agent`) shares it; `timings` logs how long the exchange took.
+    pub fn new(
+        collection_path: Option<String>,
+        create_collection: bool,
+        extra_attributes: Vec<(String, String)>,
+        label_filter: bool,
+        timings: bool,
+    ) -> Result<Self, String> {
+        let start = Instant::now();
         let ss = SecretService::connect(EncryptionType::Dh)
             .map_err(|e| format!("SecretService connect failed: {e}"))?;
-        Ok(Self { ss })
+        if timings {
+            super::log_timing("secret-service session", start);
+        }
+        let backend = Self {
+            ss,
+            collection_path,
+            extra_attributes,
+            label_filter,
+        };
+        // Fail early on a path that names no collection.
+        if backend.collection_path.is_some() {
+            backend.get_collection()?;
+        } else if create_collection {
+            backend.ensure_default_collection()?;
+        }
+        Ok(backend)
+    }
+
+    /// Create the `default` collection alias if the keyring has none.
+    fn ensure_default_collection(&self) -> Result<(), String> {
+        match self.ss.get_default_collection() {
+            Ok(_) => return Ok(()),
+            Err(secret_service::Error::NoResult) => {}
+            Err(e) => return Err(format!("SecretService default collection failed: {e}")),
+        }
+        let collection = self.ss.create_collection("Login", "default").map_err(|e| {
+            format!(
+                "Could not create a default SecretService collection: {e}\n\
+                     Creating a keyring needs a running keyring daemon that can show its \
+                     password prompt in this session. On a headless machine, start one \
+                     with `gnome-keyring-daemon --unlock` (it reads the new keyring \
+                     password from stdin) or use `--backend age`."
+            )
+        })?;
+        collection
+            .ensure_unlocked()
+            .map_err(|e| format!("Failed to unlock the new SecretService collection: {e}"))?;
+        eprintln!("Created default SecretService collection");
+        Ok(())
     }
 
     fn get_collection(&self) -> Result<Collection<'_>, String> {
-        self.ss
-            .get_default_collection()
-            .map_err(|e| format!("SecretService default collection failed: {e}"))
+        let Some(path) = &self.collection_path else {
+            return self.ss.get_default_collection().map_err(|e| match e {
+                secret_service::Error::NoResult => "The keyring has no default SecretService \
+                     collection.\nRe-run with --create-collection to create one, create a \
+                     keyring in your desktop's password manager, or use `--backend age`."
+                    .to_string(),
+                e => format!("SecretService default collection failed: {e}"),
+            });
+        };
+        let collections = self
+            .ss
+            .get_all_collections()
+            .map_err(|e| format!("SecretService collection lookup failed: {e}"))?;
+        let available: Vec<String> = collections
+            .iter()
+            .map(|c| c.collection_path.to_string())
+            .collect();
+        collections
+            .into_iter()
+            .find(|c| c.collection_path.as_str() == path)
+            .ok_or_else(|| {
+                format!(
+                    "No SecretService collection at {path}\nAvailable collections:\n  {}",
+                    available.join("\n  ")
+                )
+            })
     }
 }
 
+impl SecretServiceBackend {
+    /// Search for items to read or change, restricted to envchain's own
+    /// items with `--ss-label-filter`.
+    fn search_own<'a>(
+        &self,
+        collection: &'a Collection<'_>,
+        mut attributes: HashMap<&str, &str>,
+    ) -> Result<Vec<Item<'a>>, String> {
+        if self.label_filter {
+            attributes.insert(MARKER.0, MARKER.1);
+        }
+        collection
+            .search_items(attributes)
+            .map_err(|e| format!("search_items failed: {e}"))
+    }
+
+    /// Items stored by envchain before `MARKER` was added, with their
+    /// `namespace.key`.  Only items with the attributes envchain writes are
+    /// considered: `name` and `key`, the configured `--ss-attribute` names
+    /// and the original envchain's libsecret schema.  An item with any other
+    /// attribute belongs to another application.
+    fn untagged_items<'a>(
+        &self,
+        collection: &'a Collection<'_>,
+    ) -> Result<Vec<(Item<'a>, String)>, String> {
+        let items = collection
+            .search_items(HashMap::new())
+            .map_err(|e| format!("search_items failed: {e}"))?;
+        Ok(items
+            .into_iter()
+            .filter_map(|item| {
+                let attrs = item.get_attributes().ok()?;
+                let name = format!("{}.{}", attrs.get("name")?, attrs.get("key")?);
+                let envchain_schema =
+                    attrs
+                        .iter()
+                        .all(|(attribute, value)| match attribute.as_str() {
+                            "name" | "key" => true,
+                            a if a == SCHEMA.0 => value == SCHEMA.1,
+                            a => self.extra_attributes.iter().any(|(extra, _)| extra == a),
+                        });
+                envchain_schema.then_some((item, name))
+            })
+            .collect())
+    }
+}
+
+/// Delete `items`, returning how many were removed.  Fails only if none
+/// could be; individual failures are reported on stderr.
+fn delete_items(items: &[Item], label: &str) -> Result<usize, String> {
+    let mut deleted = 0;
+    let mut errors = Vec::new();
+    for item in items {
+        match item.delete() {
+            Ok(()) => deleted += 1,
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if deleted == 0 && !errors.is_empty() {
+        return Err(format!("Failed to delete {label}: {}", errors.join("; ")));
+    }
+    for e in &errors {
+        eprintln!("Failed to delete one item of {label}: {e}");
+    }
+    Ok(deleted)
+}
+
 impl Backend for SecretServiceBackend {
+    fn backend_name(&self) -> &'static str {
+        "secret-service"
+    }
+
     fn list_namespaces(&self) -> Result<Vec<Namespace>, String> {
         let collection = self.get_collection()?;
-        let items: Vec<Item> = collection
-            .search_items(HashMap::new())
-            .map_err(|e| format!("search_items failed: {e}"))?;
+        let items: Vec<Item> = self.search_own(&collection, HashMap::new())?;
 
         let mut namespaces: Vec<String> = items
             .into_iter()
@@ -40,11 +201,35 @@ impl Backend for SecretServiceBackend {
         Ok(namespaces)
     }
 
+    fn for_each_namespace(
+        &self,
+        visit: &mut dyn FnMut(&str) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let collection = self.get_collection()?;
+        let items: Vec<Item> = self.search_own(&collection, HashMap::new())?;
+
+        // Attributes are fetched one item at a time; only the names already
+        // seen are kept, to skip the other keys of the same namespace.
+        let mut seen = HashSet::new();
+        for item in items {
+            let Ok(mut attrs) = item.get_attributes() else {
+                continue;
+            };
+            let Some(namespace) = attrs.remove("name") else {
+                continue;
+            };
+            if !seen.contains(&namespace) {
+                visit(&namespace)?;
+                seen.insert(namespace);
+            }
+        }
+        Ok(())
+    }
+
     fn list_secrets(&self, namespace: &str) -> Result<HashMap<EnvKey, EnvValue>, String> {
         let collection = self.get_collection()?;
-        let items: Vec<Item> = collection
-            .search_items(HashMap::from([("name", namespace)]))
-            .map_err(|e| format!("search_items failed: {e}"))?;
+        let items: Vec<Item> =
+            self.search_own(&collection, HashMap::from([("name", namespace)]))?;
 
         let mut secrets = HashMap::new();
         for item in items {
@@ -64,30 +249,272 @@ impl Backend for SecretServiceBackend {
         Ok(secrets)
     }
 
-    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+    fn list_keys(&self, namespace: &str) -> Result<Vec<EnvKey>, String> {
         let collection = self.get_collection()?;
-        collection
-            .create_item(
-                key,
-                HashMap::from([("name", namespace), ("key", key)]),
-                value.as_bytes(),
-                true,
-                "text/plain",
+        let items: Vec<Item> =
+            self.search_own(&collection, HashMap::from([("name", namespace)]))?;
+
+        // Attributes are readable without unlocking or transferring secrets.
+        let mut keys: Vec<EnvKey> = items
+            .iter()
+            .filter_map(|item| item.get_attributes().ok()?.get("key").cloned())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn list_all(&self) -> Result<HashMap<Namespace, HashMap<EnvKey, EnvValue>>, String> {
+        let collection = self.get_collection()?;
+        let items: Vec<Item> = self.search_own(&collection, HashMap::new())?;
+
+        let mut all: HashMap<Namespace, HashMap<EnvKey, EnvValue>> = HashMap::new();
+        for item in items {
+            let Ok(attrs) = item.get_attributes() else {
+                continue;
+            };
+            let (Some(namespace), Some(key)) = (attrs.get("name"), attrs.get("key")) else {
+                continue;
+            };
+            if let Ok(secret) = item.get_secret() {
+                let val = String::from_utf8(secret)
+                    .map_err(|e| format!("Secret for {key} is not valid UTF-8: {e}"))?;
+                all.entry(namespace.clone())
+                    .or_default()
+                    .insert(key.clone(), val);
+            }
+        }
+        Ok(all)
+    }
+
+    fn count_keys(&self) -> Result<HashMap<Namespace, usize>, String> {
+        let collection = self.get_collection()?;
+        let items: Vec<Item> = self.search_own(&collection, HashMap::new())?;
+
+        // Attributes only: no secret is transferred.  Duplicate items for
+        // one key count once, as in `list_keys`, and a namespace whose items
+        // lack a `key` attribute is kept with no keys, as `list_namespaces`
+        // lists it.
+        let mut keys: HashMap<Namespace, HashSet<EnvKey>> = HashMap::new();
+        for item in items {
+            let Ok(mut attrs) = item.get_attributes() else {
+                continue;
+            };
+            let Some(namespace) = attrs.remove("name") else {
+                continue;
+            };
+            let namespace_keys = keys.entry(namespace).or_default();
+            if let Some(key) = attrs.remove("key") {
+                namespace_keys.insert(key);
+            }
+        }
+        Ok(keys
+            .into_iter()
+            .map(|(namespace, keys)| (namespace, keys.len()))
+            .collect())
+    }
+
+    fn list_metadata(&self, namespace: &str) -> Result<HashMap<EnvKey, SecretMetadata>, String> {
+        let collection = self.get_collection()?;
+        let items: Vec<Item> =
+            self.search_own(&collection, HashMap::from([("name", namespace)]))?;
+
+        // Secret Service reports times as seconds since the Unix epoch; 0 means unknown.
+        let to_time = |secs: u64| (secs > 0).then(|| UNIX_EPOCH + Duration::from_secs(secs));
+
+        let mut metadata = HashMap::new();
+        for item in items {
+            let Ok(attrs) = item.get_attributes() else {
+                continue;
+            };
+            let Some(key) = attrs.get("key") else {
+                continue;
+            };
+            let meta = SecretMetadata {
+                created_at: item.get_created().ok().and_then(to_time),
+                updated_at: item.get_modified().ok().and_then(to_time),
+            };
+            metadata.insert(key.clone(), meta);
+        }
+        Ok(metadata)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            list_namespaces: true,
+            list_keys_without_values: true,
+            server_side_copy: false,
+            timestamps: true,
+            binary_values: true,
+        }
+    }
+
+    fn lock(&mut self) -> Result<bool, String> {
+        self.get_collection()?
+            .lock()
+            .map_err(|e| format!("Failed to lock SecretService collection: {e}"))?;
+        Ok(true)
+    }
+
+    fn unlock(&mut self) -> Result<bool, String> {
+        self.get_collection()?
+            .unlock()
+            .map_err(|e| format!("Failed to unlock SecretService collection: {e}"))?;
+        Ok(true)
+    }
+
+    fn health_check(&self) -> Result<(), String> {
+        self.get_collection().map(drop)
+    }
+
+    fn outdated_items(&self) -> Result<Vec<String>, String> {
+        let collection = self.get_collection()?;
+        Ok(self
+            .untagged_items(&collection)?
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect())
+    }
+
+    fn upgrade_items(&mut self) -> Result<usize, String> {
+        let collection = self.get_collection()?;
+        let mut tagged = 0;
+        for (item, _) in self.untagged_items(&collection)? {
+            let mut attributes = item
+                .get_attributes()
+                .map_err(|e| format!("Failed to read item attributes: {e}"))?;
+            attributes.insert(MARKER.0.to_string(), MARKER.1.to_string());
+            item.set_attributes(
+                attributes
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect(),
             )
+            .map_err(|e| format!("Failed to tag item: {e}"))?;
+            tagged += 1;
+        }
+        Ok(tagged)
+    }
+
+    fn is_locked(&self) -> Result<Option<bool>, String> {
+        self.get_collection()?
+            .is_locked()
+            .map(Some)
+            .map_err(|e| format!("Failed to query SecretService collection: {e}"))
+    }
+
+    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<bool, String> {
+        let collection = self.get_collection()?;
+        let lookup = HashMap::from([("name", namespace), ("key", key)]);
+        let mut attributes = lookup.clone();
+        for (name, value) in &self.extra_attributes {
+            attributes.insert(name, value);
+        }
+        attributes.insert(MARKER.0, MARKER.1);
+
+        // A single item that already has these attributes and this secret
+        // would be replaced by an identical one.
+        let existing = self.search_own(&collection, lookup.clone())?;
+        if let [item] = existing.as_slice()
+            && item.get_attributes().is_ok_and(|stored| {
+                attributes
+                    .iter()
+                    .all(|(name, value)| stored.get(*name).is_some_and(|v| v == value))
+            })
+            && item
+                .get_secret()
+                .is_ok_and(|secret| Zeroizing::new(secret).as_slice() == value.as_bytes())
+        {
+            return Ok(false);
+        }
+
+        let created = collection
+            .create_item(key, attributes, value.as_bytes(), true, "text/plain")
             .map_err(|e| format!("Failed to store secret: {e}"))?;
-        Ok(())
+
+        // `replace` only matches items with exactly the same attributes, so
+        // items stored with other extra attributes, or before the marker
+        // attribute existed, would linger as duplicates.
+        let stale: Vec<Item> = self
+            .search_own(&collection, lookup)?
+            .into_iter()
+            .filter(|item| *item != created)
+            .collect();
+        delete_items(&stale, &format!("{namespace}.{key}"))?;
+        Ok(true)
     }
 
-    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<(), String> {
+    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<usize, String> {
         let collection = self.get_collection()?;
-        let items: Vec<Item> = collection
-            .search_items(HashMap::from([("name", namespace), ("key", key)]))
-            .map_err(|e| format!("search_items failed: {e}"))?;
-        for item in items {
-            if let Err(e) = item.delete() {
-                eprintln!("Failed to delete {namespace}.{key}: {e}");
+        let items: Vec<Item> = self.search_own(
+            &collection,
+            HashMap::from([("name", namespace), ("key", key)]),
+        )?;
+
+        // Duplicate items can exist (e.g. written by concurrent envchain
+        // runs); delete them all and only fail if none could be removed.
+        delete_items(&items, &format!("{namespace}.{key}"))
+    }
+
+    fn delete_secrets(&mut self, namespace: &str, keys: &[String]) -> Result<usize, String> {
+        let collection = self.get_collection()?;
+        // One search for the namespace instead of one per key.
+        let items: Vec<Item> = self
+            .search_own(&collection, HashMap::from([("name", namespace)]))?
+            .into_iter()
+            .filter(|item| {
+                item.get_attributes()
+                    .is_ok_and(|attrs| attrs.get("key").is_some_and(|key| keys.contains(key)))
+            })
+            .collect();
+        delete_items(&items, namespace)
+    }
+
+    fn delete_namespace(&mut self, namespace: &str) -> Result<usize, String> {
+        let collection = self.get_collection()?;
+        let items: Vec<Item> =
+            self.search_own(&collection, HashMap::from([("name", namespace)]))?;
+        delete_items(&items, namespace)
+    }
+
+    /// Retags each item in place rather than recreating it, so its label,
+    /// content type, extra attributes and creation time are kept.  Items in
+    /// `dst` with a key that moves in are deleted afterwards, once the
+    /// moved items are in place.
+    fn rename_namespace(&mut self, src: &str, dst: &str) -> Result<(), String> {
+        let collection = self.get_collection()?;
+        let search =
+            |namespace: &str| self.search_own(&collection, HashMap::from([("name", namespace)]));
+        let moving = search(src)?;
+        let mut keys = HashSet::new();
+        let mut retagged = Vec::new();
+        for item in &moving {
+            let mut attributes = item
+                .get_attributes()
+                .map_err(|e| format!("Failed to read item attributes: {e}"))?;
+            if let Some(key) = attributes.get("key") {
+                keys.insert(key.clone());
             }
+            attributes.insert("name".to_string(), dst.to_string());
+            retagged.push(attributes);
         }
-        Ok(())
+        let displaced: Vec<Item> = search(dst)?
+            .into_iter()
+            .filter(|item| {
+                item.get_attributes()
+                    .is_ok_and(|attrs| attrs.get("key").is_some_and(|key| keys.contains(key)))
+            })
+            .collect();
+
+        for (item, attributes) in moving.iter().zip(&retagged) {
+            item.set_attributes(
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                    .collect(),
+            )
+            .map_err(|e| format!("Failed to move an item of {src} to {dst}: {e}"))?;
+        }
+        delete_items(&displaced, dst).map(drop)
     }
 }
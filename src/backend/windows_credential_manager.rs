@@ -5,12 +5,13 @@
 //!
 //! Credentials are stored with target names: envchain:{namespace}:{key}
 
-use super::{Backend, EnvKey, EnvValue, Namespace};
+use super::{Backend, Capabilities, EnvKey, EnvValue, Namespace};
 use keyring_core::Error as KeyringError;
 use keyring_core::api::CredentialStoreApi;
 use std::collections::HashMap;
 use std::sync::Arc;
 use windows_native_keyring_store::Store;
+use zeroize::Zeroizing;
 
 const TARGET_PREFIX: &str = "envchain:";
 
@@ -44,6 +45,20 @@ impl WindowsCredentialManagerBackend {
 }
 
 impl Backend for WindowsCredentialManagerBackend {
+    fn backend_name(&self) -> &'static str {
+        "wincred"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            list_namespaces: true,
+            list_keys_without_values: false,
+            server_side_copy: false,
+            timestamps: false,
+            binary_values: false,
+        }
+    }
+
     fn list_namespaces(&self) -> Result<Vec<Namespace>, String> {
         // Search for all credentials starting with "envchain:"
         let mut search_spec: HashMap<&str, &str> = HashMap::new();
@@ -115,7 +130,7 @@ impl Backend for WindowsCredentialManagerBackend {
         Ok(secrets)
     }
 
-    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+    fn set_secret(&mut self, namespace: &str, key: &str, value: &str) -> Result<bool, String> {
         // build(service, user, _) produces target_name "{prefix}{user}{divider}{service}{suffix}"
         // so build(key, namespace, _) => "envchain:{namespace}:{key}"
         let entry = self
@@ -123,25 +138,30 @@ impl Backend for WindowsCredentialManagerBackend {
             .build(key, namespace, None)
             .map_err(|e| format!("Failed to build credential entry: {e}"))?;
 
+        if entry
+            .get_password()
+            .is_ok_and(|stored| Zeroizing::new(stored).as_str() == value)
+        {
+            return Ok(false);
+        }
         entry
             .set_password(value)
             .map_err(|e| format!("Failed to set password: {e}"))?;
 
-        Ok(())
+        Ok(true)
     }
 
-    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<(), String> {
+    fn delete_secret(&mut self, namespace: &str, key: &str) -> Result<usize, String> {
         // build(key, namespace, _) => target_name "envchain:{namespace}:{key}"
         let entry = self
             .store
             .build(key, namespace, None)
             .map_err(|e| format!("Failed to build credential entry: {e}"))?;
 
-        entry.delete_credential().map_err(|e| match e {
-            KeyringError::NoEntry => format!("Credential not found for {namespace}:{key}"),
-            _ => format!("Failed to delete credential: {e}"),
-        })?;
-
-        Ok(())
+        match entry.delete_credential() {
+            Ok(()) => Ok(1),
+            Err(KeyringError::NoEntry) => Ok(0),
+            Err(e) => Err(format!("Failed to delete credential: {e}")),
+        }
     }
 }
@@ -0,0 +1,372 @@
+//! Shell completion scripts.
+//!
+//! `get-completions` prints clap's static script, which completes
+//! subcommands and options.  With `--dynamic`, shell code is appended that
+//! calls back into envchain (`complete-namespaces`, `complete-keys`) to
+//! complete namespace and key names from the backend.  Names are read one
+//! per line, so names containing spaces complete as a single word.
+
+use clap_complete::Shell;
+use std::io::Write;
+
+/// Subcommands whose first argument is a namespace.
+const NAMESPACE_COMMANDS: &[&str] = &[
+    "set", "unset", "printenv", "render", "rotate", "list", "clear", "copy", "import",
+];
+
+/// Subcommands whose arguments after the namespace are key names: either
+/// any number of them, or exactly one.
+const KEYS_COMMANDS: &[&str] = &["set", "unset"];
+const KEY_COMMANDS: &[&str] = &["printenv", "rotate"];
+
+/// Print the completion script for `shell`, optionally with the dynamic
+/// namespace and key completion.
+pub fn generate(shell: Shell, cmd: &mut clap::Command, dynamic: bool) -> Result<(), String> {
+    let name = cmd.get_name().to_string();
+    if !dynamic {
+        clap_complete::generate(shell, cmd, name, &mut std::io::stdout());
+        return Ok(());
+    }
+
+    let glue = match shell {
+        Shell::Bash => bash_glue(cmd),
+        Shell::Zsh => zsh_glue(cmd),
+        Shell::Fish => fish_glue(cmd),
+        _ => {
+            return Err(format!(
+                "--dynamic supports bash, zsh and fish, not {shell}"
+            ));
+        }
+    };
+    let mut script = install_instructions(shell, &name).into_bytes();
+    clap_complete::generate(shell, cmd, name, &mut script);
+    script.extend_from_slice(glue.as_bytes());
+    std::io::stdout()
+        .write_all(&script)
+        .map_err(|e| format!("Failed to write completion script: {e}"))
+}
+
+/// Comment block opening a dynamic script, saying how to install it.
+fn install_instructions(shell: Shell, name: &str) -> String {
+    let install = match shell {
+        Shell::Bash => {
+            format!("Add to ~/.bashrc:\n#   source <({name} get-completions bash --dynamic)")
+        }
+        Shell::Zsh => format!(
+            "Add to ~/.zshrc, after compinit:\n#   source <({name} get-completions zsh --dynamic)"
+        ),
+        _ => format!(
+            "Save it as a fish completion file:\n#   {name} get-completions fish --dynamic > ~/.config/fish/completions/{name}.fish"
+        ),
+    };
+    format!(
+        "# {name} completion with namespace and key names from the backend.\n\
+         # {install}\n\
+         # Names are looked up with `{name} complete-namespaces` and `{name} complete-keys`,\n\
+         # using the backend configured by ENVCHAIN_BACKEND or ENVCHAIN_PROFILE.\n\n"
+    )
+}
+
+/// Long and short forms of every option that takes a value, so that the
+/// shell code can skip option values when counting positional arguments.
+fn value_options(cmd: &clap::Command) -> Vec<String> {
+    let mut options = Vec::new();
+    let mut commands = vec![cmd];
+    while let Some(command) = commands.pop() {
+        for arg in command.get_arguments() {
+            if arg.is_positional() || !arg.get_action().takes_values() {
+                continue;
+            }
+            options.extend(arg.get_long().map(|long| format!("--{long}")));
+            options.extend(arg.get_short().map(|short| format!("-{short}")));
+        }
+        commands.extend(command.get_subcommands());
+    }
+    options.sort();
+    options.dedup();
+    options
+}
+
+/// Shell function shared by bash and zsh that prints what the word being
+/// completed is: `top` (a subcommand or an exec-mode namespace),
+/// `namespaces`, `keys NAMESPACE`, or nothing.
+fn context_function(name: &str, cmd: &clap::Command) -> String {
+    format!(
+        r#"
+__{name}_context() {{
+    local word skip= pos=0 sub= ns=
+    for word in "$@"; do
+        if [ -n "$skip" ]; then
+            [ "$word" = "=" ] || skip=
+            continue
+        fi
+        case "$word" in
+            {options}) skip=1; continue ;;
+            -*) continue ;;
+        esac
+        if [ $pos -eq 0 ]; then
+            case "$word" in
+                {namespace_commands}) sub=$word ;;
+                *) sub=exec ;;
+            esac
+        elif [ $pos -eq 1 ]; then
+            ns=$word
+        fi
+        pos=$((pos + 1))
+    done
+    case "$sub:$pos" in
+        :0) echo top ;;
+        {namespace_positions}) echo namespaces ;;
+        {key_positions}) echo "keys $ns" ;;
+    esac
+}}
+"#,
+        options = value_options(cmd).join("|"),
+        namespace_commands = NAMESPACE_COMMANDS.join("|"),
+        namespace_positions = NAMESPACE_COMMANDS
+            .iter()
+            .map(|c| format!("{c}:1"))
+            .collect::<Vec<_>>()
+            .join("|"),
+        key_positions = KEYS_COMMANDS
+            .iter()
+            .map(|c| format!("{c}:*"))
+            .chain(KEY_COMMANDS.iter().map(|c| format!("{c}:2")))
+            .collect::<Vec<_>>()
+            .join("|"),
+    )
+}
+
+fn bash_glue(cmd: &clap::Command) -> String {
+    let name = cmd.get_name();
+    format!(
+        r#"{context}
+# Strip the quotes and backslashes from a word on the command line.
+__{name}_dequote() {{
+    local word=${{1//\\/}}
+    word=${{word//\'/}}
+    printf '%s' "${{word//\"/}}"
+}}
+
+# Add each line on stdin that starts with $2 to COMPREPLY, prefixed with $1
+# and escaped, so that a name containing spaces stays one word.
+__{name}_reply() {{
+    local candidate quoted
+    while IFS= read -r candidate; do
+        [[ $candidate == "$2"* ]] || continue
+        printf -v quoted %q "$1$candidate"
+        COMPREPLY+=("$quoted")
+    done
+}}
+
+# Complete the last of comma-separated namespaces.
+__{name}_namespaces() {{
+    local word
+    word=$(__{name}_dequote "$1")
+    __{name}_reply "${{word%"${{word##*,}}"}}" "${{word##*,}}" < <({name} complete-namespaces 2>/dev/null)
+}}
+
+_{name}_dynamic() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" context
+    context=$(__{name}_context "${{COMP_WORDS[@]:1:COMP_CWORD-1}}")
+    case "$context" in
+        top)
+            _{name} "$@"
+            [[ $cur == -* ]] || __{name}_namespaces "$cur"
+            ;;
+        namespaces)
+            COMPREPLY=()
+            __{name}_namespaces "$cur"
+            ;;
+        keys\ *)
+            COMPREPLY=()
+            __{name}_reply "" "$(__{name}_dequote "$cur")" \
+                < <({name} complete-keys "$(__{name}_dequote "${{context#keys }}")" 2>/dev/null)
+            ;;
+        *)
+            _{name} "$@"
+            ;;
+    esac
+}}
+
+complete -F _{name}_dynamic -o bashdefault -o default {name}
+"#,
+        context = context_function(name, cmd),
+    )
+}
+
+fn zsh_glue(cmd: &clap::Command) -> String {
+    let name = cmd.get_name();
+    format!(
+        r#"{context}
+# Complete the last of comma-separated namespaces.
+__{name}_namespaces() {{
+    compset -P '*,'
+    compadd -- ${{(f)"$({name} complete-namespaces 2>/dev/null)"}}
+}}
+
+_{name}_dynamic() {{
+    local context
+    context=$(__{name}_context "${{(@)words[2,CURRENT-1]}}")
+    case "$context" in
+        top)
+            _{name} "$@"
+            [[ $PREFIX == -* ]] || __{name}_namespaces
+            ;;
+        namespaces)
+            __{name}_namespaces
+            ;;
+        keys\ *)
+            compadd -- ${{(f)"$({name} complete-keys "${{(Q)${{context#keys }}}}" 2>/dev/null)"}}
+            ;;
+        *)
+            _{name} "$@"
+            ;;
+    esac
+}}
+
+compdef _{name}_dynamic {name}
+"#,
+        context = context_function(name, cmd),
+    )
+}
+
+fn fish_glue(cmd: &clap::Command) -> String {
+    let name = cmd.get_name();
+    format!(
+        r#"
+# What the word being completed is: top, namespaces, "keys NAMESPACE" or nothing.
+function __{name}_context
+    set -l words (commandline -opc)
+    set -e words[1]
+    set -l skip 0
+    set -l pos 0
+    set -l sub
+    set -l ns
+    for word in $words
+        if test $skip = 1
+            test "$word" = "="; or set skip 0
+            continue
+        end
+        switch $word
+            case {options}
+                set skip 1
+                continue
+            case '-*'
+                continue
+        end
+        if test $pos = 0
+            switch $word
+                case {namespace_commands}
+                    set sub $word
+                case '*'
+                    set sub exec
+            end
+        else if test $pos = 1
+            set ns $word
+        end
+        set pos (math $pos + 1)
+    end
+    switch "$sub:$pos"
+        case ':0'
+            echo top
+        case {namespace_positions}
+            echo namespaces
+        case {key_positions}
+            echo "keys $ns"
+    end
+end
+
+# Complete the last of comma-separated namespaces.
+function __{name}_namespaces
+    set -l prefix (string replace -r '[^,]*$' '' -- (commandline -ct))
+    for namespace in ({name} complete-namespaces 2>/dev/null)
+        echo $prefix$namespace
+    end
+end
+
+complete -c {name} -n 'contains -- (__{name}_context) top namespaces' -f -a '(__{name}_namespaces)'
+complete -c {name} -n '__{name}_context | string match -q "keys *"' -f -a '({name} complete-keys (__{name}_context | string replace "keys " "") 2>/dev/null)'
+"#,
+        options = value_options(cmd).join(" "),
+        namespace_commands = NAMESPACE_COMMANDS.join(" "),
+        namespace_positions = NAMESPACE_COMMANDS
+            .iter()
+            .map(|c| format!("'{c}:1'"))
+            .collect::<Vec<_>>()
+            .join(" "),
+        key_positions = KEYS_COMMANDS
+            .iter()
+            .map(|c| format!("'{c}:*'"))
+            .chain(KEY_COMMANDS.iter().map(|c| format!("'{c}:2'")))
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// Stand-in for envchain's helper subcommands, with a namespace and a
+    /// key that contain spaces.
+    const FAKE_ENVCHAIN: &str = r#"
+envchain() {
+    case "$1" in
+        complete-namespaces) printf '%s\n' 'my ns' other ;;
+        complete-keys) [ "$2" = 'my ns' ] && printf '%s\n' 'MY KEY' MY_TOKEN ;;
+    esac
+}
+"#;
+
+    /// Run the bash completion for `words`, the last being the word being
+    /// completed, and return COMPREPLY.
+    fn complete_bash(words: &str) -> Vec<String> {
+        let script = format!(
+            "{FAKE_ENVCHAIN}{}\nCOMP_WORDS=({words})\nCOMP_CWORD=$((${{#COMP_WORDS[@]}} - 1))\n\
+             _envchain_dynamic\nprintf '%s\\n' \"${{COMPREPLY[@]}}\"\n",
+            bash_glue(&crate::Cli::command())
+        );
+        let output = std::process::Command::new("bash")
+            .args(["--norc", "-c", &script])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn bash_completes_names_with_spaces_as_one_word() {
+        assert_eq!(complete_bash(r"envchain set my"), [r"my\ ns"]);
+        assert_eq!(
+            complete_bash(r"envchain set other,my\\\ n"),
+            [r"other\,my\ ns"]
+        );
+        assert_eq!(
+            complete_bash(r"envchain printenv 'my\ ns' MY"),
+            [r"MY\ KEY", "MY_TOKEN"]
+        );
+        assert_eq!(
+            complete_bash(r#"envchain set "'my ns'" MY\\\ "#),
+            [r"MY\ KEY"]
+        );
+    }
+
+    #[test]
+    fn zsh_and_fish_read_names_one_per_line() {
+        let cmd = crate::Cli::command();
+        let zsh = zsh_glue(&cmd);
+        assert!(zsh.contains(r#"compadd -- ${(f)"$(envchain complete-namespaces"#));
+        assert!(zsh.contains(r#"complete-keys "${(Q)${context#keys }}""#));
+        // fish splits command substitutions on newlines only, and
+        // `commandline -opc` hands over the namespace unquoted.
+        let fish = fish_glue(&cmd);
+        assert!(fish.contains("for namespace in (envchain complete-namespaces"));
+        assert!(fish.contains("set -l words (commandline -opc)"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Values derived on demand from a stored seed.
+//!
+//! `set --derive totp` stores a record instead of a static secret:
+//!
+//! ```text
+//! envchain-derive:totp:sha1:6:30:JBSWY3DPEHPK3PXP
+//! ```
+//!
+//! Whatever injects variables (exec mode, `printenv`, `list --format
+//! env|export`) recognises the `envchain-derive:` prefix and uses the value
+//! the scheme yields right now, here an RFC 6238 one-time code.  Everything
+//! else (`list -v`, `copy`, `rotate`) sees the record, so the seed travels
+//! with the namespace like any other secret.
+
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+const PREFIX: &str = "envchain-derive:";
+
+/// How a derived value is computed from its seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Scheme {
+    /// Time-based one-time password: HMAC-SHA1 of the seed and the current
+    /// 30-second counter, truncated to 6 digits (RFC 6238)
+    Totp,
+}
+
+/// The record `set --derive` stores for `seed`, a base32 string as shown by
+/// services that hand out TOTP secrets.  Spaces, dashes and padding are
+/// ignored.
+pub fn encode(scheme: Scheme, seed: &str) -> Result<Zeroizing<String>, String> {
+    let Scheme::Totp = scheme;
+    let normalized: Zeroizing<String> = Zeroizing::new(
+        seed.chars()
+            .filter(|c| !matches!(c, ' ' | '-' | '='))
+            .map(|c| c.to_ascii_uppercase())
+            .collect(),
+    );
+    match base32_decode(&normalized) {
+        Some(bytes) if !bytes.is_empty() => {}
+        _ => return Err("TOTP seed must be a non-empty base32 string".to_string()),
+    }
+    Ok(Zeroizing::new(format!(
+        "{PREFIX}totp:sha1:6:30:{}",
+        normalized.as_str()
+    )))
+}
+
+/// The value to inject for a stored `value`: the current derived value for
+/// a `set --derive` record, `None` for an ordinary secret.
+pub fn resolve(value: &str) -> Result<Option<Zeroizing<String>>, String> {
+    let Some(record) = value.strip_prefix(PREFIX) else {
+        return Ok(None);
+    };
+    let invalid = || format!("Malformed derived value `{PREFIX}{}...`", scheme_of(record));
+    let mut fields = record.splitn(5, ':');
+    let (Some("totp"), Some("sha1"), Some(digits), Some(period), Some(seed)) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid());
+    };
+    let digits: u32 = digits
+        .parse()
+        .ok()
+        .filter(|d| (1..=9).contains(d))
+        .ok_or_else(invalid)?;
+    let period: u64 = period.parse().ok().filter(|p| *p > 0).ok_or_else(invalid)?;
+    let seed = base32_decode(seed).ok_or_else(invalid)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("System clock is before 1970: {e}"))?
+        .as_secs();
+    Ok(Some(totp(&seed, digits, period, now)))
+}
+
+/// The scheme name of a record, for error messages that must not echo the
+/// seed.
+fn scheme_of(record: &str) -> &str {
+    record.split(':').next().unwrap_or_default()
+}
+
+/// RFC 6238 code for `seed` at Unix time `now`.
+fn totp(seed: &[u8], digits: u32, period: u64, now: u64) -> Zeroizing<String> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(seed).expect("HMAC accepts keys of any length");
+    mac.update(&(now / period).to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = usize::from(digest[digest.len() - 1] & 0x0f);
+    let code = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    Zeroizing::new(format!(
+        "{:0width$}",
+        code % 10u32.pow(digits),
+        width = digits as usize
+    ))
+}
+
+/// Decode unpadded RFC 4648 base32, or `None` if `s` has other characters.
+fn base32_decode(s: &str) -> Option<Zeroizing<Vec<u8>>> {
+    let mut out = Zeroizing::new(Vec::with_capacity(s.len() * 5 / 8));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u32::from(v);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    Some(out)
+}
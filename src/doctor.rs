@@ -0,0 +1,168 @@
+//! `doctor`: diagnose the local envchain setup.
+//!
+//! Each check reports at most one problem, together with the repair
+//! `doctor --fix` may make for it.  Only repairs that cannot lose data are
+//! offered: nothing here deletes or overwrites a file.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// A repair `doctor --fix` knows how to make.
+pub enum Fix {
+    /// Create the directory, owner only
+    CreateDir(PathBuf),
+    /// Remove group and other access from a file or directory
+    RestrictToOwner(PathBuf),
+    /// Generate a native age identity, writing its recipient if given
+    #[cfg(feature = "age-backend")]
+    GenerateIdentity {
+        identity: PathBuf,
+        recipient: Option<PathBuf>,
+    },
+}
+
+impl Fix {
+    /// Short imperative description, used to ask for confirmation.
+    pub fn describe(&self) -> String {
+        match self {
+            Self::CreateDir(dir) => format!("Create {} (mode 700)", dir.display()),
+            Self::RestrictToOwner(path) => {
+                let mode = if path.is_dir() { "700" } else { "600" };
+                format!("Set the mode of {} to {mode}", path.display())
+            }
+            #[cfg(feature = "age-backend")]
+            Self::GenerateIdentity { identity, .. } => {
+                format!("Generate a new age identity at {}", identity.display())
+            }
+        }
+    }
+
+    pub fn apply(&self) -> Result<(), String> {
+        match self {
+            Self::CreateDir(dir) => {
+                fs::create_dir_all(dir)
+                    .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+                restrict_to_owner(dir)
+            }
+            Self::RestrictToOwner(path) => restrict_to_owner(path),
+            #[cfg(feature = "age-backend")]
+            Self::GenerateIdentity {
+                identity,
+                recipient,
+            } => crate::backend::age::generate_identity(identity, recipient.as_deref()).map(drop),
+        }
+    }
+}
+
+/// Something `doctor` found wrong, and how to repair it if it can.
+pub struct Problem {
+    pub description: String,
+    pub fix: Option<Fix>,
+}
+
+/// The permission bits of `meta` if group or other users have any access.
+/// Windows ACLs are not inspected.
+#[cfg(unix)]
+fn loose_mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = meta.permissions().mode() & 0o777;
+    (mode & 0o077 != 0).then_some(mode)
+}
+
+#[cfg(not(unix))]
+fn loose_mode(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn restrict_to_owner(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if path.is_dir() { 0o700 } else { 0o600 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|e| format!("Failed to set permissions on {}: {e}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn restrict_to_owner(_path: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+/// Check that the envchain config directory exists and is owner only.
+pub fn check_config_dir(dir: &Path) -> Option<Problem> {
+    match fs::metadata(dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Some(Problem {
+            description: format!("config directory {} does not exist", dir.display()),
+            fix: Some(Fix::CreateDir(dir.to_path_buf())),
+        }),
+        Err(e) => Some(Problem {
+            description: format!("cannot read config directory {}: {e}", dir.display()),
+            fix: None,
+        }),
+        Ok(meta) => loose_mode(&meta).map(|mode| Problem {
+            description: format!(
+                "config directory {} is accessible by other users (mode {mode:03o})",
+                dir.display()
+            ),
+            fix: Some(Fix::RestrictToOwner(dir.to_path_buf())),
+        }),
+    }
+}
+
+/// Check the age identity at `identity`.  A missing identity is only
+/// generated at the default location, and never next to a secrets file it
+/// could not decrypt.
+#[cfg(feature = "age-backend")]
+pub fn check_identity(
+    identity: &Path,
+    is_default: bool,
+    secrets: &Path,
+    recipient: Option<PathBuf>,
+) -> Option<Problem> {
+    match fs::metadata(identity) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let has_secrets = fs::metadata(secrets).is_ok_and(|m| m.len() > 0);
+            let description = if has_secrets {
+                format!(
+                    "age identity {} does not exist, but {} holds secrets; \
+                     restore the identity that encrypted them",
+                    identity.display(),
+                    secrets.display()
+                )
+            } else {
+                format!("age identity {} does not exist", identity.display())
+            };
+            Some(Problem {
+                description,
+                fix: (is_default && !has_secrets).then(|| Fix::GenerateIdentity {
+                    identity: identity.to_path_buf(),
+                    recipient,
+                }),
+            })
+        }
+        Err(e) => Some(Problem {
+            description: format!("cannot read age identity {}: {e}", identity.display()),
+            fix: None,
+        }),
+        Ok(meta) => loose_mode(&meta).map(|mode| Problem {
+            description: format!(
+                "age identity {} is readable by other users (mode {mode:03o})",
+                identity.display()
+            ),
+            fix: Some(Fix::RestrictToOwner(identity.to_path_buf())),
+        }),
+    }
+}
+
+/// Ask a yes/no question on stderr; anything but `y`/`yes` is a no.
+pub fn confirm(question: &str) -> Result<bool, String> {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| format!("Failed to read answer: {e}"))?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
@@ -0,0 +1,409 @@
+//! Importing variables from files, and the nested-config conversions used
+//! by `import --flatten-sep` and `list --nest`.
+//!
+//! `import --null-input` reads NUL-delimited `KEY=VALUE` records instead,
+//! so values may contain newlines.  A `.csv` file holds `namespace,key,value`
+//! rows, the format `list --format csv` writes.
+
+use crate::backend::{Backend, EnvKey, Namespace};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// One variable read from an import file.
+pub struct ImportEntry {
+    pub namespace: Namespace,
+    pub key: EnvKey,
+    pub value: Zeroizing<String>,
+}
+
+/// What to do with an entry whose variable already exists with another
+/// value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Store nothing at all
+    Fail,
+    /// Leave the existing value
+    Keep,
+    /// Replace the existing value
+    Overwrite,
+    /// Ask for each variable on a terminal; without one, behave like `fail`
+    Prompt,
+}
+
+/// How `store` dealt with the entries.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StoreSummary {
+    /// Entries stored, new or overwritten
+    pub written: usize,
+    /// Namespaces written to
+    pub namespaces: usize,
+    /// Existing values replaced
+    pub overwritten: usize,
+    /// Existing values left in place
+    pub kept: usize,
+    /// Entries that matched the stored value already
+    pub unchanged: usize,
+}
+
+impl std::fmt::Display for StoreSummary {
+    /// The non-zero conflict counts, as ` (1 overwritten, 2 kept)`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = [
+            (self.overwritten, "overwritten"),
+            (self.kept, "kept"),
+            (self.unchanged, "unchanged"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, label)| format!("{count} {label}"))
+        .collect();
+        if parts.is_empty() {
+            return Ok(());
+        }
+        write!(f, " ({})", parts.join(", "))
+    }
+}
+
+/// Ask whether to overwrite `namespace.key`, showing only the lengths of
+/// the two values.  `None` aborts.
+fn ask_overwrite(entry: &ImportEntry, current: &str) -> Result<Option<bool>, String> {
+    eprintln!(
+        "{}.{} already exists: current ******** ({} chars), new ******** ({} chars)",
+        entry.namespace,
+        entry.key,
+        current.chars().count(),
+        entry.value.chars().count()
+    );
+    loop {
+        eprint!("[k]eep, [o]verwrite or [a]bort? ");
+        let mut answer = String::new();
+        let read = std::io::stdin()
+            .read_line(&mut answer)
+            .map_err(|e| format!("Failed to read answer: {e}"))?;
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "k" | "keep" => return Ok(Some(false)),
+            "o" | "overwrite" => return Ok(Some(true)),
+            "a" | "abort" => return Ok(None),
+            _ if read == 0 => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Write imported entries to the backend, resolving entries whose variable
+/// already holds another value as `on_conflict` says.  Entries that match
+/// the stored value are left alone.
+///
+/// Every decision is made before anything is written, so a `fail` conflict
+/// or an aborted prompt stores nothing.
+pub fn store(
+    backend: &mut dyn Backend,
+    entries: &[ImportEntry],
+    on_conflict: OnConflict,
+) -> Result<StoreSummary, String> {
+    let on_conflict = match on_conflict {
+        OnConflict::Prompt if !std::io::stdin().is_terminal() => OnConflict::Fail,
+        other => other,
+    };
+    let mut existing: HashMap<&str, HashMap<EnvKey, Zeroizing<String>>> = HashMap::new();
+    let mut summary = StoreSummary::default();
+    let mut writes = Vec::new();
+    for entry in entries {
+        if !existing.contains_key(entry.namespace.as_str()) {
+            let values = backend
+                .list_secrets(&entry.namespace)?
+                .into_iter()
+                .map(|(key, value)| (key, Zeroizing::new(value)))
+                .collect();
+            existing.insert(&entry.namespace, values);
+        }
+        let Some(current) = existing[entry.namespace.as_str()].get(&entry.key) else {
+            writes.push(entry);
+            continue;
+        };
+        if *current == entry.value {
+            summary.unchanged += 1;
+            continue;
+        }
+        let overwrite = match on_conflict {
+            OnConflict::Fail => {
+                return Err(format!(
+                    "{}.{} already exists; pass --force to overwrite or --on-conflict to choose",
+                    entry.namespace, entry.key
+                ));
+            }
+            OnConflict::Keep => false,
+            OnConflict::Overwrite => true,
+            OnConflict::Prompt => {
+                ask_overwrite(entry, current)?.ok_or("Aborted; nothing was stored")?
+            }
+        };
+        if overwrite {
+            summary.overwritten += 1;
+            writes.push(entry);
+        } else {
+            summary.kept += 1;
+        }
+    }
+
+    for entry in &writes {
+        backend.set_secret(&entry.namespace, &entry.key, &entry.value)?;
+    }
+    backend.flush()?;
+
+    let mut namespaces: Vec<&str> = writes.iter().map(|e| e.namespace.as_str()).collect();
+    namespaces.sort();
+    namespaces.dedup();
+    summary.written = writes.len();
+    summary.namespaces = namespaces.len();
+    Ok(summary)
+}
+
+/// Read a JSON or TOML document (TOML if the extension is `.toml`) and
+/// flatten it into `namespace`, joining nested keys with `sep`.
+pub fn parse_structured(
+    path: &Path,
+    namespace: &str,
+    sep: &str,
+) -> Result<Vec<ImportEntry>, String> {
+    let text = Zeroizing::new(
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?,
+    );
+    let is_toml = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+    let document: Value = if is_toml {
+        toml::from_str(&text).map_err(|e| format!("Failed to parse {}: {e}", path.display()))?
+    } else {
+        serde_json::from_str(&text)
+            .map_err(|e| format!("Failed to parse {}: {e}", path.display()))?
+    };
+    if !document.is_object() {
+        return Err(format!(
+            "{} must contain an object at the top level",
+            path.display()
+        ));
+    }
+
+    let mut flat = Vec::new();
+    flatten(&document, sep, &mut String::new(), &mut flat);
+    Ok(flat
+        .into_iter()
+        .map(|(key, value)| ImportEntry {
+            namespace: namespace.to_string(),
+            key,
+            value,
+        })
+        .collect())
+}
+
+/// Collect the leaves of `value` as `(PARENT{sep}CHILD, value)` pairs.
+/// Array elements use their index as the key; nulls are skipped.
+fn flatten(
+    value: &Value,
+    sep: &str,
+    prefix: &mut String,
+    out: &mut Vec<(String, Zeroizing<String>)>,
+) {
+    let mut descend = |key: &str, child: &Value, prefix: &mut String| {
+        let len = prefix.len();
+        if !prefix.is_empty() {
+            prefix.push_str(sep);
+        }
+        prefix.push_str(key);
+        flatten(child, sep, prefix, out);
+        prefix.truncate(len);
+    };
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                descend(key, child, prefix);
+            }
+        }
+        Value::Array(items) => {
+            for (i, child) in items.iter().enumerate() {
+                descend(&i.to_string(), child, prefix);
+            }
+        }
+        Value::Null => {}
+        Value::String(s) => out.push((prefix.clone(), Zeroizing::new(s.clone()))),
+        other => out.push((prefix.clone(), Zeroizing::new(other.to_string()))),
+    }
+}
+
+/// Read NUL-delimited `KEY=VALUE` records from `path` (`-` for stdin).
+/// Everything after the first `=` is the value, newlines included; a
+/// trailing NUL is optional.
+pub fn parse_null(path: &Path, namespace: &str) -> Result<Vec<ImportEntry>, String> {
+    let data = Zeroizing::new(if path == Path::new("-") {
+        let mut buf = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut buf)
+            .map_err(|e| format!("Failed to read stdin: {e}"))?;
+        buf
+    } else {
+        fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?
+    });
+
+    let mut entries: Vec<ImportEntry> = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, record) in data.split(|&b| b == 0).enumerate() {
+        if record.is_empty() {
+            continue;
+        }
+        let record = std::str::from_utf8(record)
+            .map_err(|_| format!("Record {} is not valid UTF-8", i + 1))?;
+        let Some((key, value)) = record.split_once('=').filter(|(key, _)| !key.is_empty()) else {
+            return Err(format!("Record {} is not of the form KEY=VALUE", i + 1));
+        };
+        if !seen.insert(key.to_string()) {
+            return Err(format!("{key} appears more than once in the input"));
+        }
+        entries.push(ImportEntry {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value: Zeroizing::new(value.to_string()),
+        });
+    }
+    Ok(entries)
+}
+
+/// Read `namespace,key,value` (or `key,value`) rows from a CSV file, with
+/// an optional header row.  Rows belonging to another namespace are
+/// skipped, so one export can be imported a namespace at a time.
+pub fn parse_csv(path: &Path, namespace: &str) -> Result<Vec<ImportEntry>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+    let mut entries: Vec<ImportEntry> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut skipped = 0;
+    for (i, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| format!("Failed to parse {}: {e}", path.display()))?;
+        let fields: Vec<&str> = record.iter().collect();
+        let is_header = |names: &[&str]| {
+            fields.len() == names.len()
+                && fields
+                    .iter()
+                    .zip(names)
+                    .all(|(field, name)| field.trim().eq_ignore_ascii_case(name))
+        };
+        if i == 0 && (is_header(&["namespace", "key", "value"]) || is_header(&["key", "value"])) {
+            continue;
+        }
+        let (key, value) = match fields[..] {
+            [ns, key, value] if ns == namespace => (key, value),
+            [_, _, _] => {
+                skipped += 1;
+                continue;
+            }
+            [key, value] => (key, value),
+            _ => {
+                return Err(format!(
+                    "Row {} of {} has {} columns; expected namespace,key,value",
+                    i + 1,
+                    path.display(),
+                    fields.len()
+                ));
+            }
+        };
+        if key.is_empty() {
+            return Err(format!(
+                "Row {} of {} has an empty key",
+                i + 1,
+                path.display()
+            ));
+        }
+        if !seen.insert(key.to_string()) {
+            return Err(format!("{key} appears more than once in the input"));
+        }
+        entries.push(ImportEntry {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value: Zeroizing::new(value.to_string()),
+        });
+    }
+    if skipped > 0 {
+        eprintln!("Skipped {skipped} rows for other namespaces");
+    }
+    Ok(entries)
+}
+
+/// Rebuild a nested JSON object from flat keys split on `sep`.
+///
+/// Fails if a key is both a value and a parent (e.g. `DB` and `DB_HOST`).
+pub fn nest<'a>(
+    pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+    sep: &str,
+) -> Result<Value, String> {
+    let mut root = Map::new();
+    for (key, value) in pairs {
+        let mut parts: Vec<&str> = key.split(sep).collect();
+        let leaf = parts.pop().unwrap_or(key);
+        let mut node = &mut root;
+        for part in parts {
+            let child = node
+                .entry(part)
+                .or_insert_with(|| Value::Object(Map::new()));
+            node = child
+                .as_object_mut()
+                .ok_or_else(|| format!("Cannot nest `{key}`: `{part}` is also a variable"))?;
+        }
+        if node.contains_key(leaf) {
+            return Err(format!(
+                "Cannot nest `{key}`: it is also a parent of other variables"
+            ));
+        }
+        node.insert(leaf.to_string(), Value::String(value.to_string()));
+    }
+    Ok(Value::Object(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows_keep_quoted_commas_and_newlines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.csv");
+        fs::write(
+            &path,
+            "namespace,key,value\n\
+             app,DSN,\"host=db,port=5432\"\n\
+             other,TOKEN,skipped\n\
+             app,CERT,\"line 1\nline 2\"\n\
+             app,QUOTE,\"say \"\"hi\"\"\"\n",
+        )
+        .unwrap();
+
+        let entries = parse_csv(&path, "app").unwrap();
+        let rows: Vec<(&str, &str, &str)> = entries
+            .iter()
+            .map(|e| (e.namespace.as_str(), e.key.as_str(), e.value.as_str()))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("app", "DSN", "host=db,port=5432"),
+                ("app", "CERT", "line 1\nline 2"),
+                ("app", "QUOTE", "say \"hi\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_rows_with_the_wrong_column_count_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.csv");
+        fs::write(&path, "key,value\nA,1\nB\n").unwrap();
+        let err = parse_csv(&path, "app").err().unwrap();
+        assert!(err.starts_with("Row 3 of "), "{err}");
+    }
+}
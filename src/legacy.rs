@@ -0,0 +1,166 @@
+//! Import secrets exported from the original (C) envchain.
+//!
+//! The original tool stores each variable as a keychain item whose service
+//! is `envchain-NAMESPACE` and whose account is the variable name.  Two
+//! export shapes are understood:
+//!
+//! * a property list (XML or binary) holding an array of dictionaries with
+//!   `service`/`svce`, `account`/`acct` and `password`/`data`/`v_Data`
+//!   entries, as produced by scripted `security` extracts;
+//! * plain text with one `namespace<TAB>key<TAB>value` line per variable.
+//!   Blank lines and lines starting with `#` are ignored.
+
+use crate::import::ImportEntry;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Keychain service prefix used by the original envchain.
+const SERVICE_PREFIX: &str = "envchain-";
+
+/// Read and parse a legacy export, detecting its format from the content.
+pub fn parse_file(path: &Path) -> Result<Vec<ImportEntry>, String> {
+    let content = Zeroizing::new(
+        fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?,
+    );
+    let trimmed = content.trim_ascii_start();
+    if trimmed.starts_with(b"bplist")
+        || trimmed.starts_with(b"<?xml")
+        || trimmed.starts_with(b"<plist")
+    {
+        parse_plist(&content)
+    } else {
+        let text = std::str::from_utf8(&content)
+            .map_err(|e| format!("{} is not valid UTF-8: {e}", path.display()))?;
+        parse_tsv(text)
+    }
+}
+
+fn parse_tsv(text: &str) -> Result<Vec<ImportEntry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, '\t');
+        let (Some(namespace), Some(key), Some(value)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!(
+                "Line {}: expected namespace<TAB>key<TAB>value",
+                i + 1
+            ));
+        };
+        if namespace.is_empty() || key.is_empty() {
+            return Err(format!("Line {}: empty namespace or key", i + 1));
+        }
+        entries.push(ImportEntry {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value: Zeroizing::new(value.to_string()),
+        });
+    }
+    Ok(entries)
+}
+
+/// First string-ish value among `names` in a plist dictionary.
+fn dict_string(dict: &plist::Dictionary, names: &[&str]) -> Option<Zeroizing<String>> {
+    names.iter().find_map(|name| match dict.get(name)? {
+        plist::Value::String(s) => Some(Zeroizing::new(s.clone())),
+        plist::Value::Data(d) => String::from_utf8(d.clone()).ok().map(Zeroizing::new),
+        _ => None,
+    })
+}
+
+fn parse_plist(content: &[u8]) -> Result<Vec<ImportEntry>, String> {
+    let value = plist::Value::from_reader(std::io::Cursor::new(content))
+        .map_err(|e| format!("Failed to parse property list: {e}"))?;
+    let items = match value {
+        plist::Value::Array(items) => items,
+        plist::Value::Dictionary(dict) => vec![plist::Value::Dictionary(dict)],
+        _ => return Err("Property list must contain an array of items".to_string()),
+    };
+
+    let mut entries = Vec::new();
+    for item in &items {
+        let Some(dict) = item.as_dictionary() else {
+            continue;
+        };
+        let Some(service) = dict_string(dict, &["service", "svce"]) else {
+            continue;
+        };
+        // Items that envchain did not create are not ours to import.
+        let Some(namespace) = service.strip_prefix(SERVICE_PREFIX) else {
+            continue;
+        };
+        let key = dict_string(dict, &["account", "acct"])
+            .ok_or_else(|| format!("Item for service `{}` has no account", service.as_str()))?;
+        let value = dict_string(dict, &["password", "data", "v_Data"])
+            .ok_or_else(|| format!("Item {namespace}.{} has no password data", key.as_str()))?;
+        entries.push(ImportEntry {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn triples(entries: &[ImportEntry]) -> Vec<(&str, &str, &str)> {
+        entries
+            .iter()
+            .map(|e| (e.namespace.as_str(), e.key.as_str(), e.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn plist_exports_import_envchain_items_only() {
+        let entries = parse_file(&fixture("legacy-export.plist")).unwrap();
+        assert_eq!(
+            triples(&entries),
+            [
+                ("aws", "AWS_ACCESS_KEY_ID", "AKIAEXAMPLE"),
+                ("db", "DB_PASSWORD", "s3cret"),
+            ]
+        );
+    }
+
+    #[test]
+    fn plist_items_without_an_account_are_refused() {
+        let content = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict><key>svce</key><string>envchain-aws</string></dict></plist>"#;
+        let err = parse_plist(content).err().unwrap();
+        assert_eq!(err, "Item for service `envchain-aws` has no account");
+    }
+
+    #[test]
+    fn tsv_exports_skip_comments_and_blank_lines() {
+        let entries = parse_file(&fixture("legacy-export.tsv")).unwrap();
+        assert_eq!(
+            triples(&entries),
+            [
+                ("aws", "AWS_ACCESS_KEY_ID", "AKIAEXAMPLE"),
+                ("db", "DB_PASSWORD", "pass\twith\ttabs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_tsv_lines_are_reported() {
+        let err = parse_tsv("aws\tA\t1\naws B 2\n").err().unwrap();
+        assert_eq!(err, "Line 2: expected namespace<TAB>key<TAB>value");
+        let err = parse_tsv("\tA\t1\n").err().unwrap();
+        assert_eq!(err, "Line 1: empty namespace or key");
+    }
+}
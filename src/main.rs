@@ -1,12 +1,28 @@
+mod audit;
 mod backend;
+mod completions;
+#[cfg(feature = "derived-values")]
+mod derive;
+mod doctor;
+mod import;
+mod legacy;
+mod policy;
+mod profile;
+mod render;
+#[cfg(feature = "watch")]
+mod watch;
 
 use backend::Backend;
-use clap::{CommandFactory, Parser, Subcommand};
+use base64::Engine;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 use rpassword::read_password;
+use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
 use zeroize::Zeroizing;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,21 +33,86 @@ enum BackendType {
     Age,
     #[cfg(feature = "windows-credential-manager")]
     WindowsCredentialManager,
+    #[cfg(unix)]
+    Agent,
 }
 
 impl BackendType {
-    fn from_str(s: &str) -> Option<Self> {
-        match s.to_lowercase().as_str() {
+    /// Parse a backend name, telling unknown names apart from backends
+    /// that exist but were not compiled into this binary.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let lower = s.to_lowercase();
+        match lower.as_str() {
             #[cfg(feature = "secret-service-backend")]
-            "secret-service" | "secretservice" | "dbus" => Some(Self::SecretService),
+            "secret-service" | "secretservice" | "dbus" => return Ok(Self::SecretService),
             #[cfg(feature = "age-backend")]
-            "age" | "file" => Some(Self::Age),
+            "age" | "file" => return Ok(Self::Age),
             #[cfg(feature = "windows-credential-manager")]
             "wincred" | "windows-credential-manager" | "windows" => {
-                Some(Self::WindowsCredentialManager)
+                return Ok(Self::WindowsCredentialManager);
             }
-            _ => None,
+            #[cfg(unix)]
+            "agent" => return Ok(Self::Agent),
+            _ => {}
         }
+
+        let feature = match lower.as_str() {
+            "secret-service" | "secretservice" | "dbus" => "secret-service-backend",
+            "age" | "file" => "age-backend",
+            "wincred" | "windows-credential-manager" | "windows" => "windows-credential-manager",
+            "agent" => return Err("The agent backend is only available on Unix".to_string()),
+            _ => {
+                return Err(format!(
+                    "Unknown backend `{s}`; expected secret-service, age, wincred, agent or auto"
+                ));
+            }
+        };
+        Err(format!(
+            "Backend `{s}` is not built into this binary (requires the `{feature}` feature).\n\
+             Enabled features: {}",
+            compiled_features()
+        ))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "secret-service-backend")]
+            Self::SecretService => "secret-service",
+            #[cfg(feature = "age-backend")]
+            Self::Age => "age",
+            #[cfg(feature = "windows-credential-manager")]
+            Self::WindowsCredentialManager => "wincred",
+            #[cfg(unix)]
+            Self::Agent => "agent",
+        }
+    }
+
+    /// Every compiled-in backend, in the default auto-selection order.
+    fn all() -> Vec<Self> {
+        vec![
+            #[cfg(feature = "secret-service-backend")]
+            Self::SecretService,
+            #[cfg(feature = "windows-credential-manager")]
+            Self::WindowsCredentialManager,
+            #[cfg(feature = "age-backend")]
+            Self::Age,
+        ]
+    }
+
+    /// Parse a comma-separated backend order such as `age,secret-service`.
+    fn parse_order(s: &str) -> Result<Vec<Self>, String> {
+        let mut order = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let backend_type =
+                Self::from_str(name).map_err(|e| format!("In backend order: {e}"))?;
+            if !order.contains(&backend_type) {
+                order.push(backend_type);
+            }
+        }
+        if order.is_empty() {
+            return Err("Backend order is empty".to_string());
+        }
+        Ok(order)
     }
 
     fn default() -> Self {
@@ -58,20 +139,262 @@ impl BackendType {
     }
 }
 
+/// Backend features compiled into this binary, for error messages.
+fn compiled_features() -> String {
+    let features: Vec<&str> = [
+        (
+            "secret-service-backend",
+            cfg!(feature = "secret-service-backend"),
+        ),
+        ("age-backend", cfg!(feature = "age-backend")),
+        (
+            "windows-credential-manager",
+            cfg!(feature = "windows-credential-manager"),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(", ")
+    }
+}
+
 #[derive(Parser)]
 #[command(name = "envchain")]
 #[command(version)]
 #[command(about = "Environment variables meet secret storage")]
 #[command(long_about = None)]
 struct Cli {
-    /// Backend type: 'secret-service', 'age', or 'wincred'
+    /// Backend type: 'secret-service', 'age', 'wincred', 'agent', or 'auto'
     #[arg(long, global = true, value_name = "TYPE")]
     backend: Option<String>,
 
+    /// Comma-separated order in which 'auto' tries backends
+    #[arg(long, global = true, value_name = "LIST")]
+    backend_order: Option<String>,
+
+    /// Fail unless the backend actually used is TYPE, whatever the configuration says
+    #[arg(long, global = true, value_name = "TYPE")]
+    require_backend: Option<String>,
+
     /// Path to age identity file
     #[arg(long, global = true, value_name = "PATH")]
     age_identity: Option<PathBuf>,
 
+    /// Shell command that prints the age identity on stdout (no identity file needed)
+    #[arg(
+        long,
+        global = true,
+        value_name = "CMD",
+        conflicts_with = "age_identity"
+    )]
+    age_identity_command: Option<String>,
+
+    /// Parse the age identity as this format instead of detecting it
+    #[arg(long, global = true, value_name = "TYPE", value_parser = ["auto", "ssh", "age"])]
+    age_identity_type: Option<String>,
+
+    /// Age recipient file to encrypt to when no identity is available (write-only)
+    #[arg(long, global = true, value_name = "PATH")]
+    age_recipient: Option<PathBuf>,
+
+    /// File of age1... recipients and SSH public keys to also encrypt the age store to
+    #[arg(long, global = true, value_name = "PATH")]
+    age_recipients_file: Option<PathBuf>,
+
+    /// Break-glass recipient (age1... or SSH public key) the age store is always encrypted to
+    #[arg(long, global = true, value_name = "RECIPIENT")]
+    age_recovery_recipient: Option<String>,
+
+    /// Without a configured age identity, offer ~/.ssh/id_ed25519 or ~/.ssh/id_rsa
+    #[arg(long, global = true)]
+    use_ssh_key: bool,
+
+    /// Do not create the config directory for reads; only a write creates it
+    #[arg(long, global = true)]
+    no_auto_create_config_dir: bool,
+
+    /// Refuse to run when the age identity or secrets file is accessible by other users
+    #[arg(long, global = true)]
+    strict_perms: bool,
+
+    /// Gzip the age backend's secrets before encrypting them when saving
+    #[arg(long, global = true)]
+    age_compress: bool,
+
+    /// Refuse every change to the store; nothing is written or re-encrypted
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// Print the fingerprint of the age key in use and exit
+    #[cfg(feature = "age-backend")]
+    #[arg(long, conflicts_with = "namespace")]
+    age_identity_fingerprint: bool,
+
+    /// Fail unless the age key in use has this fingerprint
+    #[arg(long, global = true, value_name = "FP")]
+    verify_fingerprint: Option<String>,
+
+    /// D-Bus object path of the secret-service collection to use instead of the default
+    #[arg(long, global = true, value_name = "PATH")]
+    ss_collection_path: Option<String>,
+
+    /// Create the default secret-service collection if the keyring has none
+    #[arg(long, global = true)]
+    create_collection: bool,
+
+    /// Only read secret-service items tagged application=envchain, ignoring other applications' items
+    #[arg(long, global = true)]
+    ss_label_filter: bool,
+
+    /// Extra attribute to store on secret-service items (repeatable)
+    #[arg(long = "ss-attribute", global = true, value_name = "KEY=VALUE", value_parser = parse_ss_attribute)]
+    ss_attributes: Vec<(String, String)>,
+
+    /// Profile from ~/.config/envchain/profiles.toml supplying backend, identity and namespace
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Retry transient backend errors up to N times with exponential backoff
+    #[arg(long, global = true, value_name = "N")]
+    retry: Option<u32>,
+
+    /// Write the age secrets file once at the end instead of after every change
+    #[arg(long, global = true)]
+    deferred: bool,
+
+    /// Generate a native age identity on first use if none exists
+    #[arg(long, global = true)]
+    auto_generate: bool,
+
+    /// Show what set/unset/clear would change without touching the backend
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Log each injected variable's name, length and source namespace to stderr (exec mode)
+    #[arg(long, global = true)]
+    verbose: bool,
+
+    /// With --verbose, log how long opening the backend, reading secrets and starting the command take
+    #[arg(long, global = true, requires = "verbose")]
+    timings: bool,
+
+    /// When to color output: auto (terminal without NO_COLOR), always or never
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Match namespace names case-insensitively in exec mode and list
+    #[arg(long, global = true)]
+    ignore_case: bool,
+
+    /// Run as an agent holding the unlocked backend for `--backend agent` clients
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "namespace")]
+    agent: bool,
+
+    /// Exit the agent after this long without a client request
+    #[cfg(unix)]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "15m", requires = "agent")]
+    agent_timeout: Duration,
+
+    /// Agent socket path (default: $XDG_RUNTIME_DIR/envchain/agent.sock)
+    #[cfg(unix)]
+    #[arg(long, global = true, value_name = "PATH")]
+    agent_socket: Option<PathBuf>,
+
+    /// Do not append injected keys to WSLENV (Windows exec mode)
+    #[arg(long)]
+    no_wslenv: bool,
+
+    /// Fail instead of warning when injected variables approach the OS size limit (exec mode)
+    #[arg(long)]
+    strict: bool,
+
+    /// Which namespace wins when several define the same key (exec mode and list --format env)
+    #[arg(long, global = true, value_enum, default_value_t = Precedence::Last)]
+    precedence: Precedence,
+
+    /// Pass secrets on an inherited pipe named by ENVCHAIN_SECRETS_FD instead of the environment (exec mode)
+    #[cfg(unix)]
+    #[arg(long, conflicts_with_all = ["pre_exec", "post_exec", "retry_exec"])]
+    no_env_exposure: bool,
+
+    /// Pipe KEY's value to the command's stdin instead of setting it in the environment (exec mode)
+    #[cfg(unix)]
+    #[arg(long, value_name = "KEY", conflicts_with = "retry_exec")]
+    stdin_key: Option<String>,
+
+    /// Keep variables already set in the environment instead of replacing them with stored values (exec mode)
+    #[arg(
+        long,
+        visible_alias = "no-overwrite",
+        conflicts_with = "force_override"
+    )]
+    no_override_existing: bool,
+
+    /// Replace variables already set in the environment with stored values, the default (exec mode)
+    #[arg(long)]
+    force_override: bool,
+
+    /// Also load the namespaces of the envchain that started this one, from ENVCHAIN_ACTIVE_NAMESPACES (exec mode)
+    #[arg(long)]
+    inherit: bool,
+
+    /// Only inject the keys a namespace lists in its __exec_allowlist__ key, if it has one (exec mode)
+    #[arg(long)]
+    respect_allowlist: bool,
+
+    /// Set KEY to VALUE for this run only, overriding stored secrets (exec mode, repeatable)
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_set_env)]
+    set_env: Vec<(String, String)>,
+
+    /// Run the command in DIR instead of the current directory (exec mode)
+    #[arg(long, short = 'C', value_name = "DIR")]
+    chdir: Option<PathBuf>,
+
+    /// Run the command as USER (name or uid), after the secrets are read (exec mode, Unix)
+    #[arg(long, value_name = "USER")]
+    setuid: Option<String>,
+
+    /// Run the command with GROUP (name or gid) instead of USER's primary group (exec mode, Unix)
+    #[arg(long, value_name = "GROUP")]
+    setgid: Option<String>,
+
+    /// Name the namespace after the git repository (its directory, or with =remote its origin URL); every positional is then the command (exec mode)
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SOURCE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "dir"
+    )]
+    namespace_from_git: Option<GitNamespace>,
+
+    /// Re-run the command up to N more times while it exits non-zero (exec mode)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retry_exec: u32,
+
+    /// Seconds to wait between --retry-exec attempts
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 1,
+        requires = "retry_exec"
+    )]
+    retry_exec_delay: u64,
+
+    /// Shell command to run with the secrets set before the command (exec mode)
+    #[arg(long, value_name = "CMD")]
+    pre_exec: Option<String>,
+
+    /// Shell command to run after the command exits, even if it failed (exec mode)
+    #[arg(long, value_name = "CMD")]
+    post_exec: Option<String>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 
@@ -93,6 +416,41 @@ struct Cli {
     exec_args: Vec<String>,
 }
 
+/// Tools `hook` prints integration code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HookTool {
+    /// A `use_envchain` function for `use envchain NAMESPACE` in .envrc files
+    Direnv,
+}
+
+/// direnv stdlib extension printed by `hook direnv`.
+const DIRENV_HOOK: &str = r#"# envchain integration for direnv.  Install it with
+#   envchain hook direnv >> ~/.config/direnv/direnvrc
+# and load namespaces from an .envrc with
+#   use envchain NAMESPACE [NAMESPACE...]
+use_envchain() {
+  local exports
+  exports=$(envchain list --format export "$(IFS=,; echo "$*")") || return
+  eval "$exports"
+}
+"#;
+
+/// Output format for `export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// `export KEY='value'` lines for `eval`
+    Shell,
+    /// Bare `KEY=value` lines for tools that read `.env` files
+    Dotenv,
+}
+
+/// Text encoding for generated secret values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ValueEncoding {
+    Base64url,
+    Hex,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Set environment variables in a namespace
@@ -101,32 +459,382 @@ enum Commands {
         namespace: String,
 
         /// Environment variable names to set
-        #[arg(required = true)]
+        #[arg(required_unless_present = "block")]
         vars: Vec<String>,
 
         /// Do not echo user input
         #[arg(short, long)]
         noecho: bool,
+
+        /// Store a random value of LENGTH bytes (default 32) instead of prompting
+        #[arg(
+            long,
+            value_name = "LENGTH",
+            num_args = 0..=1,
+            default_missing_value = "32",
+            conflicts_with = "noecho"
+        )]
+        generate: Option<usize>,
+
+        /// Encoding of generated values
+        #[arg(long, value_enum, default_value_t = ValueEncoding::Base64url, requires = "generate")]
+        encoding: ValueEncoding,
+
+        /// Print generated values once to stderr
+        #[arg(long, requires = "generate")]
+        show_generated: bool,
+
+        /// Prompt template; `{ns}` and `{key}` are replaced
+        #[arg(long, value_name = "TEMPLATE", default_value = "{ns}.{key}: ")]
+        prompt_format: String,
+
+        /// Read the value of a single variable from this inherited file descriptor (Unix)
+        #[arg(long, value_name = "FD", conflicts_with_all = ["noecho", "generate"])]
+        value_from_fd: Option<i32>,
+
+        /// Ask for each value twice and store it only if both entries match
+        #[arg(long, conflicts_with_all = ["generate", "value_from_fd"])]
+        confirm: bool,
+
+        /// Store input exactly as read, including any trailing newline
+        #[arg(long, conflicts_with_all = ["noecho", "generate"])]
+        raw: bool,
+
+        /// Change a single variable, keeping its current value on empty input
+        #[arg(long, conflicts_with_all = ["generate", "value_from_fd", "confirm", "raw"])]
+        edit: bool,
+
+        /// Show the current value and pre-fill it for editing (with --edit)
+        #[arg(long, requires = "edit", conflicts_with = "noecho")]
+        show_current: bool,
+
+        /// Read the value of a single variable from the clipboard
+        #[cfg(feature = "clipboard")]
+        #[arg(long, conflicts_with_all = ["noecho", "generate", "value_from_fd", "confirm", "edit"])]
+        from_clipboard: bool,
+
+        /// Clear the clipboard after reading it (with --from-clipboard)
+        #[cfg(feature = "clipboard")]
+        #[arg(long, requires = "from_clipboard")]
+        clear_clipboard: bool,
+
+        /// Accept a variable named more than once, prompting for it each time
+        #[arg(long)]
+        allow_duplicates: bool,
+
+        /// Skip variables that are already set instead of overwriting them
+        #[arg(long, conflicts_with = "edit")]
+        if_absent: bool,
+
+        /// Read KEY=VALUE or KEY: VALUE lines from stdin until EOF or a `.` line, storing only VARS if given
+        #[arg(long, visible_alias = "stdin", conflicts_with_all = ["noecho", "generate", "value_from_fd", "confirm", "raw", "edit"])]
+        block: bool,
+
+        /// Parse each value as JSON and store it compactly with sorted object keys
+        #[arg(long, conflicts_with = "generate")]
+        canonicalize_json: bool,
+
+        /// Store the input as the seed of SCHEME; exec and printenv then inject the current derived value
+        #[cfg(feature = "derived-values")]
+        #[arg(long, value_enum, value_name = "SCHEME", conflicts_with_all = ["generate", "edit", "block", "canonicalize_json"])]
+        derive: Option<derive::Scheme>,
+
+        /// Do not print the summary of what was stored
+        #[arg(short, long)]
+        quiet: bool,
     },
 
     /// List namespaces or variables
     List {
         /// Namespace to list variables from (lists all namespaces if omitted)
+        #[arg(required_if_eq_any([("format", "env"), ("format", "export")]))]
         namespace: Option<String>,
 
         /// Show values when listing
         #[arg(short = 'v', long)]
         show_value: bool,
+
+        /// Show when each variable was created and last modified, if the backend records it
+        #[arg(short, long)]
+        long: bool,
+
+        /// Only show variables modified within DURATION (e.g. 7d, 12h)
+        #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, requires = "namespace")]
+        changed_since: Option<Duration>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ListFormat::Text)]
+        format: ListFormat,
+
+        /// Emit indented JSON (implies --format json)
+        #[arg(long)]
+        json_pretty: bool,
+
+        /// Emit NUL-terminated records for scripts (same as --format machine)
+        #[arg(long, conflicts_with_all = ["format", "json_pretty"])]
+        machine: bool,
+
+        /// Write output to this inherited file descriptor instead of stdout (Unix)
+        #[arg(long, value_name = "FD")]
+        output_fd: Option<i32>,
+
+        /// Group namespace names by the prefix before DELIM
+        #[arg(long, value_name = "DELIM", conflicts_with = "namespace")]
+        group_by: Option<String>,
+
+        /// Only list namespaces whose name matches PATTERN (a regular expression)
+        #[arg(long, value_name = "PATTERN", value_parser = parse_namespace_regex, conflicts_with = "namespace")]
+        namespace_regex: Option<regex::Regex>,
+
+        /// Rebuild nested objects from keys split on SEP (JSON output with values)
+        #[arg(long, value_name = "SEP", requires_all = ["namespace", "show_value"])]
+        nest: Option<String>,
+
+        /// Draw grouped namespaces as a tree (with --group-by)
+        #[arg(long, requires = "group_by")]
+        tree: bool,
+
+        /// Show how many keys each namespace holds, read in one pass
+        #[arg(long, conflicts_with_all = ["namespace", "show_value", "long", "redacted"])]
+        with_counts: bool,
+
+        /// Only list namespaces that hold no keys, candidates for cleanup
+        #[arg(long, conflicts_with_all = ["namespace", "show_value", "long", "redacted", "with_counts"])]
+        only_empty: bool,
+
+        /// Print keys as NAMESPACE@KEY, naming the namespace each value comes from (--format env)
+        #[arg(long, requires = "namespace")]
+        qualified: bool,
+
+        /// Exit with status 4 when the namespace has no variables
+        #[arg(long, requires = "namespace")]
+        exit_on_empty: bool,
+
+        /// Do not warn when the namespace has no variables
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Show key names with value lengths and character classes, never values (safe to share)
+        #[arg(long, conflicts_with_all = ["show_value", "long", "changed_since", "group_by", "nest", "exit_on_empty"])]
+        redacted: bool,
+
+        /// Report whether each of KEYS is set and fail if any is missing
+        #[arg(
+            long,
+            value_name = "KEYS",
+            value_delimiter = ',',
+            requires = "namespace",
+            conflicts_with_all = ["show_value", "long", "changed_since", "format", "json_pretty", "nest", "exit_on_empty", "redacted"]
+        )]
+        require: Vec<String>,
+    },
+
+    /// Print variables with their values, like printenv
+    // Not called `env`: `envchain NAMESPACE env` must keep running env(1).
+    #[command(name = "printenv")]
+    Env {
+        /// Namespace or comma-separated namespaces, resolved as in exec mode, or NAMESPACE@KEY
+        namespace: String,
+
+        /// Print only this variable's value
+        key: Option<String>,
+
+        /// Print keys as NAMESPACE@KEY, naming the namespace each value comes from
+        #[arg(long, conflicts_with = "key")]
+        qualified: bool,
+    },
+
+    /// Print variables as shell `export` lines or a .env file instead of running a command
+    Export {
+        /// Namespace or comma-separated namespaces, resolved as in exec mode
+        namespace: String,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ExportFormat::Shell)]
+        format: ExportFormat,
+    },
+
+    /// Fill `{{KEY}}` placeholders in a template file with secrets
+    Render {
+        /// Namespace or comma-separated namespaces, resolved as in exec mode
+        namespace: String,
+
+        /// Template file
+        template: PathBuf,
+
+        /// Write the result to FILE (mode 0600) instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Leave placeholders for unset variables as they are instead of failing
+        #[arg(long)]
+        allow_missing: bool,
     },
 
     /// Remove variables from a namespace
     Unset {
-        /// Namespace to remove variables from
+        /// Namespace to remove variables from, or a single NAMESPACE/KEY
         namespace: String,
 
         /// Environment variable names to remove
-        #[arg(required = true)]
         vars: Vec<String>,
+
+        /// Do not print the summary of what was removed
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Remove every variable in a namespace
+    Clear {
+        /// Namespace to clear
+        namespace: String,
+
+        /// Confirm that all variables in the namespace should be deleted
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Lock the backend's store (the secret-service collection)
+    Lock,
+
+    /// Unlock the backend's store, prompting if needed
+    Unlock,
+
+    /// Check the local setup for common problems
+    Doctor {
+        /// Offer to repair the problems that can be repaired safely
+        #[arg(long)]
+        fix: bool,
+
+        /// Apply every repair without asking (with --fix)
+        #[arg(long, requires = "fix")]
+        yes: bool,
+
+        /// Print the problems and the availability of every backend as JSON
+        #[arg(long, conflicts_with = "fix")]
+        json: bool,
+    },
+
+    /// Move all variables from one namespace to another and remove the old one
+    Rename {
+        /// Namespace to rename
+        src: String,
+
+        /// New name
+        dst: String,
+
+        /// Merge into a destination that already has variables, overwriting clashes
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Copy all variables from one namespace into a new namespace
+    Copy {
+        /// Namespace to copy from
+        src: String,
+
+        /// Namespace to copy into
+        dst: String,
+
+        /// Copy even if the destination already has variables, overwriting clashes
+        #[arg(short, long)]
+        force: bool,
+
+        /// Copy into a non-empty destination, resolving variables that hold another value there
+        #[arg(long, value_enum, value_name = "MODE", conflicts_with = "force")]
+        on_conflict: Option<import::OnConflict>,
+    },
+
+    /// Replace a value with what a command prints when given the old one
+    Rotate {
+        /// Namespace holding the variable
+        namespace: String,
+
+        /// Variable to rotate
+        key: String,
+
+        /// Command that reads the current value on stdin and prints the new one
+        #[arg(
+            value_name = "COMMAND",
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        command: Vec<String>,
+    },
+
+    /// Run a command and restart it whenever the secrets file changes
+    #[cfg(feature = "watch")]
+    Watch {
+        /// Namespace or comma-separated namespaces, resolved as in exec mode
+        namespace: String,
+
+        /// Command to run, followed by its arguments
+        #[arg(
+            value_name = "COMMAND",
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        command: Vec<String>,
+    },
+
+    /// Import a JSON or TOML file into one namespace, flattening nested keys
+    Import {
+        /// Namespace to store the variables in
+        namespace: String,
+
+        /// JSON file, or TOML/CSV if the name ends in .toml/.csv (`-` for stdin with --null-input)
+        file: PathBuf,
+
+        /// Separator placed between nested keys
+        #[arg(long, value_name = "SEP", default_value = "_")]
+        flatten_sep: String,
+
+        /// Read NUL-delimited KEY=VALUE records instead of JSON or TOML
+        #[arg(long, short = '0', conflicts_with = "flatten_sep")]
+        null_input: bool,
+
+        /// Overwrite variables that already exist
+        #[arg(short, long)]
+        force: bool,
+
+        /// What to do with variables that already hold another value
+        #[arg(long, value_enum, value_name = "MODE", conflicts_with = "force")]
+        on_conflict: Option<import::OnConflict>,
+    },
+
+    /// Import variables exported from the original envchain
+    ImportLegacy {
+        /// Keychain property list or namespace<TAB>key<TAB>value file
+        file: PathBuf,
+
+        /// Overwrite variables that already exist
+        #[arg(short, long)]
+        force: bool,
+
+        /// What to do with variables that already hold another value
+        #[arg(long, value_enum, value_name = "MODE", conflicts_with = "force")]
+        on_conflict: Option<import::OnConflict>,
+    },
+
+    /// Generate a native age identity
+    #[cfg(feature = "age-backend")]
+    GenIdentity {
+        /// Where to write the identity (defaults to ~/.config/envchain/identity.txt)
+        path: Option<PathBuf>,
+    },
+
+    /// Delete the age backend's secrets and default identity
+    #[cfg(feature = "age-backend")]
+    Reset {
+        /// Confirm that all age secrets and the default identity should be deleted
+        #[arg(long)]
+        yes: bool,
+
+        /// Generate a fresh default identity afterwards
+        #[arg(long)]
+        regenerate: bool,
     },
 
     /// Generate shell completion script
@@ -134,204 +842,3721 @@ enum Commands {
         /// Shell type
         #[arg(value_enum)]
         shell: Shell,
+
+        /// Also complete namespace and key names by calling back into envchain (bash, zsh, fish)
+        #[arg(long)]
+        dynamic: bool,
+    },
+
+    /// Print shell code that integrates envchain with another tool
+    Hook {
+        /// Tool to integrate with
+        #[arg(value_enum)]
+        tool: HookTool,
+    },
+
+    /// Print every namespace name, one per line (used by dynamic completion)
+    #[command(hide = true)]
+    CompleteNamespaces,
+
+    /// Print the key names of a namespace, one per line (used by dynamic completion)
+    #[command(hide = true)]
+    CompleteKeys {
+        /// Namespace or comma-separated namespaces
+        namespace: String,
     },
 }
 
-fn create_backend(
+/// Options controlling which backend is opened and how.
+struct BackendOptions {
+    /// Backends to try in order; the first one that opens wins
+    candidates: Vec<BackendType>,
+    /// Extra attempts for transient errors (0 disables retrying)
+    retries: u32,
+    /// Refuse to run with any other backend
+    require: Option<BackendType>,
+    #[allow(dead_code)]
+    age_identity: Option<PathBuf>,
+    /// Identity key material from `ENVCHAIN_AGE_IDENTITY`
+    #[allow(dead_code)]
+    age_identity_inline: Option<Zeroizing<String>>,
+    #[allow(dead_code)]
+    age_identity_command: Option<String>,
+    #[allow(dead_code)]
+    age_identity_type: Option<String>,
+    #[allow(dead_code)]
+    auto_generate: bool,
+    #[allow(dead_code)]
+    age_recipient: Option<PathBuf>,
+    #[allow(dead_code)]
+    age_recipients_file: Option<PathBuf>,
+    #[allow(dead_code)]
+    age_recovery_recipient: Option<String>,
+    /// Fingerprint the age key must have
+    verify_fingerprint: Option<String>,
+    /// Offer a default SSH key when no age identity is configured
+    #[allow(dead_code)]
+    use_ssh_key: bool,
+    /// Leave a missing config directory alone until something is written
+    #[allow(dead_code)]
+    no_create_config_dir: bool,
+    /// Fail instead of warning about loose file permissions
+    #[allow(dead_code)]
+    strict_perms: bool,
+    /// Compress the age secrets file on save
+    #[allow(dead_code)]
+    age_compress: bool,
+    /// Wrap the backend so that every mutation is refused
+    read_only: bool,
+    /// Buffer writes until the operation finishes
+    #[allow(dead_code)]
+    deferred: bool,
+    #[allow(dead_code)]
+    agent_socket: Option<PathBuf>,
+    #[allow(dead_code)]
+    ss_collection_path: Option<String>,
+    #[allow(dead_code)]
+    create_collection: bool,
+    #[allow(dead_code)]
+    ss_attributes: Vec<(String, String)>,
+    #[allow(dead_code)]
+    ss_label_filter: bool,
+    /// Open only far enough for `Backend::health_check`
+    #[allow(dead_code)]
+    probe: bool,
+    /// Log how long opening the backend takes
+    timings: bool,
+}
+
+fn open_backend(
     backend_type: BackendType,
-    #[allow(unused_variables)] age_identity: Option<PathBuf>,
+    #[allow(unused_variables)] opts: &BackendOptions,
 ) -> Result<Box<dyn Backend>, String> {
     match backend_type {
         #[cfg(feature = "secret-service-backend")]
         BackendType::SecretService => Ok(Box::new(
-            backend::secret_service::SecretServiceBackend::new()?,
+            backend::secret_service::SecretServiceBackend::new(
+                opts.ss_collection_path.clone(),
+                opts.create_collection,
+                opts.ss_attributes.clone(),
+                opts.ss_label_filter,
+                opts.timings,
+            )?,
         )),
         #[cfg(feature = "age-backend")]
-        BackendType::Age => Ok(Box::new(backend::age::AgeBackend::new(age_identity)?)),
+        BackendType::Age => Ok(Box::new(backend::age::AgeBackend::new(age_options(opts)?)?)),
         #[cfg(feature = "windows-credential-manager")]
         BackendType::WindowsCredentialManager => Ok(Box::new(
             backend::windows_credential_manager::WindowsCredentialManagerBackend::new()?,
         )),
+        #[cfg(unix)]
+        BackendType::Agent => {
+            let socket = match &opts.agent_socket {
+                Some(path) => path.clone(),
+                None => backend::agent::default_socket_path()?,
+            };
+            Ok(Box::new(backend::agent::AgentBackend::connect(&socket)?))
+        }
     }
 }
 
-fn print_completions(shell: Shell, cmd: &mut clap::Command) {
-    clap_complete::generate(
-        shell,
-        cmd,
-        cmd.get_name().to_string(),
-        &mut std::io::stdout(),
-    );
+#[cfg(feature = "age-backend")]
+fn age_options(opts: &BackendOptions) -> Result<backend::age::AgeOptions, String> {
+    let identity = match &opts.age_identity {
+        None if opts.use_ssh_key
+            && opts.age_identity_command.is_none()
+            && opts.age_identity_inline.is_none() =>
+        {
+            adopt_ssh_identity()?
+        }
+        identity => identity.clone(),
+    };
+    Ok(backend::age::AgeOptions {
+        identity,
+        identity_inline: opts.age_identity_inline.clone(),
+        identity_command: opts.age_identity_command.clone(),
+        identity_type: opts
+            .age_identity_type
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        auto_generate: opts.auto_generate,
+        recipient: opts.age_recipient.clone(),
+        recipients_file: opts.age_recipients_file.clone(),
+        recovery_recipient: opts.age_recovery_recipient.clone(),
+        deferred: opts.deferred,
+        secure_delete: env_flag("ENVCHAIN_SECURE_DELETE"),
+        compress: opts.age_compress,
+        no_recipient_file: env_flag("ENVCHAIN_AGE_NO_RECIPIENT_FILE"),
+        probe_only: opts.probe,
+        timings: opts.timings,
+        expected_fingerprint: opts.verify_fingerprint.clone(),
+        no_create_config_dir: opts.no_create_config_dir,
+        strict_perms: opts.strict_perms,
+    })
 }
 
-fn list_namespaces(backend: &dyn Backend) -> Result<(), String> {
-    let namespaces = backend.list_namespaces()?;
-    for ns in namespaces {
-        println!("{ns}");
+/// `--use-ssh-key`: a default SSH key to use as the age identity, unless an
+/// envchain identity already exists.  At a terminal the user is asked first.
+#[cfg(feature = "age-backend")]
+fn adopt_ssh_identity() -> Result<Option<PathBuf>, String> {
+    let default_identity = dirs::config_dir()
+        .ok_or("Could not determine config directory")?
+        .join("envchain")
+        .join("identity.txt");
+    if default_identity.exists() {
+        return Ok(None);
     }
-    Ok(())
+    let Some(key) = backend::age::find_ssh_identity() else {
+        return Ok(None);
+    };
+    if std::io::stdin().is_terminal() {
+        if !doctor::confirm(&format!(
+            "Use the SSH key {} as the age identity?",
+            key.display()
+        ))? {
+            return Ok(None);
+        }
+    } else {
+        eprintln!("Using the SSH key {} as the age identity", key.display());
+    }
+    Ok(Some(key))
 }
 
-fn list_values(backend: &dyn Backend, target: &str, show_value: bool) -> Result<(), String> {
-    let secrets = backend.list_secrets(target)?;
-    if secrets.is_empty() {
-        eprintln!(
-            "WARNING: namespace `{}` not defined.\n         You can set via running `{} --set {} SOME_ENV_NAME`.\n",
-            target,
-            env::args().next().unwrap_or_else(|| "envchain".into()),
-            target
-        );
-        return Ok(());
+/// Open the first candidate backend that initializes successfully.
+fn open_first_candidate(opts: &BackendOptions) -> Result<Box<dyn Backend>, String> {
+    let open = |backend_type| {
+        backend::retry::with_retry(opts.retries, || open_backend(backend_type, opts))
+    };
+
+    if let [backend_type] = opts.candidates[..] {
+        return open(backend_type);
     }
-    let mut keys: Vec<_> = secrets.keys().collect();
-    keys.sort();
-    for key in keys {
-        if show_value {
-            println!("{}={}", key, secrets.get(key).unwrap());
-        } else {
-            println!("{key}");
+
+    let mut errors = Vec::new();
+    for &backend_type in &opts.candidates {
+        match open(backend_type) {
+            Ok(b) => return Ok(b),
+            Err(e) => errors.push(format!("  {}: {e}", backend_type.name())),
         }
     }
-    Ok(())
+    Err(format!(
+        "No backend could be opened:\n{}",
+        errors.join("\n")
+    ))
 }
 
-fn set_values(
-    backend: &mut dyn Backend,
-    noecho: bool,
-    name: &str,
-    keys: &[String],
-) -> Result<(), String> {
-    for key in keys {
-        let prompt = format!("{name}.{key}");
-        let value: Zeroizing<String> = if noecho {
-            eprint!("{prompt} (noecho):");
-            read_password()
-                .map(Zeroizing::new)
-                .map_err(|e| format!("Failed to read password: {e}"))?
-        } else {
-            eprint!("{prompt}: ");
-            let mut buf = Zeroizing::new(String::new());
-            std::io::stdin()
-                .read_line(&mut buf)
-                .map_err(|e| format!("Failed to read line: {e}"))?;
-            let trimmed = buf.trim_end_matches(['\n', '\r']).to_string();
-            Zeroizing::new(trimmed)
-        };
-        backend.set_secret(name, key, &value)?;
+fn create_backend(opts: BackendOptions) -> Result<Box<dyn Backend>, String> {
+    // Check the configuration first so that no other backend is even opened.
+    if let Some(required) = opts.require
+        && !opts.candidates.contains(&required)
+    {
+        let configured: Vec<&str> = opts.candidates.iter().map(|b| b.name()).collect();
+        return Err(format!(
+            "--require-backend {}: the configured backend is {}",
+            required.name(),
+            configured.join(" or ")
+        ));
+    }
+    let start = Instant::now();
+    let backend = open_first_candidate(&opts)?;
+    if opts.timings {
+        backend::log_timing(&format!("open {} backend", backend.backend_name()), start);
+    }
+    if let Some(required) = opts.require
+        && backend.backend_name() != required.name()
+    {
+        return Err(format!(
+            "--require-backend {}: the {} backend was opened instead",
+            required.name(),
+            backend.backend_name()
+        ));
+    }
+    // Only the age backend checks the fingerprint; never let it pass silently.
+    if opts.verify_fingerprint.is_some() && backend.backend_name() != "age" {
+        return Err(format!(
+            "--verify-fingerprint applies to the age backend only, not {}",
+            backend.backend_name()
+        ));
+    }
+    let backend: Box<dyn Backend> = if opts.retries == 0 {
+        backend
+    } else {
+        Box::new(backend::retry::RetryBackend::new(backend, opts.retries))
+    };
+    if opts.read_only {
+        return Ok(Box::new(backend::read_only::ReadOnlyBackend::new(backend)));
+    }
+    Ok(backend)
+}
+
+/// `complete-namespaces` and `complete-keys`: print names for the dynamic
+/// completion scripts, sorted and without duplicates.
+fn print_completion_names(backend: &dyn Backend, namespace: Option<&str>) -> Result<(), String> {
+    let mut names = match namespace {
+        None => backend.list_namespaces()?,
+        Some(csv) => {
+            let mut keys = Vec::new();
+            for namespace in csv.split(',').filter(|n| !n.is_empty()) {
+                keys.extend(backend.list_keys(namespace)?);
+            }
+            keys
+        }
+    };
+    names.sort();
+    names.dedup();
+    let mut out = std::io::stdout().lock();
+    for name in names {
+        write_line(&mut out, &name)?;
     }
     Ok(())
 }
 
-fn unset_values(backend: &mut dyn Backend, name: &str, keys: &[String]) -> Result<(), String> {
-    for key in keys {
-        backend.delete_secret(name, key)?;
+#[cfg(feature = "age-backend")]
+fn gen_identity(path: Option<PathBuf>) -> Result<(), String> {
+    let identity_path = match path {
+        Some(p) => p,
+        None => backend::age::default_identity_path()?,
+    };
+    let recipient_path = if env_flag("ENVCHAIN_AGE_NO_RECIPIENT_FILE") {
+        None
+    } else {
+        Some(backend::age::recipient_path_for(&identity_path)?)
+    };
+    let recipient = backend::age::generate_identity(&identity_path, recipient_path.as_deref())?;
+    eprintln!("Created age identity at {}", identity_path.display());
+    if let Some(recipient_path) = &recipient_path {
+        eprintln!("Public key written to {}", recipient_path.display());
     }
+    println!("{recipient}");
     Ok(())
 }
 
-fn exec_with(
-    backend: Box<dyn Backend>,
+/// Open an inherited file descriptor for writing output (Unix only).
+#[cfg(unix)]
+fn open_output_fd(fd: i32) -> Result<Box<dyn Write>, String> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fcntl(F_GETFD) only queries descriptor flags; it is safe to
+    // call on any integer and fails with EBADF if `fd` is not open.
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(format!("--output-fd {fd} is not an open file descriptor"));
+    }
+    // SAFETY: the descriptor was verified open above, and envchain does not
+    // otherwise use it, so taking ownership (and closing it on drop) is sound.
+    Ok(Box::new(unsafe { std::fs::File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn open_output_fd(_fd: i32) -> Result<Box<dyn Write>, String> {
+    Err("--output-fd is only supported on Unix".to_string())
+}
+
+/// Read a secret value from an inherited file descriptor (Unix only).  Unless
+/// `raw`, one trailing newline is removed, matching what the interactive
+/// prompt stores.
+#[cfg(unix)]
+fn read_value_fd(fd: i32, raw: bool) -> Result<Zeroizing<String>, String> {
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fcntl(F_GETFD) only queries descriptor flags; it is safe to
+    // call on any integer and fails with EBADF if `fd` is not open.
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(format!(
+            "--value-from-fd {fd} is not an open file descriptor"
+        ));
+    }
+    // SAFETY: the descriptor was verified open above, and envchain does not
+    // otherwise use it, so taking ownership (and closing it on drop) is sound.
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    let mut buf = Zeroizing::new(Vec::new());
+    file.read_to_end(&mut buf)
+        .map_err(|e| format!("Failed to read from fd {fd}: {e}"))?;
+    let text = utf8_value(buf, &format!("Value read from fd {fd}"))?;
+    if raw {
+        return Ok(text);
+    }
+    let value = text
+        .strip_suffix('\n')
+        .map(|v| v.strip_suffix('\r').unwrap_or(v))
+        .unwrap_or(&text);
+    Ok(Zeroizing::new(value.to_string()))
+}
+
+#[cfg(not(unix))]
+fn read_value_fd(_fd: i32, _raw: bool) -> Result<Zeroizing<String>, String> {
+    Err("--value-from-fd is only supported on Unix".to_string())
+}
+
+/// Check that a value entered as raw bytes is UTF-8 text before it goes
+/// anywhere near a backend.  Values are `String`s, and a store that can hold
+/// bytes (see `Capabilities::binary_values`) would only hand back something
+/// `list_secrets` cannot read, so binary input is refused here, naming the
+/// offending byte, rather than stored.
+fn utf8_value(mut bytes: Zeroizing<Vec<u8>>, source: &str) -> Result<Zeroizing<String>, String> {
+    match String::from_utf8(std::mem::take(&mut *bytes)) {
+        Ok(text) => Ok(Zeroizing::new(text)),
+        Err(e) => {
+            let offset = e.utf8_error().valid_up_to();
+            let bytes = Zeroizing::new(e.into_bytes());
+            Err(format!(
+                "{source} is not valid UTF-8 (byte 0x{:02x} at offset {offset}); \
+                 envchain stores text only, so encode binary data first, e.g. with base64",
+                bytes[offset]
+            ))
+        }
+    }
+}
+
+fn write_line(out: &mut dyn Write, line: &str) -> Result<(), String> {
+    writeln!(out, "{line}").map_err(|e| format!("Failed to write output: {e}"))
+}
+
+/// `--age-identity-fingerprint`: print the fingerprint of the age recipient
+/// in use, without decrypting the secrets file.
+#[cfg(feature = "age-backend")]
+fn print_age_fingerprint(cli: &Cli, profile: &profile::Profile) -> Result<(), String> {
+    let mut opts = parse_backend_options(cli, profile)?;
+    opts.probe = true;
+    let backend = backend::age::AgeBackend::new(age_options(&opts)?)?;
+    println!("{}", backend.fingerprint()?);
+    Ok(())
+}
+
+#[cfg(feature = "age-backend")]
+fn reset_age(
+    cli: &Cli,
+    profile: &profile::Profile,
+    yes: bool,
+    regenerate: bool,
+) -> Result<(), String> {
+    if !yes {
+        return Err(
+            "reset deletes all age secrets and the default identity; pass --yes to confirm"
+                .to_string(),
+        );
+    }
+    let opts = parse_backend_options(cli, profile)?;
+    if opts.read_only {
+        return Err("Refusing to reset: --read-only is set".to_string());
+    }
+    // Only touch keys envchain created itself.
+    if let Some(identity) = opts.age_identity {
+        return Err(format!(
+            "Refusing to reset while a custom identity is configured ({}); \
+             envchain only deletes the identity it generated",
+            identity.display()
+        ));
+    }
+    if let Some(recipient) =
+        backend::age::reset(regenerate, env_flag("ENVCHAIN_AGE_NO_RECIPIENT_FILE"))?
+    {
+        eprintln!("Created age identity. Public key: {recipient}");
+    }
+    Ok(())
+}
+
+/// Refuse whole-store listings on backends that cannot enumerate
+/// namespaces, rather than printing a misleading empty list.
+fn ensure_can_list_namespaces(backend: &dyn Backend) -> Result<(), String> {
+    if backend.capabilities().list_namespaces {
+        return Ok(());
+    }
+    Err(format!(
+        "Listing all namespaces isn't supported by the {} backend; name a namespace instead",
+        backend.backend_name()
+    ))
+}
+
+fn list_namespaces(
+    backend: &dyn Backend,
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    ensure_can_list_namespaces(backend)?;
+    // With counts, the one bulk read also yields the namespaces.
+    let counts = (opts.with_counts || opts.only_empty)
+        .then(|| backend.count_keys())
+        .transpose()?;
+    let mut namespaces = match &counts {
+        Some(counts) => {
+            let mut namespaces: Vec<_> = counts.keys().cloned().collect();
+            namespaces.sort();
+            namespaces
+        }
+        None => backend.list_namespaces()?,
+    };
+    if let Some(re) = &opts.namespace_regex {
+        namespaces.retain(|ns| re.is_match(ns));
+    }
+    if let (true, Some(counts)) = (opts.only_empty, &counts) {
+        namespaces.retain(|ns| counts[ns] == 0);
+    }
+    let counts = counts.filter(|_| opts.with_counts);
+    let groups = opts
+        .group_by
+        .as_deref()
+        .map(|delim| group_namespaces(&namespaces, delim));
+    if opts.format == ListFormat::Json {
+        let mut json = serde_json::json!({
+            "schema_version": LIST_JSON_SCHEMA_VERSION,
+            "namespaces": namespaces,
+        });
+        if let Some(groups) = &groups {
+            let groups: serde_json::Map<_, _> = groups
+                .iter()
+                .filter(|(_, group)| !group.members.is_empty())
+                .map(|(prefix, group)| (prefix.to_string(), serde_json::json!(group.members)))
+                .collect();
+            json["groups"] = serde_json::Value::Object(groups);
+        }
+        if let Some(counts) = &counts {
+            let counts: serde_json::Map<_, _> = namespaces
+                .iter()
+                .map(|ns| (ns.clone(), counts[ns].into()))
+                .collect();
+            json["counts"] = serde_json::Value::Object(counts);
+        }
+        return write_json(out, &json, opts.pretty);
+    }
+    // `shown` is how the line names namespace `name`.
+    let label = |name: &str, shown: &str| match counts.as_ref().and_then(|c| c.get(name)) {
+        Some(1) => format!("{shown} (1 key)"),
+        Some(n) => format!("{shown} ({n} keys)"),
+        None => shown.to_string(),
+    };
+    let (Some(groups), Some(delim)) = (groups, opts.group_by.as_deref()) else {
+        for ns in &namespaces {
+            write_line(out, &label(ns, ns))?;
+        }
+        return Ok(());
+    };
+    let color = opts.color;
+    for (prefix, group) in groups {
+        if opts.tree {
+            write_line(out, &paint(prefix, HEADER_STYLE, color))?;
+            for (i, member) in group.members.iter().enumerate() {
+                let branch = if i + 1 == group.members.len() {
+                    "└──"
+                } else {
+                    "├──"
+                };
+                let name = format!("{prefix}{delim}{member}");
+                write_line(
+                    out,
+                    &format!(
+                        "{} {}",
+                        paint(branch, DIM_STYLE, color),
+                        label(&name, member)
+                    ),
+                )?;
+            }
+            continue;
+        }
+        if group.standalone {
+            write_line(out, &label(prefix, prefix))?;
+        }
+        if !group.members.is_empty() {
+            write_line(
+                out,
+                &paint(&format!("{prefix}{delim}"), HEADER_STYLE, color),
+            )?;
+            for member in &group.members {
+                let name = format!("{prefix}{delim}{member}");
+                write_line(out, &format!("  {}", label(&name, member)))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Namespaces sharing a prefix in `list --group-by`.
+#[derive(Default)]
+struct NamespaceGroup<'a> {
+    /// A namespace named exactly like the prefix exists
+    standalone: bool,
+    /// Remainders after the prefix and delimiter
+    members: Vec<&'a str>,
+}
+
+/// Group namespace names by the part before the first `delim`.
+fn group_namespaces<'a>(
+    namespaces: &'a [String],
+    delim: &str,
+) -> std::collections::BTreeMap<&'a str, NamespaceGroup<'a>> {
+    let mut groups = std::collections::BTreeMap::<_, NamespaceGroup>::new();
+    for ns in namespaces {
+        match ns.split_once(delim).filter(|_| !delim.is_empty()) {
+            Some((prefix, rest)) => groups.entry(prefix).or_default().members.push(rest),
+            None => groups.entry(ns.as_str()).or_default().standalone = true,
+        }
+    }
+    groups
+}
+
+/// Format an optional timestamp in local time for `list --long`.
+fn format_timestamp(time: Option<SystemTime>) -> String {
+    match time {
+        Some(t) => chrono::DateTime::<chrono::Local>::from(t)
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        None => "-".to_string(),
+    }
+}
+
+/// Version of the JSON emitted by `list --format json`.  Bump it on any
+/// incompatible change to the schema documented in the README.
+const LIST_JSON_SCHEMA_VERSION: u32 = 1;
+
+/// Version of the JSON emitted by `doctor --json`, likewise.
+const DOCTOR_JSON_SCHEMA_VERSION: u32 = 1;
+
+/// `--precedence` setting: which of several comma-separated namespaces
+/// supplies a key they all define.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Precedence {
+    /// The namespace listed first wins
+    First,
+    /// The namespace listed last wins
+    Last,
+}
+
+/// `--namespace-from-git` setting: what names the repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GitNamespace {
+    /// The name of the top-level directory
+    Dir,
+    /// The last component of the `origin` remote's URL, without `.git`
+    Remote,
+}
+
+/// `--color` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to emit color on a stream; `auto` requires a terminal and an
+    /// unset or empty `NO_COLOR`.
+    fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => is_terminal && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+        }
+    }
+}
+
+const KEY_STYLE: anstyle::Style = anstyle::Style::new().bold();
+const HEADER_STYLE: anstyle::Style = anstyle::Style::new()
+    .bold()
+    .fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::Blue)));
+const WARNING_STYLE: anstyle::Style = anstyle::Style::new()
+    .bold()
+    .fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::Yellow)));
+const DIM_STYLE: anstyle::Style = anstyle::Style::new().dimmed();
+
+/// Wrap `text` in `style` when color is enabled.
+fn paint(text: &str, style: anstyle::Style, enabled: bool) -> String {
+    if enabled {
+        format!("{style}{text}{style:#}")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Output format for `list`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListFormat {
+    Text,
+    Json,
+    /// The KEY=value pairs exec mode would set for a namespace list
+    Env,
+    /// The same pairs as shell-quoted `export KEY=value` lines for `eval`
+    Export,
+    /// namespace,key,value rows with a header, values included
+    Csv,
+    /// A quoted `ENVCHAIN_KEYS=(...)` or `ENVCHAIN_NAMESPACES=(...)` array for bash/zsh `eval`
+    ShellArray,
+    /// NUL-terminated fields: `ns\0` per namespace, `key\0` or `key\0value\0` per variable
+    Machine,
+}
+
+/// Options controlling how `list` prints a namespace.
+struct ListOptions {
+    show_value: bool,
+    long: bool,
+    /// Only show keys modified within this window
+    changed_since: Option<Duration>,
+    format: ListFormat,
+    /// Indent JSON output
+    pretty: bool,
+    /// Match the namespace case-insensitively
+    ignore_case: bool,
+    /// Group namespace names by the prefix before this delimiter
+    group_by: Option<String>,
+    /// Only list namespaces matching this pattern
+    namespace_regex: Option<regex::Regex>,
+    /// Draw groups as a tree
+    tree: bool,
+    /// Show each namespace's key count
+    with_counts: bool,
+    /// List only namespaces without keys
+    only_empty: bool,
+    /// Prefix keys with the namespace their value comes from
+    qualified: bool,
+    /// Add a `nested` object built by splitting keys on this separator
+    nest: Option<String>,
+    /// Color text output
+    color: bool,
+    /// Color warnings on stderr
+    color_stderr: bool,
+    /// Suppress the empty-namespace warning
+    quiet: bool,
+    /// Resolution of keys defined in several namespaces (`--format env`)
+    precedence: Precedence,
+}
+
+fn write_json(out: &mut dyn Write, value: &serde_json::Value, pretty: bool) -> Result<(), String> {
+    let json = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+    .map_err(|e| format!("Failed to serialize JSON: {e}"))?;
+    write_line(out, &json)
+}
+
+fn format_timestamp_json(time: Option<SystemTime>) -> serde_json::Value {
+    time.map(|t| humantime::format_rfc3339_seconds(t).to_string().into())
+        .unwrap_or(serde_json::Value::Null)
+}
+
+/// A named character class and its membership test.
+type CharClass = (&'static str, fn(&char) -> bool);
+
+/// Character classes reported by `list --redacted`.
+const CHAR_CLASSES: [CharClass; 6] = [
+    ("lower", char::is_ascii_lowercase),
+    ("upper", char::is_ascii_uppercase),
+    ("digit", char::is_ascii_digit),
+    ("symbol", char::is_ascii_punctuation),
+    ("space", |c| c.is_whitespace()),
+    ("non-ascii", |c| !c.is_ascii()),
+];
+
+/// Names of the character classes that occur in `value`.
+fn char_classes(value: &str) -> Vec<&'static str> {
+    CHAR_CLASSES
+        .iter()
+        .filter(|(_, matches)| value.chars().any(|c| matches(&c)))
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// A namespace and its variables.
+type NamespaceSecrets = (String, HashMap<String, String>);
+
+/// Read one namespace, or every namespace matching `--namespace-regex`,
+/// sorted by namespace.
+fn read_store(
+    backend: &dyn Backend,
+    namespace: Option<&str>,
+    opts: &ListOptions,
+) -> Result<Vec<NamespaceSecrets>, String> {
+    let mut store: Vec<NamespaceSecrets> = match namespace {
+        Some(ns) => {
+            let ns = resolve_namespace(backend, ns, opts.ignore_case)?;
+            let secrets = backend.list_secrets(&ns)?;
+            vec![(ns, secrets)]
+        }
+        None => {
+            ensure_can_list_namespaces(backend)?;
+            backend
+                .list_all()?
+                .into_iter()
+                .filter(|(ns, _)| {
+                    opts.namespace_regex
+                        .as_ref()
+                        .is_none_or(|re| re.is_match(ns))
+                })
+                .collect()
+        }
+    };
+    store.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(store)
+}
+
+/// Write `namespace,key,value` rows with a header, sorted by namespace and
+/// key.  Returns whether there were no variables.
+fn list_csv(
+    backend: &dyn Backend,
+    namespace: Option<&str>,
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<bool, String> {
+    let store = read_store(backend, namespace, opts)?;
+    let mut writer = csv::Writer::from_writer(out);
+    let write_err = |e: csv::Error| format!("Failed to write output: {e}");
+    writer
+        .write_record(["namespace", "key", "value"])
+        .map_err(write_err)?;
+    let mut empty = true;
+    for (ns, secrets) in &store {
+        let mut pairs: Vec<_> = secrets.iter().collect();
+        pairs.sort();
+        for (key, value) in pairs {
+            writer
+                .write_record([ns.as_str(), key, value])
+                .map_err(write_err)?;
+            empty = false;
+        }
+    }
+    writer
+        .flush()
+        .map_err(|e| format!("Failed to write output: {e}"))?;
+    Ok(empty)
+}
+
+/// Print the shape of the store without any values: namespaces, key names,
+/// value lengths and character classes.  Meant for pasting into bug reports.
+fn list_redacted(
+    backend: &dyn Backend,
+    namespace: Option<&str>,
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    let store = read_store(backend, namespace, opts)?;
+
+    if opts.format == ListFormat::Json {
+        let namespaces: serde_json::Map<_, _> = store
+            .iter()
+            .map(|(ns, secrets)| {
+                let mut keys: Vec<_> = secrets.iter().collect();
+                keys.sort();
+                let entries: Vec<_> = keys
+                    .into_iter()
+                    .map(|(key, value)| {
+                        serde_json::json!({
+                            "key": key,
+                            "length": value.chars().count(),
+                            "classes": char_classes(value),
+                        })
+                    })
+                    .collect();
+                (ns.clone(), serde_json::Value::Array(entries))
+            })
+            .collect();
+        let json = serde_json::json!({
+            "schema_version": LIST_JSON_SCHEMA_VERSION,
+            "backend": backend.backend_name(),
+            "namespaces": namespaces,
+        });
+        return write_json(out, &json, opts.pretty);
+    }
+
+    write_line(out, &format!("backend: {}", backend.backend_name()))?;
+    for (ns, secrets) in &store {
+        write_line(out, &paint(ns, HEADER_STYLE, opts.color))?;
+        let mut keys: Vec<_> = secrets.iter().collect();
+        keys.sort();
+        for (key, value) in keys {
+            let classes = char_classes(value);
+            let classes = if classes.is_empty() {
+                "empty".to_string()
+            } else {
+                classes.join(",")
+            };
+            write_line(
+                out,
+                &format!(
+                    "  {}\t{} chars\t{classes}",
+                    paint(key, KEY_STYLE, opts.color),
+                    value.chars().count()
+                ),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// `printenv`: print `KEY=value` for everything exec mode would inject, or just
+/// the value of `key` like `printenv VAR`.  Returns whether `key` was found.
+fn print_env(
+    backend: &dyn Backend,
+    name_csv: &str,
+    key: Option<&str>,
+    qualified: bool,
+    ignore_case: bool,
+    precedence: Precedence,
+) -> Result<bool, String> {
+    // `NAMESPACE@KEY` addresses one variable in a single argument.
+    let (name_csv, key) = match (key, name_csv.rsplit_once('@')) {
+        (None, Some((ns, key))) if !ns.is_empty() && !key.is_empty() => (ns, Some(key)),
+        _ => (name_csv, key),
+    };
+    let CollectedEnv {
+        mut pairs, sources, ..
+    } = collect_env(backend, name_csv, ignore_case, precedence, false)?;
+    let out = &mut std::io::stdout();
+    if let Some(key) = key {
+        let Some((_, value)) = pairs.iter().find(|(k, _)| k == key) else {
+            return Ok(false);
+        };
+        write_line(out, value)?;
+        return Ok(true);
+    }
+    pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (key, value) in &pairs {
+        if qualified {
+            let source = winning_source(&sources[key], precedence);
+            write_line(out, &format!("{source}@{key}={}", value.as_str()))?;
+        } else {
+            write_line(out, &format!("{key}={}", value.as_str()))?;
+        }
+    }
+    Ok(true)
+}
+
+/// `render`: fill `template` with the variables exec mode would inject for
+/// `name_csv` and write it to `output` or stdout.
+fn render_template(
+    backend: &dyn Backend,
+    name_csv: &str,
+    template: &Path,
+    output: Option<&Path>,
+    allow_missing: bool,
+    ignore_case: bool,
+    precedence: Precedence,
+) -> Result<(), String> {
+    let text = std::fs::read_to_string(template)
+        .map_err(|e| format!("Failed to read template {}: {e}", template.display()))?;
+    let pairs = collect_env(backend, name_csv, ignore_case, precedence, false)
+        .map_err(|e| attribute_error(backend, e))?
+        .pairs;
+    let values = pairs
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    let rendered = render::render(&text, &values, allow_missing)?;
+    match output {
+        Some(path) => render::write_private(path, &rendered),
+        None => std::io::stdout()
+            .write_all(rendered.as_bytes())
+            .map_err(|e| format!("Failed to write output: {e}")),
+    }
+}
+
+/// Whether `key` can be assigned by a POSIX shell.  `--format export`
+/// skips other names: its output is meant for `eval`.
+fn is_shell_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Quote `value` for a POSIX shell: wrapped in single quotes, with each `'`
+/// written as `'\''`.  Nothing inside single quotes is expanded, so this is
+/// safe for any value except one holding a NUL byte, which no shell
+/// variable can.
+fn single_quote(value: &str) -> Option<Zeroizing<String>> {
+    if value.contains('\0') {
+        return None;
+    }
+    Some(Zeroizing::new(format!(
+        "'{}'",
+        value.replace('\'', "'\\''")
+    )))
+}
+
+/// Print the `KEY=value` pairs exec mode would inject for `name_csv`,
+/// sorted by key.  Returns whether there are none.
+fn list_env(
+    backend: &dyn Backend,
+    name_csv: &str,
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<bool, String> {
+    let CollectedEnv {
+        mut pairs, sources, ..
+    } = collect_env(backend, name_csv, opts.ignore_case, opts.precedence, false)?;
+    if pairs.is_empty() && !opts.quiet {
+        eprintln!(
+            "{} no variables defined in `{name_csv}`.",
+            paint("WARNING:", WARNING_STYLE, opts.color_stderr)
+        );
+    }
+    pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (key, value) in &pairs {
+        if opts.qualified {
+            let source = winning_source(&sources[key], opts.precedence);
+            write_line(out, &format!("{source}@{key}={}", value.as_str()))?;
+            continue;
+        }
+        if opts.format != ListFormat::Export {
+            write_line(out, &format!("{key}={}", value.as_str()))?;
+            continue;
+        }
+        if !is_shell_identifier(key) {
+            eprintln!(
+                "{} skipping `{key}`: not a valid shell variable name",
+                paint("WARNING:", WARNING_STYLE, opts.color_stderr)
+            );
+            continue;
+        }
+        let quoted =
+            single_quote(value).ok_or_else(|| format!("The value of {key} contains a NUL byte"))?;
+        write_line(out, &format!("export {key}={}", quoted.as_str()))?;
+    }
+    Ok(pairs.is_empty())
+}
+
+/// Print key or namespace names as a bash/zsh array assignment, each name
+/// quoted for the shell.  Returns whether the list is empty.
+fn list_shell_array(
+    backend: &dyn Backend,
+    namespace: Option<&str>,
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<bool, String> {
+    let (variable, mut names) = match namespace {
+        Some(target) => {
+            let target = resolve_namespace(backend, target, opts.ignore_case)?;
+            let keys = backend.list_keys(&target)?;
+            if keys.is_empty() && !opts.quiet {
+                eprintln!(
+                    "{} namespace `{target}` not defined.",
+                    paint("WARNING:", WARNING_STYLE, opts.color_stderr)
+                );
+            }
+            ("ENVCHAIN_KEYS", keys)
+        }
+        None => {
+            ensure_can_list_namespaces(backend)?;
+            let mut namespaces = backend.list_namespaces()?;
+            if let Some(re) = &opts.namespace_regex {
+                namespaces.retain(|ns| re.is_match(ns));
+            }
+            ("ENVCHAIN_NAMESPACES", namespaces)
+        }
+    };
+    names.sort();
+    let quoted = names
+        .iter()
+        .map(|name| {
+            shlex::try_quote(name).map_err(|_| format!("`{name}` cannot be quoted for the shell"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    write_line(out, &format!("{variable}=({})", quoted.join(" ")))?;
+    Ok(names.is_empty())
+}
+
+/// Print NUL-terminated fields: each namespace name, or each key of
+/// `namespace` followed by its value with `--show-value`.  Nothing is
+/// escaped, so a NUL inside a name or value is an error.  Returns whether
+/// the list is empty.
+fn list_machine(
+    backend: &dyn Backend,
+    namespace: Option<&str>,
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<bool, String> {
+    let fields: Vec<String> = match namespace {
+        Some(target) => {
+            let target = resolve_namespace(backend, target, opts.ignore_case)?;
+            let mut pairs: Vec<(String, Option<String>)> = if opts.show_value {
+                let secrets = backend.list_secrets(&target)?;
+                secrets
+                    .into_iter()
+                    .map(|(key, value)| (key, Some(value)))
+                    .collect()
+            } else {
+                let keys = backend.list_keys(&target)?;
+                keys.into_iter().map(|key| (key, None)).collect()
+            };
+            if pairs.is_empty() && !opts.quiet {
+                eprintln!(
+                    "{} namespace `{target}` not defined.",
+                    paint("WARNING:", WARNING_STYLE, opts.color_stderr)
+                );
+            }
+            pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+            if let Some((key, _)) = pairs
+                .iter()
+                .find(|(_, value)| value.as_ref().is_some_and(|v| v.contains('\0')))
+            {
+                return Err(format!("The value of {key} contains a NUL byte"));
+            }
+            pairs
+                .into_iter()
+                .flat_map(|(key, value)| std::iter::once(key).chain(value))
+                .collect()
+        }
+        None => {
+            ensure_can_list_namespaces(backend)?;
+            let mut namespaces = backend.list_namespaces()?;
+            if let Some(re) = &opts.namespace_regex {
+                namespaces.retain(|ns| re.is_match(ns));
+            }
+            namespaces.sort();
+            namespaces
+        }
+    };
+    if let Some(name) = fields.iter().find(|field| field.contains('\0')) {
+        return Err(format!("`{}` contains a NUL byte", name.escape_debug()));
+    }
+    for field in &fields {
+        out.write_all(field.as_bytes())
+            .and_then(|()| out.write_all(b"\0"))
+            .map_err(|e| format!("Failed to write output: {e}"))?;
+    }
+    Ok(fields.is_empty())
+}
+
+/// `list --require`: print whether each of `keys` is set in the namespace.
+/// Returns whether all of them are.
+fn list_required(
+    backend: &dyn Backend,
+    target: &str,
+    keys: &[String],
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<bool, String> {
+    let target = resolve_namespace(backend, target, opts.ignore_case)?;
+    let present: std::collections::HashSet<String> =
+        backend.list_keys(&target)?.into_iter().collect();
+    let mut all_present = true;
+    for key in keys {
+        let status = if present.contains(key) {
+            "present".to_string()
+        } else {
+            all_present = false;
+            paint("missing", WARNING_STYLE, opts.color)
+        };
+        write_line(
+            out,
+            &format!("{}: {status}", paint(key, KEY_STYLE, opts.color)),
+        )?;
+    }
+    Ok(all_present)
+}
+
+/// List one namespace.  Returns whether the namespace has no variables.
+fn list_values(
+    backend: &dyn Backend,
+    target: &str,
+    opts: &ListOptions,
+    out: &mut dyn Write,
+) -> Result<bool, String> {
+    if opts.changed_since.is_some() && !backend.capabilities().timestamps {
+        return Err(
+            "--changed-since is not supported: this backend does not record modification times"
+                .to_string(),
+        );
+    }
+    let json = opts.format == ListFormat::Json;
+    let target = &resolve_namespace(backend, target, opts.ignore_case)?;
+    // Only fetch (and decrypt) values when they are going to be shown.
+    let secrets = if opts.show_value {
+        backend.list_secrets(target)?
+    } else {
+        HashMap::new()
+    };
+    let mut keys = if opts.show_value {
+        secrets.keys().cloned().collect()
+    } else {
+        backend.list_keys(target)?
+    };
+    // The allowlist configures exec mode; it is not a variable.
+    keys.retain(|key| key != EXEC_ALLOWLIST_KEY);
+    let empty = keys.is_empty();
+    if empty && !opts.quiet {
+        eprintln!(
+            "{} namespace `{}` not defined.\n         You can set via running `{} --set {} SOME_ENV_NAME`.\n",
+            paint("WARNING:", WARNING_STYLE, opts.color_stderr),
+            target,
+            env::args().next().unwrap_or_else(|| "envchain".into()),
+            target
+        );
+    }
+    if empty && !json {
+        return Ok(true);
+    }
+    let metadata = if json || opts.long || opts.changed_since.is_some() {
+        backend.list_metadata(target)?
+    } else {
+        HashMap::new()
+    };
+    let cutoff = opts.changed_since.map(|d| {
+        SystemTime::now()
+            .checked_sub(d)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    });
+    keys.sort();
+    keys.retain(|key| {
+        cutoff.is_none_or(|cutoff| {
+            let updated_at = metadata.get(key).and_then(|m| m.updated_at);
+            updated_at.is_some_and(|t| t >= cutoff)
+        })
+    });
+
+    if json {
+        let entries: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                let meta = metadata.get(key).cloned().unwrap_or_default();
+                let mut entry = serde_json::json!({
+                    "key": key,
+                    "created_at": format_timestamp_json(meta.created_at),
+                    "updated_at": format_timestamp_json(meta.updated_at),
+                });
+                if opts.show_value {
+                    entry["value"] = secrets[key].clone().into();
+                }
+                entry
+            })
+            .collect();
+        let mut json = serde_json::json!({
+            "schema_version": LIST_JSON_SCHEMA_VERSION,
+            "namespace": target,
+            "secrets": entries,
+        });
+        if let Some(sep) = opts.nest.as_deref().filter(|sep| !sep.is_empty()) {
+            let pairs = keys.iter().map(|key| (key.as_str(), secrets[key].as_str()));
+            json["nested"] = import::nest(pairs, sep)?;
+        }
+        write_json(out, &json, opts.pretty)?;
+        return Ok(empty);
+    }
+
+    for key in &keys {
+        let painted_key = paint(key, KEY_STYLE, opts.color);
+        let entry = if opts.show_value {
+            format!("{}={}", painted_key, secrets[key])
+        } else {
+            painted_key
+        };
+        if opts.long {
+            let meta = metadata.get(key).cloned().unwrap_or_default();
+            let line = format!(
+                "{}\t{}\t{entry}",
+                paint(&format_timestamp(meta.created_at), DIM_STYLE, opts.color),
+                paint(&format_timestamp(meta.updated_at), DIM_STYLE, opts.color)
+            );
+            write_line(out, &line)?;
+        } else {
+            write_line(out, &entry)?;
+        }
+    }
+    Ok(empty)
+}
+
+/// Options controlling how `set` obtains values.
+struct SetOptions {
+    noecho: bool,
+    /// Generate random values of this many bytes instead of prompting
+    generate: Option<usize>,
+    encoding: ValueEncoding,
+    show_generated: bool,
+    /// Only report the keys that would be set
+    dry_run: bool,
+    /// Interactive prompt with `{ns}` and `{key}` placeholders
+    prompt_format: String,
+    /// Read the value from this inherited file descriptor instead of prompting
+    value_from_fd: Option<i32>,
+    /// Prompt twice and require both entries to match
+    confirm: bool,
+    /// Keep trailing newlines instead of trimming them
+    raw: bool,
+    /// Edit one existing value; empty input keeps it
+    edit: bool,
+    /// Pre-fill the current value in an editable prompt
+    show_current: bool,
+    /// Read the value from the clipboard instead of prompting
+    #[cfg(feature = "clipboard")]
+    from_clipboard: bool,
+    /// Clear the clipboard once the value has been read
+    #[cfg(feature = "clipboard")]
+    clear_clipboard: bool,
+    /// Leave variables that are already set alone
+    if_absent: bool,
+    /// Read the variables and their values as lines from stdin
+    block: bool,
+    /// Store values as canonical JSON
+    canonicalize_json: bool,
+    /// Store values as seeds of this scheme
+    #[cfg(feature = "derived-values")]
+    derive: Option<derive::Scheme>,
+    /// Suppress the summary line
+    quiet: bool,
+}
+
+/// Read text from the system clipboard, optionally clearing it afterwards.
+/// Trailing newlines are trimmed unless `raw`.
+#[cfg(feature = "clipboard")]
+fn read_clipboard(clear: bool, raw: bool) -> Result<Zeroizing<String>, String> {
+    let mut clipboard =
+        arboard::Clipboard::new().map_err(|e| format!("Failed to open clipboard: {e}"))?;
+    let text = Zeroizing::new(
+        clipboard
+            .get_text()
+            .map_err(|e| format!("Failed to read clipboard: {e}"))?,
+    );
+    if clear {
+        clipboard
+            .clear()
+            .map_err(|e| format!("Failed to clear clipboard: {e}"))?;
+    }
+    let trimmed = if raw {
+        text.as_str()
+    } else {
+        text.trim_end_matches(['\n', '\r'])
+    };
+    if trimmed.is_empty() {
+        return Err("Clipboard is empty".to_string());
+    }
+    Ok(Zeroizing::new(trimmed.to_string()))
+}
+
+/// What `set` stores for an entered value: the value itself, its canonical
+/// form with `--canonicalize-json`, or with `--derive` a record of it as a
+/// seed.
+fn stored_value(
+    opts: &SetOptions,
+    name: &str,
+    key: &str,
+    value: Zeroizing<String>,
+) -> Result<Zeroizing<String>, String> {
+    if opts.canonicalize_json {
+        return canonical_json(&value).map_err(|e| format!("{name}.{key}: {e}"));
+    }
+    #[cfg(feature = "derived-values")]
+    if let Some(scheme) = opts.derive {
+        return derive::encode(scheme, &value);
+    }
+    Ok(value)
+}
+
+/// `value` parsed as JSON and written back compactly, with object keys
+/// sorted, so equal documents are always stored as the same string.
+fn canonical_json(value: &str) -> Result<Zeroizing<String>, String> {
+    let parsed: serde_json::Value = serde_json::from_str(value)
+        .map_err(|e| format!("value is not valid JSON ({e}); nothing was stored"))?;
+    Ok(Zeroizing::new(parsed.to_string()))
+}
+
+/// Fill in the `{ns}` and `{key}` placeholders of a `--prompt-format`.
+fn render_prompt(template: &str, namespace: &str, key: &str) -> String {
+    template.replace("{ns}", namespace).replace("{key}", key)
+}
+
+/// Mark a prompt as not echoing input: ` (noecho)` goes before a trailing
+/// colon, or is appended if there is none.
+fn noecho_prompt(prompt: &str) -> String {
+    let body = prompt.trim_end();
+    let trailing = &prompt[body.len()..];
+    match body.strip_suffix(':') {
+        Some(head) => format!("{head} (noecho):{trailing}"),
+        None => format!("{body} (noecho){trailing}"),
+    }
+}
+
+/// Generate `len` bytes from the OS CSPRNG and encode them as text.
+fn generate_value(len: usize, encoding: ValueEncoding) -> Result<Zeroizing<String>, String> {
+    if len == 0 {
+        return Err("--generate length must be at least 1".to_string());
+    }
+    let mut buf = Zeroizing::new(vec![0u8; len]);
+    getrandom::fill(&mut buf).map_err(|e| format!("Failed to generate random value: {e}"))?;
+    let encoded = match encoding {
+        ValueEncoding::Base64url => base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&*buf),
+        ValueEncoding::Hex => buf.iter().map(|b| format!("{b:02x}")).collect(),
+    };
+    Ok(Zeroizing::new(encoded))
+}
+
+/// Show `prompt` and read one value, hiding input with `--noecho`.
+///
+/// rpassword reads from `/dev/tty` rather than stdin, so when stdin is not a
+/// terminal the value is read from stdin like any other line: there is
+/// nothing to hide, and the pipe must not be ignored in favour of the tty.
+fn prompt_value(opts: &SetOptions, prompt: &str) -> Result<Zeroizing<String>, String> {
+    if opts.noecho && std::io::stdin().is_terminal() {
+        #[cfg(windows)]
+        if !stdin_is_console() {
+            return Err(
+                "--noecho cannot hide input here: stdin is a terminal but not a Windows \
+                 console, as in mintty (Git Bash); run envchain through `winpty`, or use \
+                 Windows Terminal, cmd.exe or PowerShell"
+                    .to_string(),
+            );
+        }
+        eprint!("{}", noecho_prompt(prompt));
+        return read_password()
+            .map(Zeroizing::new)
+            .map_err(|e| format!("Failed to read password: {e}"));
+    }
+    eprint!("{prompt}");
+    let mut bytes = Zeroizing::new(Vec::new());
+    let read = std::io::stdin()
+        .lock()
+        .read_until(b'\n', &mut bytes)
+        .map_err(|e| format!("Failed to read line: {e}"))?;
+    let buf = utf8_value(bytes, "Value read from stdin")?;
+    // A closed stdin would otherwise "confirm" an empty value.
+    if read == 0 && opts.confirm {
+        return Err("Unexpected end of input".to_string());
+    }
+    if opts.raw {
+        return Ok(buf);
+    }
+    let trimmed = buf.trim_end_matches(['\n', '\r']).to_string();
+    Ok(Zeroizing::new(trimmed))
+}
+
+/// Whether stdin is a Windows console, whose echo rpassword turns off with
+/// `SetConsoleMode`.  MSYS2 terminals such as mintty pass `is_terminal`
+/// but are pipes underneath, so rpassword would wait on a hidden console.
+#[cfg(windows)]
+fn stdin_is_console() -> bool {
+    use std::os::windows::io::AsRawHandle;
+    let mut mode = 0;
+    // SAFETY: GetConsoleMode only writes `mode`, and fails for handles
+    // that are not consoles.
+    unsafe {
+        windows_sys::Win32::System::Console::GetConsoleMode(
+            std::io::stdin().as_raw_handle(),
+            &mut mode,
+        ) != 0
+    }
+}
+
+/// Prompt for a value twice, as `passwd` does, until both entries match.
+fn prompt_confirmed(opts: &SetOptions, name: &str, key: &str) -> Result<Zeroizing<String>, String> {
+    const ATTEMPTS: usize = 3;
+    let prompt = render_prompt(&opts.prompt_format, name, key);
+    for _ in 0..ATTEMPTS {
+        let value = prompt_value(opts, &prompt)?;
+        let again = prompt_value(opts, &format!("Retype {prompt}"))?;
+        if value == again {
+            return Ok(value);
+        }
+        eprintln!("{name}.{key}: values do not match, try again");
+    }
+    Err(format!(
+        "{name}.{key}: values did not match after {ATTEMPTS} attempts; nothing stored"
+    ))
+}
+
+/// `set --edit`: ask for a new value of `key`, keeping the current one when
+/// the input is empty.  With `show_current` the current value is pre-filled
+/// in an editable line; otherwise it is masked.
+fn edit_value(
+    backend: &mut dyn Backend,
+    name: &str,
+    key: &str,
+    opts: &SetOptions,
+) -> Result<(), String> {
+    let prompt = render_prompt(&opts.prompt_format, name, key);
+    let Some(current) = backend.list_secrets(name)?.remove(key).map(Zeroizing::new) else {
+        eprintln!("{name}.{key}: not set yet");
+        let value = stored_value(opts, name, key, prompt_value(opts, &prompt)?)?;
+        backend.set_secret(name, key, &value)?;
+        return backend.flush();
+    };
+    let value = if opts.show_current {
+        let mut editor = rustyline::DefaultEditor::new()
+            .map_err(|e| format!("Failed to start line editor: {e}"))?;
+        Zeroizing::new(
+            editor
+                .readline_with_initial(&prompt, (current.as_str(), ""))
+                .map_err(|e| format!("Failed to read line: {e}"))?,
+        )
+    } else {
+        eprintln!("{name}.{key}: current value ********; press Enter to keep it");
+        prompt_value(opts, &prompt)?
+    };
+    if value.is_empty() {
+        eprintln!("{name}.{key}: unchanged");
+        return Ok(());
+    }
+    let value = stored_value(opts, name, key, value)?;
+    if value == current {
+        eprintln!("{name}.{key}: unchanged");
+        return Ok(());
+    }
+    backend.set_secret(name, key, &value)?;
+    backend.flush()
+}
+
+fn set_values(
+    backend: &mut dyn Backend,
+    name: &str,
+    keys: &[String],
+    opts: &SetOptions,
+) -> Result<(), String> {
+    if opts.block {
+        return set_block(backend, name, keys, opts);
+    }
+
+    let absent;
+    let keys = if opts.if_absent {
+        absent = skip_existing(backend, name, keys, opts.dry_run)?;
+        if absent.is_empty() {
+            return Ok(());
+        }
+        &absent[..]
+    } else {
+        keys
+    };
+
+    if opts.dry_run {
+        for key in keys {
+            println!("would set {name}.{key}");
+        }
+        return Ok(());
+    }
+
+    if let (true, [key]) = (opts.edit, keys) {
+        return edit_value(backend, name, key, opts);
+    }
+
+    if let Some(fd) = opts.value_from_fd {
+        let [key] = keys else {
+            return Err("--value-from-fd sets exactly one variable".to_string());
+        };
+        let value = stored_value(opts, name, key, read_value_fd(fd, opts.raw)?)?;
+        if backend.set_secret(name, key, &value)? {
+            eprintln!("{name}.{key}: set from fd {fd}");
+        } else {
+            eprintln!("{name}.{key}: unchanged");
+        }
+        return backend.flush();
+    }
+
+    #[cfg(feature = "clipboard")]
+    if opts.from_clipboard {
+        let [key] = keys else {
+            return Err("--from-clipboard sets exactly one variable".to_string());
+        };
+        let value = stored_value(
+            opts,
+            name,
+            key,
+            read_clipboard(opts.clear_clipboard, opts.raw)?,
+        )?;
+        if backend.set_secret(name, key, &value)? {
+            eprintln!("{name}.{key}: set from clipboard");
+        } else {
+            eprintln!("{name}.{key}: unchanged");
+        }
+        return backend.flush();
+    }
+
+    // Every value is entered before anything is stored, so the backend can
+    // write them in one go and an interrupted prompt stores nothing.
+    let mut entries = Vec::with_capacity(keys.len());
+    for key in keys {
+        let prompt = format!("{name}.{key}");
+        let value: Zeroizing<String> = if let Some(len) = opts.generate {
+            let value = generate_value(len, opts.encoding)?;
+            if opts.show_generated {
+                eprintln!("{prompt}: {}", value.as_str());
+            }
+            value
+        } else if opts.confirm {
+            prompt_confirmed(opts, name, key)?
+        } else {
+            prompt_value(opts, &render_prompt(&opts.prompt_format, name, key))?
+        };
+        entries.push((key.clone(), stored_value(opts, name, key, value)?));
+    }
+    let existing = backend.list_keys(name)?.into_iter().collect();
+    let written = backend.set_secrets(name, &entries)?;
+    for ((key, _), written) in entries.iter().zip(&written) {
+        if !written {
+            eprintln!("{name}.{key}: unchanged");
+        }
+    }
+    backend.flush()?;
+    if !opts.quiet {
+        eprintln!("{}", set_summary(name, &entries, &written, &existing));
+    }
+    Ok(())
+}
+
+/// The line confirming a `set`: how many variables were stored, split into
+/// new ones, replaced ones and those that already held the value.
+fn set_summary(
+    name: &str,
+    entries: &[(String, Zeroizing<String>)],
+    written: &[bool],
+    existing: &std::collections::HashSet<String>,
+) -> String {
+    let (mut new, mut updated, mut unchanged) = (0, 0, 0);
+    for ((key, _), written) in entries.iter().zip(written) {
+        match (written, existing.contains(key)) {
+            (false, _) => unchanged += 1,
+            (true, false) => new += 1,
+            (true, true) => updated += 1,
+        }
+    }
+    let details: Vec<String> = [(new, "new"), (updated, "updated"), (unchanged, "unchanged")]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, what)| format!("{count} {what}"))
+        .collect();
+    let stored = new + updated;
+    let plural = if stored == 1 { "" } else { "s" };
+    format!(
+        "Set {stored} variable{plural} in {name} ({})",
+        details.join(", ")
+    )
+}
+
+/// `set --if-absent`: the keys not yet set in `name`, reporting the others
+/// as skipped.
+fn skip_existing(
+    backend: &dyn Backend,
+    name: &str,
+    keys: &[String],
+    dry_run: bool,
+) -> Result<Vec<String>, String> {
+    let existing: std::collections::HashSet<_> = backend.list_keys(name)?.into_iter().collect();
+    let (set, missing): (Vec<_>, Vec<_>) =
+        keys.iter().cloned().partition(|key| existing.contains(key));
+    for key in set {
+        if dry_run {
+            println!("would skip {name}.{key} (already set)");
+        } else {
+            eprintln!("{name}.{key}: already set, skipped");
+        }
+    }
+    Ok(missing)
+}
+
+/// `set --block`: read `KEY=VALUE` or `KEY: VALUE` lines from stdin until
+/// EOF or a line holding only `.`, then store them all, or only those named
+/// in `keys` when it is not empty.  Nothing is stored if any line is
+/// malformed.
+fn set_block(
+    backend: &mut dyn Backend,
+    name: &str,
+    keys: &[String],
+    opts: &SetOptions,
+) -> Result<(), String> {
+    let interactive = std::io::stdin().is_terminal();
+    if interactive {
+        eprintln!("Enter KEY=VALUE lines for {name}; end with a `.` line or Ctrl-D:");
+    }
+    let mut entries: Vec<(String, Zeroizing<String>)> = Vec::new();
+    let mut number = 0;
+    loop {
+        let mut bytes = Zeroizing::new(Vec::new());
+        let read = std::io::stdin()
+            .lock()
+            .read_until(b'\n', &mut bytes)
+            .map_err(|e| format!("Failed to read line: {e}"))?;
+        number += 1;
+        let line = utf8_value(bytes, &format!("Line {number} of the block"))?;
+        let line = line.trim_end_matches(['\n', '\r']);
+        if read == 0 || line == "." {
+            break;
+        }
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let (key, value) = match (line.find('='), line.find(':')) {
+            (Some(eq), Some(colon)) if colon < eq => {
+                (&line[..colon], line[colon + 1..].trim_start())
+            }
+            (Some(eq), _) => (&line[..eq], &line[eq + 1..]),
+            (None, Some(colon)) => (&line[..colon], line[colon + 1..].trim_start()),
+            (None, None) => {
+                return Err(format!(
+                    "line {number}: expected KEY=VALUE or KEY: VALUE; nothing was stored"
+                ));
+            }
+        };
+        let key = key.trim();
+        if !is_shell_identifier(key) {
+            return Err(format!(
+                "line {number}: `{key}` is not a valid variable name; nothing was stored"
+            ));
+        }
+        if entries.iter().any(|(k, _)| k == key) {
+            return Err(format!(
+                "line {number}: `{key}` is given more than once; nothing was stored"
+            ));
+        }
+        let value = if opts.canonicalize_json {
+            canonical_json(value).map_err(|e| format!("line {number}: {e}"))?
+        } else {
+            Zeroizing::new(value.to_string())
+        };
+        entries.push((key.to_string(), value));
+        if interactive {
+            eprintln!("  {} accepted ({key})", entries.len());
+        }
+    }
+    if entries.is_empty() {
+        return Err("No variables given".to_string());
+    }
+    if !keys.is_empty() {
+        entries.retain(|(key, _)| {
+            let listed = keys.contains(key);
+            if !listed {
+                eprintln!("{name}.{key}: not listed on the command line, skipped");
+            }
+            listed
+        });
+        if entries.is_empty() {
+            return Err(format!(
+                "None of the variables read from stdin are listed; nothing stored in {name}"
+            ));
+        }
+    }
+
+    if opts.if_absent {
+        let keys: Vec<String> = entries.iter().map(|(key, _)| key.clone()).collect();
+        let missing = skip_existing(backend, name, &keys, opts.dry_run)?;
+        entries.retain(|(key, _)| missing.contains(key));
+        if entries.is_empty() {
+            return Ok(());
+        }
+    }
+    if opts.dry_run {
+        for (key, _) in &entries {
+            println!("would set {name}.{key}");
+        }
+        return Ok(());
+    }
+    let existing = backend.list_keys(name)?.into_iter().collect();
+    let written = backend.set_secrets(name, &entries)?;
+    backend.flush()?;
+    if !opts.quiet {
+        eprintln!("{}", set_summary(name, &entries, &written, &existing));
+    }
+    Ok(())
+}
+
+/// The first name that appears more than once in `names`.
+fn first_duplicate(names: &[String]) -> Option<&str> {
+    let mut seen = std::collections::HashSet::new();
+    names
+        .iter()
+        .find(|name| !seen.insert(name.as_str()))
+        .map(String::as_str)
+}
+
+/// Resolve the `(namespace, keys)` an `unset`-style command operates on.
+///
+/// A lone `NAMESPACE/KEY` argument is split on the first `/`; when explicit
+/// keys follow, the namespace is taken literally.
+fn resolve_key_path<'a>(
+    namespace: &'a str,
+    vars: &'a [String],
+) -> Result<(&'a str, Vec<String>), String> {
+    if !vars.is_empty() {
+        return Ok((namespace, vars.to_vec()));
+    }
+    match namespace.split_once('/') {
+        Some((ns, key)) if !ns.is_empty() && !key.is_empty() => Ok((ns, vec![key.to_string()])),
+        _ => Err(format!(
+            "No variables given for `{namespace}`; pass VARS or use NAMESPACE/KEY"
+        )),
+    }
+}
+
+fn unset_values(
+    backend: &mut dyn Backend,
+    name: &str,
+    keys: &[String],
+    dry_run: bool,
+    quiet: bool,
+) -> Result<(), String> {
+    let present: std::collections::HashSet<_> = backend.list_keys(name)?.into_iter().collect();
+    if dry_run {
+        for key in keys {
+            if present.contains(key) {
+                println!("would remove {name}.{key}");
+            } else {
+                println!("would skip {name}.{key} (not set)");
+            }
+        }
+        return Ok(());
+    }
+    let mut expected = 0;
+    for (i, key) in keys.iter().enumerate() {
+        if !present.contains(key) {
+            eprintln!("{name}.{key}: not set, nothing to delete");
+        } else if !keys[..i].contains(key) {
+            expected += 1;
+        }
+    }
+    // Delete in one call so that the age backend rewrites its file once.
+    let removed = backend.delete_secrets(name, keys)?;
+    if removed != expected {
+        eprintln!("{name}: removed {removed} items for {expected} variables");
+    }
+    backend.flush()?;
+    if !quiet && expected > 0 {
+        let plural = if expected == 1 { "" } else { "s" };
+        eprintln!("Removed {expected} variable{plural} from {name}");
+    }
+    Ok(())
+}
+
+fn clear_namespace(backend: &mut dyn Backend, name: &str, dry_run: bool) -> Result<(), String> {
+    if dry_run {
+        let count = backend.list_keys(name)?.len();
+        println!("would remove {count} variables from {name}");
+        return Ok(());
+    }
+    let removed = backend.delete_namespace(name)?;
+    backend.flush()?;
+    if removed == 0 {
+        eprintln!("{name}: not defined, nothing to delete");
+    } else {
+        eprintln!("Removed {removed} items from {name}");
+    }
+    Ok(())
+}
+
+fn set_locked(backend: &mut dyn Backend, lock: bool) -> Result<(), String> {
+    let (applied, done) = if lock {
+        (backend.lock()?, "Locked")
+    } else {
+        (backend.unlock()?, "Unlocked")
+    };
+    if applied {
+        eprintln!("{done} the {} store", backend.backend_name());
+    } else {
+        eprintln!(
+            "{}: not applicable to the {} backend",
+            if lock { "lock" } else { "unlock" },
+            backend.backend_name()
+        );
+    }
+    Ok(())
+}
+
+/// With `fix`, offer a repair (asking first unless `yes`) and apply it.
+/// Returns whether the problem was repaired.
+fn offer_fix(
+    description: &str,
+    fix: bool,
+    yes: bool,
+    apply: impl FnOnce() -> Result<(), String>,
+) -> Result<bool, String> {
+    if !fix {
+        return Ok(false);
+    }
+    if !yes && !doctor::confirm(&format!("{description}?"))? {
+        println!("  skipped: {description}");
+        return Ok(false);
+    }
+    match apply() {
+        Ok(()) => {
+            println!("  fixed: {description}");
+            Ok(true)
+        }
+        Err(e) => {
+            println!("  failed: {description}: {e}");
+            Ok(false)
+        }
+    }
+}
+
+/// The config directory and age identity problems `doctor` reports.
+fn doctor_file_problems(
+    #[cfg_attr(not(feature = "age-backend"), allow(unused_variables))] opts: &BackendOptions,
+) -> Result<Vec<doctor::Problem>, String> {
+    let config_dir = dirs::config_dir()
+        .ok_or("Could not determine config directory")?
+        .join("envchain");
+
+    #[cfg_attr(not(feature = "age-backend"), allow(unused_mut))]
+    let mut problems: Vec<doctor::Problem> =
+        doctor::check_config_dir(&config_dir).into_iter().collect();
+    #[cfg(feature = "age-backend")]
+    if opts.candidates.contains(&BackendType::Age) && opts.age_identity_command.is_none() {
+        let is_default = opts.age_identity.is_none();
+        let identity = opts
+            .age_identity
+            .clone()
+            .unwrap_or_else(|| config_dir.join("identity.txt"));
+        let recipient = (is_default && !env_flag("ENVCHAIN_AGE_NO_RECIPIENT_FILE"))
+            .then(|| config_dir.join("recipient.txt"));
+        problems.extend(doctor::check_identity(
+            &identity,
+            is_default,
+            &config_dir.join("secrets.age"),
+            recipient,
+        ));
+    }
+    Ok(problems)
+}
+
+/// `doctor --json`: the file problems, and whether each backend opens and
+/// passes its health check, as one JSON document.  The `default` backend is
+/// the one the configuration would use.  Returns whether there are no
+/// problems and the default backend is available.
+fn doctor_json(cli: &Cli, profile: &profile::Profile) -> Result<bool, String> {
+    let mut opts = parse_backend_options(cli, profile)?;
+    opts.probe = true;
+    // Probing must not create the config directory the file checks report on.
+    opts.no_create_config_dir = true;
+    let problems = doctor_file_problems(&opts)?;
+
+    let mut backend_types = BackendType::all();
+    for &backend_type in &opts.candidates {
+        if !backend_types.contains(&backend_type) {
+            backend_types.push(backend_type);
+        }
+    }
+    let results: Vec<(BackendType, Result<String, String>)> = backend_types
+        .into_iter()
+        .map(|backend_type| {
+            let result = open_backend(backend_type, &opts).and_then(|backend| {
+                backend.health_check()?;
+                Ok(match backend.is_locked()? {
+                    Some(true) => "passed its health check; the store is locked".to_string(),
+                    _ => "passed its health check".to_string(),
+                })
+            });
+            (backend_type, result)
+        })
+        .collect();
+    let default = opts
+        .candidates
+        .iter()
+        .find(|&&candidate| {
+            results
+                .iter()
+                .any(|(backend_type, result)| *backend_type == candidate && result.is_ok())
+        })
+        .or(opts.candidates.first())
+        .copied();
+
+    let mut backends = serde_json::Map::new();
+    let mut default_available = false;
+    for (backend_type, result) in results {
+        let is_default = Some(backend_type) == default;
+        default_available |= is_default && result.is_ok();
+        backends.insert(
+            backend_type.name().to_string(),
+            serde_json::json!({
+                "available": result.is_ok(),
+                "default": is_default,
+                "detail": result.unwrap_or_else(|e| e),
+            }),
+        );
+    }
+    let json = serde_json::json!({
+        "schema_version": DOCTOR_JSON_SCHEMA_VERSION,
+        "problems": problems.iter().map(|p| &p.description).collect::<Vec<_>>(),
+        "backends": backends,
+    });
+    println!("{json:#}");
+    Ok(problems.is_empty() && default_available)
+}
+
+/// `doctor`: report problems with the config directory, the age identity
+/// and the backend, repairing them with `fix`.  Returns whether no problems
+/// remain.
+fn run_doctor(cli: &Cli, profile: &profile::Profile, fix: bool, yes: bool) -> Result<bool, String> {
+    let mut opts = parse_backend_options(cli, profile)?;
+    opts.probe = true;
+    let problems = doctor_file_problems(&opts)?;
+
+    let mut remaining = 0;
+    let mut fixable = false;
+    for problem in &problems {
+        println!("problem: {}", problem.description);
+        let fixed = match &problem.fix {
+            Some(f) => {
+                fixable = true;
+                offer_fix(&f.describe(), fix, yes, || f.apply())?
+            }
+            None => false,
+        };
+        if !fixed {
+            remaining += 1;
+        }
+    }
+
+    // Opening the age backend creates the config directory, so a check-only
+    // run must not get that far while file problems are outstanding.
+    if remaining > 0 {
+        println!("skipped: backend check (resolve the problems above first)");
+    } else {
+        match create_backend(opts) {
+            Err(e) => {
+                println!("problem: the backend could not be opened: {e}");
+                remaining += 1;
+            }
+            Ok(mut backend) => {
+                let name = backend.backend_name();
+                match backend.health_check().and_then(|()| backend.is_locked()) {
+                    Ok(Some(true)) => {
+                        println!("problem: the {name} store is locked");
+                        fixable = true;
+                        if !offer_fix(&format!("Unlock the {name} store"), fix, yes, || {
+                            backend.unlock().map(drop)
+                        })? {
+                            remaining += 1;
+                        }
+                    }
+                    Ok(_) => {
+                        println!("ok: the {name} backend passed its health check");
+                        match backend.outdated_items() {
+                            Ok(items) if items.is_empty() => {}
+                            Ok(items) => {
+                                let count = items.len();
+                                println!(
+                                    "problem: {count} {name} items were stored by an older envchain \
+                                     and are not tagged as envchain's"
+                                );
+                                for item in &items {
+                                    println!("  {item}");
+                                }
+                                fixable = true;
+                                if !offer_fix(
+                                    &format!("Tag these {count} {name} items as envchain's"),
+                                    fix,
+                                    yes,
+                                    || backend.upgrade_items().map(drop),
+                                )? {
+                                    remaining += 1;
+                                }
+                            }
+                            Err(e) => {
+                                println!("problem: {e}");
+                                remaining += 1;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("problem: {e}");
+                        remaining += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    if remaining == 0 {
+        println!("No problems found");
+    } else if fixable && !fix {
+        println!("Run `envchain doctor --fix` to repair what can be repaired");
+    }
+    Ok(remaining == 0)
+}
+
+/// `--setuid`/`--setgid`: the uid and gid to run the command as.  Without
+/// `--setgid` the user's primary group is used, so the command does not
+/// keep envchain's own (often root's) group.
+#[cfg(unix)]
+fn run_as(user: Option<&str>, group: Option<&str>) -> Result<(Option<u32>, Option<u32>), String> {
+    let mut gid = group.map(lookup_group).transpose()?;
+    let Some(user) = user else {
+        return Ok((None, gid));
+    };
+    let (uid, primary) = lookup_user(user)?;
+    gid = gid.or(primary);
+    if gid.is_none() {
+        return Err(format!(
+            "--setuid {user}: no such user to take a primary group from; pass --setgid"
+        ));
+    }
+    Ok((Some(uid), gid))
+}
+
+/// The uid and, if the user has a passwd entry, primary gid of `user`,
+/// given by name or number.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(u32, Option<u32>), String> {
+    let entry = match user.parse::<u32>() {
+        // SAFETY: getpwuid only reads its argument.
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = std::ffi::CString::new(user)
+                .map_err(|_| format!("--setuid {user}: not a user name"))?;
+            // SAFETY: `name` is a valid NUL-terminated string.
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+    if entry.is_null() {
+        return match user.parse::<u32>() {
+            Ok(uid) => Ok((uid, None)),
+            Err(_) => Err(format!("--setuid {user}: no such user")),
+        };
+    }
+    // SAFETY: a non-null result points to a valid passwd record, read
+    // before any other lookup can overwrite it.
+    let entry = unsafe { &*entry };
+    Ok((entry.pw_uid, Some(entry.pw_gid)))
+}
+
+/// The gid of `group`, given by name or number.
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    let name =
+        std::ffi::CString::new(group).map_err(|_| format!("--setgid {group}: not a group name"))?;
+    // SAFETY: `name` is a valid NUL-terminated string.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("--setgid {group}: no such group"));
+    }
+    // SAFETY: a non-null result points to a valid group record.
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// Options controlling how the child process is launched in exec mode.
+struct ExecOptions {
+    /// Match namespace names case-insensitively
+    ignore_case: bool,
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    update_wslenv: bool,
+    /// Refuse to run when the environment is close to the OS limit
+    strict: bool,
+    /// Resolution of keys defined in several namespaces
+    precedence: Precedence,
+    /// Log each variable as it is set
+    verbose: bool,
+    /// Log how long reading secrets and starting the command take
+    timings: bool,
+    /// When envchain started, for the `--timings` total
+    started: Instant,
+    /// Hook run before the command; the command is skipped if it fails
+    pre_exec: Option<String>,
+    /// Hook run after the command exits
+    post_exec: Option<String>,
+    /// Extra attempts when the command exits non-zero
+    retry_exec: u32,
+    /// Pause between attempts
+    retry_exec_delay: Duration,
+    /// Leave variables already in the environment alone
+    keep_existing: bool,
+    /// Load the parent envchain's namespaces before our own
+    inherit: bool,
+    /// Limit each namespace to the keys its `EXEC_ALLOWLIST_KEY` lists
+    respect_allowlist: bool,
+    /// In-memory overrides applied on top of the stored secrets
+    set_env: Vec<(String, String)>,
+    /// Working directory for the command
+    chdir: Option<PathBuf>,
+    /// User to run the command as
+    setuid: Option<String>,
+    /// Group to run the command with
+    setgid: Option<String>,
+    /// Hand the secrets over on a pipe rather than in the environment
+    #[cfg(unix)]
+    no_env_exposure: bool,
+    /// Key whose value becomes the command's stdin instead of a variable
+    #[cfg(unix)]
+    stdin_key: Option<String>,
+}
+
+/// Give a spawned child the default SIGINT/SIGQUIT handling even though
+/// envchain ignores them while it waits.
+#[cfg(unix)]
+fn restore_default_signals(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    // SAFETY: the closure runs in the forked child before exec and only
+    // calls signal(2), which is async-signal-safe.
+    unsafe {
+        command.pre_exec(|| {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+            Ok(())
+        });
+    }
+}
+
+/// Fraction of the platform limit at which `check_env_size` starts warning.
+const ENV_SIZE_WARN_RATIO: f64 = 0.75;
+
+/// Linux rejects any single `KEY=value` string longer than this
+/// (`MAX_ARG_STRLEN`, 32 pages).
+#[cfg(target_os = "linux")]
+const MAX_ARG_STRLEN: usize = 32 * 4096;
+
+/// Bytes available for arguments plus environment of a new process.
+#[cfg(unix)]
+fn env_size_limit() -> Option<usize> {
+    // SAFETY: sysconf only queries a system constant.
+    let limit = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+    usize::try_from(limit).ok().filter(|&l| l > 0)
+}
+
+/// The Windows environment block holds at most 32767 UTF-16 units.
+#[cfg(not(unix))]
+fn env_size_limit() -> Option<usize> {
+    Some(32767)
+}
+
+/// Warn (or fail with `strict`) when the injected variables push the
+/// child's arguments and environment close to the platform limit, so an
+/// opaque `E2BIG` spawn failure becomes an actionable message.
+fn check_env_size(
+    env_pairs: &[(String, Zeroizing<String>)],
+    cmd: &str,
+    args: &[String],
+    strict: bool,
+) -> Result<(), String> {
+    let entry_size = |key: &str, value: &str| key.len() + value.len() + 2;
+    let injected: usize = env_pairs.iter().map(|(k, v)| entry_size(k, v)).sum();
+    let inherited: usize = env::vars_os()
+        .filter(|(k, _)| {
+            !env_pairs
+                .iter()
+                .any(|(key, _)| k.as_os_str() == key.as_str())
+        })
+        .map(|(k, v)| k.len() + v.len() + 2)
+        .sum();
+    let arguments: usize = cmd.len() + 1 + args.iter().map(|a| a.len() + 1).sum::<usize>();
+    let total = injected + inherited + arguments;
+
+    let mut problems = Vec::new();
+    if let Some(limit) = env_size_limit()
+        && total as f64 >= limit as f64 * ENV_SIZE_WARN_RATIO
+    {
+        problems.push(format!(
+            "arguments and environment total {total} bytes ({injected} from envchain), \
+             close to the limit of {limit} bytes"
+        ));
+    }
+    #[cfg(target_os = "linux")]
+    for (key, value) in env_pairs {
+        if entry_size(key, value) > MAX_ARG_STRLEN {
+            problems.push(format!(
+                "{key} is {} bytes, over the {MAX_ARG_STRLEN}-byte limit for a single variable",
+                value.len()
+            ));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let mut largest: Vec<(&str, usize)> = env_pairs
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.len()))
+        .collect();
+    largest.sort_by_key(|&(_, len)| std::cmp::Reverse(len));
+    let largest: Vec<String> = largest
+        .iter()
+        .take(3)
+        .map(|(k, len)| format!("{k} ({len} bytes)"))
+        .collect();
+    let message = format!(
+        "{}\nLargest injected variables: {}",
+        problems.join("\n"),
+        largest.join(", ")
+    );
+    if strict {
+        return Err(message);
+    }
+    eprintln!("Warning: {message}");
+    Ok(())
+}
+
+/// Run `git` with `args` in `dir` (or the current directory) and return its
+/// trimmed output.
+fn git_output(dir: Option<&Path>, args: &[&str]) -> Result<String, String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let output = command
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run git: {e}"))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// `--namespace-from-git`: the name of the repository containing `dir`.
+fn git_namespace(source: GitNamespace, dir: Option<&Path>) -> Result<String, String> {
+    let fail = |e: String| format!("--namespace-from-git: {e}");
+    let toplevel = git_output(dir, &["rev-parse", "--show-toplevel"]).map_err(fail)?;
+    let name = match source {
+        GitNamespace::Dir => Path::new(&toplevel)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        GitNamespace::Remote => {
+            let url = git_output(dir, &["remote", "get-url", "origin"]).map_err(fail)?;
+            // https://host/owner/repo.git, git@host:owner/repo.git, /path/repo
+            url.trim_end_matches('/')
+                .rsplit(['/', ':'])
+                .next()
+                .map(|name| name.trim_end_matches(".git").to_string())
+        }
+    };
+    name.filter(|name| !name.is_empty())
+        .ok_or_else(|| fail(format!("cannot derive a namespace from {toplevel}")))
+}
+
+/// Read one command line from stdin and split it into words with POSIX
+/// shell quoting rules.  Nothing is expanded or interpreted by a shell.
+fn read_stdin_command() -> Result<(String, Vec<String>), String> {
+    let mut input = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+        .map_err(|e| format!("Failed to read command from stdin: {e}"))?;
+    let mut lines = input.lines().filter(|l| !l.trim().is_empty());
+    let line = lines.next().ok_or("No command given on stdin")?;
+    if lines.next().is_some() {
+        return Err("Expected a single command line on stdin".to_string());
+    }
+    let mut words = shlex::split(line)
+        .ok_or("Unbalanced quotes in the command read from stdin")?
+        .into_iter();
+    let command = words.next().ok_or("No command given on stdin")?;
+    Ok((command, words.collect()))
+}
+
+/// Build a command that runs `script` through the platform shell.
+fn shell_command(script: &str) -> Command {
+    #[cfg(unix)]
+    {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+    #[cfg(not(unix))]
+    {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(script);
+        command
+    }
+}
+
+/// Whether `cmd` is a script whose `#!` line starts envchain itself, as in
+/// `#!/usr/bin/env -S envchain NAMESPACE`.  The kernel then runs
+/// `envchain NAMESPACE SCRIPT ARGS...`, and executing the script again
+/// would only start envchain once more, forever.
+fn runs_envchain(cmd: &str) -> bool {
+    use std::io::Read;
+
+    // The kernel passes the script's path; a bare name is a PATH lookup,
+    // not a script that invoked us.
+    if !cmd.contains('/') {
+        return false;
+    }
+    let mut head = Vec::new();
+    let read = std::fs::File::open(cmd).and_then(|file| file.take(256).read_to_end(&mut head));
+    if read.is_err() {
+        return false;
+    }
+    let Some(shebang) = head.strip_prefix(b"#!") else {
+        return false;
+    };
+    let line = shebang.split(|&b| b == b'\n').next().unwrap_or_default();
+    String::from_utf8_lossy(line)
+        .split_whitespace()
+        .any(|word| {
+            Path::new(word)
+                .file_name()
+                .is_some_and(|name| name == "envchain")
+        })
+}
+
+/// Run an exec hook and return its exit code.
+fn run_hook(kind: &str, mut command: Command) -> Result<i32, String> {
+    #[cfg(unix)]
+    restore_default_signals(&mut command);
+    let status = command
+        .status()
+        .map_err(|e| format!("Failed to run {kind} hook: {e}"))?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// `import --force` is `--on-conflict overwrite`; without either, any
+/// conflict fails.
+fn conflict_mode(force: bool, on_conflict: Option<import::OnConflict>) -> import::OnConflict {
+    on_conflict.unwrap_or(if force {
+        import::OnConflict::Overwrite
+    } else {
+        import::OnConflict::Fail
+    })
+}
+
+fn copy_namespace(
+    backend: &mut dyn Backend,
+    src: &str,
+    dst: &str,
+    force: bool,
+    on_conflict: Option<import::OnConflict>,
+) -> Result<(), String> {
+    if src == dst {
+        return Err("Source and destination namespaces are the same".to_string());
+    }
+    let secrets = backend.list_secrets(src)?;
+    if secrets.is_empty() {
+        return Err(format!("namespace `{src}` not defined"));
+    }
+    if let Some(on_conflict) = on_conflict {
+        let entries: Vec<import::ImportEntry> = secrets
+            .into_iter()
+            .map(|(key, value)| import::ImportEntry {
+                namespace: dst.to_string(),
+                key,
+                value: Zeroizing::new(value),
+            })
+            .collect();
+        let summary = import::store(backend, &entries, on_conflict)?;
+        eprintln!(
+            "Copied {} variables from {src} to {dst}{summary}",
+            summary.written
+        );
+        return Ok(());
+    }
+    if !force && !backend.list_secrets(dst)?.is_empty() {
+        return Err(format!(
+            "namespace `{dst}` already has variables; pass --force to overwrite or --on-conflict to choose"
+        ));
+    }
+    backend.clone_namespace(src, dst)?;
+    backend.flush()
+}
+
+/// `rename`: move every variable in `src` to `dst`.  Unless `force` is
+/// given, `dst` must not have variables yet.
+fn rename_namespace(
+    backend: &mut dyn Backend,
+    src: &str,
+    dst: &str,
+    force: bool,
+) -> Result<(), String> {
+    if src == dst {
+        return Err("Source and destination namespaces are the same".to_string());
+    }
+    if backend.list_keys(src)?.is_empty() {
+        return Err(format!("namespace `{src}` not defined"));
+    }
+    if !force && !backend.list_keys(dst)?.is_empty() {
+        return Err(format!(
+            "namespace `{dst}` already has variables; pass --force to merge into it"
+        ));
+    }
+    backend.rename_namespace(src, dst)?;
+    backend.flush()
+}
+
+/// `rotate`: pipe the current value into `command` and store its output,
+/// minus trailing newlines, in place of the old value.
+fn rotate(
+    backend: &mut dyn Backend,
+    namespace: &str,
+    key: &str,
+    command: &[String],
+) -> Result<(), String> {
+    let (program, args) = command.split_first().ok_or("No rotation command given")?;
+    backend.rotate_value(
+        namespace,
+        key,
+        Box::new(|current| run_rotation(program, args, current)),
+    )?;
+    backend.flush()?;
+    eprintln!("{namespace}.{key} rotated");
+    Ok(())
+}
+
+/// Run a rotation command with `current` on stdin and return what it prints.
+fn run_rotation(program: &str, args: &[String], current: &str) -> Result<String, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {program}: {e}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that ignores its input may exit before reading it.
+        let _ = stdin.write_all(current.as_bytes());
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run {program}: {e}"))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        return Err(format!(
+            "{program} failed ({}); the value was not changed",
+            output.status
+        ));
+    }
+    let stdout = utf8_value(stdout, &format!("The value printed by {program}"))?;
+    let value = stdout.trim_end_matches(['\n', '\r']);
+    if value.is_empty() {
+        return Err(format!(
+            "{program} printed nothing; the value was not changed"
+        ));
+    }
+    Ok(value.to_string())
+}
+
+fn import_legacy(
+    backend: &mut dyn Backend,
+    file: &std::path::Path,
+    on_conflict: import::OnConflict,
+) -> Result<(), String> {
+    let entries = legacy::parse_file(file)?;
+    if entries.is_empty() {
+        return Err(format!("No envchain variables found in {}", file.display()));
+    }
+    report_import(&import::store(backend, &entries, on_conflict)?);
+    Ok(())
+}
+
+fn report_import(summary: &import::StoreSummary) {
+    eprintln!(
+        "Imported {} variables into {} namespaces{summary}",
+        summary.written, summary.namespaces
+    );
+}
+
+fn import_structured(
+    backend: &mut dyn Backend,
+    namespace: &str,
+    file: &std::path::Path,
+    sep: &str,
+    null_input: bool,
+    on_conflict: import::OnConflict,
+) -> Result<(), String> {
+    if sep.is_empty() {
+        return Err("--flatten-sep must not be empty".to_string());
+    }
+    let is_csv = file
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    let entries = if null_input {
+        import::parse_null(file, namespace)?
+    } else if is_csv {
+        import::parse_csv(file, namespace)?
+    } else {
+        import::parse_structured(file, namespace, sep)?
+    };
+    if entries.is_empty() {
+        return Err(format!("No values found in {}", file.display()));
+    }
+    report_import(&import::store(backend, &entries, on_conflict)?);
+    Ok(())
+}
+
+/// Map a user-supplied namespace to the stored one.
+///
+/// With `ignore_case`, the namespace is matched against the backend's
+/// namespaces case-insensitively.  An exact match wins; otherwise, if several
+/// namespaces differ only by case, a warning is printed and the first one in
+/// sorted order is used.
+fn resolve_namespace(
+    backend: &dyn Backend,
+    name: &str,
+    ignore_case: bool,
+) -> Result<String, String> {
+    if !ignore_case {
+        return Ok(name.to_string());
+    }
+    let wanted = name.to_lowercase();
+    let mut matches = Vec::new();
+    backend.for_each_namespace(&mut |ns| {
+        if ns.to_lowercase() == wanted {
+            matches.push(ns.to_string());
+        }
+        Ok(())
+    })?;
+    matches.sort();
+    if matches.len() > 1 {
+        let chosen = matches
+            .iter()
+            .find(|ns| ns.as_str() == name)
+            .unwrap_or(&matches[0]);
+        eprintln!(
+            "WARNING: namespace `{name}` matches several namespaces differing only by case ({}); using `{chosen}`",
+            matches.join(", ")
+        );
+        return Ok(chosen.clone());
+    }
+    Ok(matches
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| name.to_string()))
+}
+
+/// Variables gathered from a namespace list for exec mode.
+struct CollectedEnv {
+    /// Resolved namespace names, in the order given
+    namespaces: Vec<String>,
+    /// One value per key, after `--precedence`
+    pairs: Vec<(String, Zeroizing<String>)>,
+    /// Every namespace defining each key, in the order given
+    sources: HashMap<String, Vec<String>>,
+}
+
+/// Keep one value per key, taken from the first or last namespace that
+/// defines it.  `pairs` is in namespace order.
+fn apply_precedence(pairs: &mut Vec<(String, Zeroizing<String>)>, precedence: Precedence) {
+    let mut seen = std::collections::HashSet::new();
+    if precedence == Precedence::Last {
+        pairs.reverse();
+    }
+    pairs.retain(|(key, _)| seen.insert(key.clone()));
+    if precedence == Precedence::Last {
+        pairs.reverse();
+    }
+}
+
+/// Reserved key holding the comma-separated keys of its namespace that
+/// `--respect-allowlist` injects.  It is never injected itself.
+const EXEC_ALLOWLIST_KEY: &str = "__exec_allowlist__";
+
+/// Resolve a comma-separated namespace list and gather the variables exec
+/// mode injects for it, in namespace order.  With `respect_allowlist`, a
+/// namespace with an `EXEC_ALLOWLIST_KEY` only contributes the keys it
+/// lists.
+fn collect_env(
+    backend: &dyn Backend,
+    name_csv: &str,
+    ignore_case: bool,
+    precedence: Precedence,
+    respect_allowlist: bool,
+) -> Result<CollectedEnv, String> {
+    let namespaces: Vec<String> = name_csv
+        .split(',')
+        .map(|name| resolve_namespace(backend, name, ignore_case))
+        .collect::<Result<_, _>>()?;
+    let mut pairs = Vec::new();
+    let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+    for name in &namespaces {
+        let mut secrets = backend.list_secrets(name)?;
+        let allowlist = secrets.remove(EXEC_ALLOWLIST_KEY).map(Zeroizing::new);
+        let allowed: Option<Vec<&str>> = allowlist
+            .as_ref()
+            .filter(|_| respect_allowlist)
+            .map(|list| list.split(',').map(str::trim).collect());
+        for (key, val) in secrets {
+            let val = Zeroizing::new(val);
+            if allowed
+                .as_ref()
+                .is_some_and(|allowed| !allowed.contains(&key.as_str()))
+            {
+                continue;
+            }
+            #[cfg(feature = "derived-values")]
+            let val = derive::resolve(&val)
+                .map_err(|e| format!("{name}.{key}: {e}"))?
+                .unwrap_or(val);
+            sources.entry(key.clone()).or_default().push(name.clone());
+            pairs.push((key, val));
+        }
+    }
+    apply_precedence(&mut pairs, precedence);
+    Ok(CollectedEnv {
+        namespaces,
+        pairs,
+        sources,
+    })
+}
+
+/// Of the namespaces defining a key, in the order given, the one its value
+/// is taken from.
+fn winning_source(sources: &[String], precedence: Precedence) -> &str {
+    match precedence {
+        Precedence::First => &sources[0],
+        Precedence::Last => &sources[sources.len() - 1],
+    }
+}
+
+/// Describe on stderr where an injected variable comes from, without its
+/// value.
+fn log_injection(key: &str, value: &str, sources: &[String], precedence: Precedence) {
+    let winner = winning_source(sources, precedence);
+    let others: Vec<&str> = sources
+        .iter()
+        .map(String::as_str)
+        .filter(|source| *source != winner)
+        .collect();
+    let mut line = format!(
+        "envchain: setting {key} ({} chars) from {winner}",
+        value.chars().count()
+    );
+    if !others.is_empty() {
+        line.push_str(&format!(
+            ", overriding {} (--precedence {})",
+            others.join(", "),
+            match precedence {
+                Precedence::First => "first",
+                Precedence::Last => "last",
+            }
+        ));
+    }
+    eprintln!("{line}");
+}
+
+/// `--no-override-existing`: drop the collected keys that are already set
+/// in envchain's environment, so the command inherits those values.  The
+/// `--stdin-key` variable is kept, as it does not go to the environment.
+fn keep_existing(pairs: &mut Vec<(String, Zeroizing<String>)>, opts: &ExecOptions) {
+    #[cfg(unix)]
+    let stdin_key = opts.stdin_key.as_deref();
+    #[cfg(not(unix))]
+    let stdin_key: Option<&str> = None;
+    pairs.retain(|(key, _)| {
+        if Some(key.as_str()) == stdin_key || env::var_os(key).is_none() {
+            return true;
+        }
+        if opts.verbose {
+            eprintln!("envchain: keeping {key} from the environment (--no-override-existing)");
+        }
+        false
+    });
+}
+
+/// Set by exec mode for the command: the namespaces it loaded, and how
+/// many envchain exec levels it runs under.
+const ACTIVE_NAMESPACES_VAR: &str = "ENVCHAIN_ACTIVE_NAMESPACES";
+const DEPTH_VAR: &str = "ENVCHAIN_DEPTH";
+/// Deeper nesting is taken to be envchain starting itself in a loop.
+const MAX_DEPTH: u32 = 16;
+
+/// The exec level this envchain runs at, from `ENVCHAIN_DEPTH`, refusing
+/// to go past `MAX_DEPTH`.
+fn exec_depth() -> Result<u32, String> {
+    let depth = env::var(DEPTH_VAR)
+        .ok()
+        .and_then(|depth| depth.parse::<u32>().ok())
+        .unwrap_or(0);
+    if depth >= MAX_DEPTH {
+        return Err(format!(
+            "envchain is already nested {depth} levels deep ({DEPTH_VAR}); refusing to start \
+             another level, which usually means a command runs envchain in a loop"
+        ));
+    }
+    Ok(depth)
+}
+
+/// `--inherit`: `name_csv` preceded by the parent envchain's namespaces
+/// that it does not name itself, so that with the default precedence its
+/// own namespaces win.
+fn inherit_namespaces(name_csv: &str, parent: &str) -> String {
+    let own: Vec<&str> = name_csv.split(',').collect();
+    parent
+        .split(',')
+        .filter(|name| !name.is_empty() && !own.contains(name))
+        .chain(own.iter().copied())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Layer `--set-env` overrides over the collected secrets, replacing stored
+/// values of the same key.  Returns the overridden keys.
+fn apply_set_env(
+    pairs: &mut Vec<(String, Zeroizing<String>)>,
+    overrides: &[(String, String)],
+) -> std::collections::HashSet<String> {
+    let mut overridden = std::collections::HashSet::new();
+    for (key, value) in overrides {
+        let value = Zeroizing::new(value.clone());
+        match pairs.iter_mut().find(|(k, _)| k == key) {
+            Some((_, stored)) => *stored = value,
+            None => pairs.push((key.clone(), value)),
+        }
+        overridden.insert(key.clone());
+    }
+    overridden
+}
+
+/// `--verbose` line for a variable set by `--set-env`.
+fn log_override(key: &str, value: &str, stored_in: Option<&Vec<String>>) {
+    let mut line = format!(
+        "envchain: setting {key} ({} chars) from --set-env",
+        value.chars().count()
+    );
+    if let Some(namespaces) = stored_in {
+        line.push_str(&format!(", overriding {}", namespaces.join(", ")));
+    }
+    eprintln!("{line}");
+}
+
+/// Create a pipe holding `data` and return its read end.  The write end is
+/// closed, so a reader sees EOF after the data.
+#[cfg(unix)]
+fn filled_pipe(data: &[u8]) -> std::io::Result<i32> {
+    use std::os::fd::FromRawFd;
+
+    let mut fds = [0; 2];
+    // SAFETY: pipe(2) stores two new descriptors in `fds`.  They are
+    // created without O_CLOEXEC, so the read end survives exec.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    // SAFETY: `write_fd` was just created and is owned by nothing else.
+    let mut writer = unsafe { std::fs::File::from_raw_fd(write_fd) };
+    // Nothing reads the pipe until the command starts, so fail rather than
+    // block when the data exceeds the pipe buffer.
+    // SAFETY: fcntl(2) on a descriptor we own.
+    unsafe { libc::fcntl(write_fd, libc::F_SETFL, libc::O_NONBLOCK) };
+    writer.write_all(data)?;
+    // Dropping the writer closes the write end.
+    Ok(read_fd)
+}
+
+/// Write `pairs` as NUL-terminated `KEY=VALUE` records into a pipe and
+/// return its read end, which the command inherits.
+#[cfg(unix)]
+fn secrets_pipe(pairs: &[(String, Zeroizing<String>)]) -> Result<i32, String> {
+    let mut records = Zeroizing::new(Vec::new());
+    for (key, value) in pairs {
+        records.extend_from_slice(key.as_bytes());
+        records.push(b'=');
+        records.extend_from_slice(value.as_bytes());
+        records.push(0);
+    }
+    filled_pipe(&records).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::WouldBlock {
+            "The secrets do not fit in a pipe buffer; --no-env-exposure cannot pass them"
+                .to_string()
+        } else {
+            format!("Failed to write secrets pipe: {e}")
+        }
+    })
+}
+
+/// A pipe holding `value`, to become the command's stdin.
+#[cfg(unix)]
+fn stdin_pipe(key: &str, value: &str) -> Result<std::process::Stdio, String> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let fd = filled_pipe(value.as_bytes()).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::WouldBlock {
+            format!("The value of {key} does not fit in a pipe buffer; --stdin-key cannot pass it")
+        } else {
+            format!("Failed to pipe {key} to stdin: {e}")
+        }
+    })?;
+    // The descriptor is duplicated onto stdin; keep the original from
+    // leaking into the command as well.
+    // SAFETY: fcntl(2) on a descriptor we own.
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    // SAFETY: `fd` was just created and is owned by nothing else.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) }.into())
+}
+
+/// Changes exec mode makes to the environment of the command and its
+/// hooks.  They are applied to each `Command` rather than to envchain's own
+/// environment, which never holds the secrets.
+struct ChildEnv {
+    vars: Vec<(String, Zeroizing<String>)>,
+    removed: Vec<&'static str>,
+}
+
+impl ChildEnv {
+    fn apply(&self, command: &mut Command) {
+        for name in &self.removed {
+            command.env_remove(name);
+        }
+        for (key, value) in &self.vars {
+            command.env(key, value.as_str());
+        }
+    }
+}
+
+/// The variables to give the command for the collected `env_pairs`:
+/// the pairs themselves, or with `--no-env-exposure` the pipe carrying
+/// them, plus `WSLENV` on Windows.
+fn child_env(
+    env_pairs: Vec<(String, Zeroizing<String>)>,
+    #[cfg_attr(not(target_os = "windows"), allow(unused_variables))] keys: &[String],
+    opts: &ExecOptions,
+) -> Result<ChildEnv, String> {
+    #[cfg(unix)]
+    #[cfg_attr(not(target_os = "windows"), allow(unused_mut))]
+    let mut vars = if opts.no_env_exposure {
+        let fd = secrets_pipe(&env_pairs)?;
+        if opts.verbose {
+            eprintln!(
+                "envchain: passing {} variables on fd {fd} (ENVCHAIN_SECRETS_FD) instead of the environment",
+                env_pairs.len()
+            );
+        }
+        vec![(
+            "ENVCHAIN_SECRETS_FD".to_string(),
+            Zeroizing::new(fd.to_string()),
+        )]
+    } else {
+        env_pairs
+    };
+    #[cfg(not(unix))]
+    #[cfg_attr(not(target_os = "windows"), allow(unused_mut))]
+    let mut vars = env_pairs;
+
+    // On Windows, append secret keys to WSLENV so they are forwarded
+    // across the WSL interop boundary when the child process is a WSL command.
+    // Keys already listed (e.g. by an outer envchain) are not appended twice.
+    #[cfg(target_os = "windows")]
+    if opts.update_wslenv && !keys.is_empty() {
+        let mut wslenv = env::var("WSLENV").unwrap_or_default();
+        for key in keys {
+            let present = wslenv
+                .split(':')
+                .any(|entry| entry.split('/').next() == Some(key.as_str()));
+            if present {
+                continue;
+            }
+            if !wslenv.is_empty() {
+                wslenv.push(':');
+            }
+            wslenv.push_str(key);
+        }
+        if opts.verbose {
+            eprintln!("envchain: setting WSLENV={wslenv}");
+        }
+        vars.push(("WSLENV".to_string(), Zeroizing::new(wslenv)));
+    }
+
+    // Key material inlined in ENVCHAIN_AGE_IDENTITY is not passed on to the
+    // command or hooks; a variable of that name from a namespace still is.
+    let removed = if inline_age_identity().is_some() {
+        vec!["ENVCHAIN_AGE_IDENTITY"]
+    } else {
+        Vec::new()
+    };
+    Ok(ChildEnv { vars, removed })
+}
+
+fn exec_with(
+    backend: Box<dyn Backend>,
     name_csv: &str,
     cmd: &str,
     args: &[String],
+    opts: &ExecOptions,
 ) -> Result<(), String> {
+    if let Some(dir) = &opts.chdir
+        && !dir.is_dir()
+    {
+        return Err(format!("--chdir {}: no such directory", dir.display()));
+    }
+    #[cfg(unix)]
+    let (uid, gid) = run_as(opts.setuid.as_deref(), opts.setgid.as_deref())?;
+    #[cfg(not(unix))]
+    if opts.setuid.is_some() || opts.setgid.is_some() {
+        return Err("--setuid and --setgid are only supported on Unix".to_string());
+    }
+    let depth = exec_depth()?;
+    let inherited;
+    let name_csv = match env::var(ACTIVE_NAMESPACES_VAR) {
+        Ok(parent) if opts.inherit && !parent.is_empty() => {
+            inherited = inherit_namespaces(name_csv, &parent);
+            if opts.verbose {
+                eprintln!("envchain: inheriting namespaces: {inherited}");
+            }
+            inherited.as_str()
+        }
+        _ => name_csv,
+    };
+
     // Collect all secrets before touching the environment.
-    let mut env_pairs: Vec<(String, Zeroizing<String>)> = Vec::new();
-    let mut keys: Vec<String> = Vec::new();
-    for name in name_csv.split(',') {
-        let secrets = backend.list_secrets(name)?;
-        for (key, val) in secrets {
-            env_pairs.push((key.clone(), Zeroizing::new(val)));
-            keys.push(key);
+    let start = Instant::now();
+    let CollectedEnv {
+        namespaces: names,
+        pairs: mut env_pairs,
+        sources,
+    } = collect_env(
+        backend.as_ref(),
+        name_csv,
+        opts.ignore_case,
+        opts.precedence,
+        opts.respect_allowlist,
+    )
+    .map_err(|e| attribute_error(backend.as_ref(), e))?;
+    if opts.timings {
+        backend::log_timing("read secrets", start);
+    }
+    if opts.keep_existing {
+        keep_existing(&mut env_pairs, opts);
+    }
+    let overridden = apply_set_env(&mut env_pairs, &opts.set_env);
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    policy::check_command(&names, cmd)?;
+    let keys: Vec<String> = env_pairs.iter().map(|(key, _)| key.clone()).collect();
+
+    #[cfg(unix)]
+    let stdin_value = match &opts.stdin_key {
+        Some(key) => {
+            let Some(index) = env_pairs.iter().position(|(k, _)| k == key) else {
+                return Err(format!("--stdin-key {key}: not set in {name_csv}"));
+            };
+            Some(env_pairs.remove(index))
+        }
+        None => None,
+    };
+
+    #[cfg(unix)]
+    let in_environment = !opts.no_env_exposure;
+    #[cfg(not(unix))]
+    let in_environment = true;
+    if in_environment {
+        check_env_size(&env_pairs, cmd, args, opts.strict)?;
+    }
+    audit::record_exec(backend.backend_name(), &names, &keys, cmd)?;
+
+    // Release the backend (and any keyring connection) before running
+    // anything.
+    drop(backend);
+
+    if opts.verbose {
+        for (key, val) in &env_pairs {
+            if overridden.contains(key) {
+                log_override(key, val, sources.get(key));
+            } else {
+                log_injection(key, val, &sources[key], opts.precedence);
+            }
+        }
+    }
+    if in_environment && opts.verbose && !env_pairs.is_empty() {
+        eprintln!(
+            "envchain: note: the injected variables can be read by other processes running as \
+             your user for as long as {cmd} runs (/proc/PID/environ on Linux); \
+             --no-env-exposure passes them on a pipe instead"
+        );
+    }
+
+    // The hooks are built up front so that the values can be dropped (and
+    // zeroized) as soon as every command has its copy.
+    let mut child_env = child_env(env_pairs, &keys, opts)?;
+    child_env.vars.push((
+        ACTIVE_NAMESPACES_VAR.to_string(),
+        Zeroizing::new(names.join(",")),
+    ));
+    child_env.vars.push((
+        DEPTH_VAR.to_string(),
+        Zeroizing::new((depth + 1).to_string()),
+    ));
+    let pre_exec = opts.pre_exec.as_deref().map(|script| {
+        let mut hook = shell_command(script);
+        child_env.apply(&mut hook);
+        hook
+    });
+    let post_exec = opts.post_exec.as_deref().map(|script| {
+        let mut hook = shell_command(script);
+        child_env.apply(&mut hook);
+        hook
+    });
+    let mut command = if runs_envchain(cmd) {
+        if opts.verbose {
+            eprintln!("envchain: {cmd} starts envchain from its #! line; running it with sh");
+        }
+        let mut command = Command::new("sh");
+        command.arg(cmd);
+        command
+    } else {
+        Command::new(cmd)
+    };
+    command.args(args);
+    child_env.apply(&mut command);
+    drop(child_env);
+
+    if let Some(hook) = pre_exec {
+        let code = run_hook("pre-exec", hook)?;
+        if code != 0 {
+            return Err(format!(
+                "pre-exec hook exited with {code}; not running {cmd}"
+            ));
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some((key, value)) = &stdin_value {
+        if opts.verbose {
+            eprintln!("envchain: piping {key} to the command's stdin");
+        }
+        command.stdin(stdin_pipe(key, value)?);
+    }
+    if let Some(dir) = &opts.chdir {
+        command.current_dir(dir);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        if let Some(gid) = gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = uid {
+            command.uid(uid);
+        }
+        if opts.verbose && (uid.is_some() || gid.is_some()) {
+            let id = |id: Option<u32>| id.map_or("unchanged".to_string(), |id| id.to_string());
+            eprintln!(
+                "envchain: running {cmd} as uid {}, gid {}",
+                id(uid),
+                id(gid)
+            );
+        }
+    }
+
+    // On Unix, replace this process with the command, as the original
+    // envchain does.  The command then owns the terminal directly: isatty,
+    // window-size changes (SIGWINCH) and job control behave exactly as if it
+    // had been started from the shell, with no intermediary process.
+    // A post-exec hook or retries need envchain to outlive the command, so
+    // they force the spawn path below.
+    #[cfg(unix)]
+    if opts.post_exec.is_none() && opts.retry_exec == 0 {
+        use std::os::unix::process::CommandExt;
+        if opts.timings {
+            backend::log_timing("total before exec", opts.started);
+        }
+        let err = command.exec();
+        return Err(format!("exec failed: {err}"));
+    }
+
+    // Otherwise spawn and wait.  The child inherits stdin/stdout/stderr, so
+    // console detection still sees the original terminal.
+    //
+    // Like system(3), ignore terminal interrupts while waiting so Ctrl-C
+    // reaches only the command and the post-exec hook still runs.
+    #[cfg(unix)]
+    {
+        restore_default_signals(&mut command);
+        // SAFETY: setting a signal disposition to SIG_IGN installs no
+        // handler code; children get SIG_DFL back before exec.
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+            libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+        }
+    }
+
+    let attempts = opts.retry_exec.saturating_add(1);
+    let mut code;
+    let mut attempt = 1;
+    loop {
+        let start = Instant::now();
+        let mut child = command.spawn().map_err(|e| format!("exec failed: {e}"))?;
+        if opts.timings {
+            backend::log_timing("spawn", start);
+            backend::log_timing("total before command", opts.started);
+        }
+        let status = child.wait().map_err(|e| format!("exec failed: {e}"))?;
+        code = status.code().unwrap_or(1);
+        if status.success() || attempt == attempts {
+            break;
+        }
+        // A command killed by a signal (e.g. Ctrl-C) is not retried.
+        #[cfg(unix)]
+        if std::os::unix::process::ExitStatusExt::signal(&status).is_some() {
+            break;
         }
+        eprintln!(
+            "Attempt {attempt}/{attempts} of {cmd} exited with {code}; retrying in {}",
+            humantime::format_duration(opts.retry_exec_delay)
+        );
+        std::thread::sleep(opts.retry_exec_delay);
+        attempt += 1;
+    }
+
+    if let Some(hook) = post_exec {
+        let hook_code = run_hook("post-exec", hook)?;
+        if hook_code != 0 {
+            eprintln!("post-exec hook exited with {hook_code} (command exited with {code})");
+            if code == 0 {
+                std::process::exit(hook_code);
+            }
+        }
+    }
+    std::process::exit(code);
+}
+
+/// envchain's own exit statuses.  Exec mode otherwise exits with the
+/// command's status.
+#[derive(Debug, Clone, Copy)]
+enum ExitCode {
+    /// Any runtime failure, or a lookup that found nothing (`printenv`,
+    /// `doctor`)
+    Error = 1,
+    /// Invalid arguments, detected before any backend is opened
+    Usage = 2,
+    /// `list --exit-on-empty` found no variables
+    Empty = 4,
+    /// `list --require` found a variable missing
+    MissingVars = 5,
+}
+
+impl ExitCode {
+    fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+fn main() {
+    let started = Instant::now();
+    let cli = Cli::parse();
+
+    let profile_name = cli
+        .profile
+        .clone()
+        .or_else(|| env::var("ENVCHAIN_PROFILE").ok());
+    let profile = match profile_name.as_deref().map(profile::load).transpose() {
+        Ok(p) => p.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::Error.exit();
+        }
+    };
+
+    #[cfg(unix)]
+    if cli.agent {
+        if let Err(e) = run_agent(&cli, &profile) {
+            eprintln!("{e}");
+            ExitCode::Error.exit();
+        }
+        return;
+    }
+
+    #[cfg(feature = "age-backend")]
+    if cli.age_identity_fingerprint {
+        if let Err(e) = print_age_fingerprint(&cli, &profile) {
+            eprintln!("{e}");
+            ExitCode::Error.exit();
+        }
+        return;
     }
 
-    // Drop the backend explicitly before mutating the environment.
-    // The secret-service backend's async-io reactor thread does not access the
-    // process environment, but dropping here ensures no backend-owned threads
-    // are running when set_var is called, making the unsafety below sound.
-    drop(backend);
+    // Handle get-completions subcommand first
+    if let Some(command) = &cli.command {
+        match command {
+            Commands::GetCompletions { shell, dynamic } => {
+                let mut cmd = Cli::command();
+                if let Err(e) = completions::generate(*shell, &mut cmd, *dynamic) {
+                    eprintln!("{e}");
+                    ExitCode::Usage.exit();
+                }
+                return;
+            }
+            Commands::Hook { tool } => {
+                match tool {
+                    HookTool::Direnv => print!("{DIRENV_HOOK}"),
+                }
+                return;
+            }
+            Commands::CompleteNamespaces | Commands::CompleteKeys { .. } => {
+                let namespace = match command {
+                    Commands::CompleteKeys { namespace } => Some(namespace.as_str()),
+                    _ => None,
+                };
+                let backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+                if let Err(e) = print_completion_names(backend.as_ref(), namespace) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            #[cfg(feature = "age-backend")]
+            Commands::Reset { yes, regenerate } => {
+                if let Err(e) = reset_age(&cli, &profile, *yes, *regenerate) {
+                    eprintln!("{e}");
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            #[cfg(feature = "age-backend")]
+            Commands::GenIdentity { path } => {
+                if let Err(e) = gen_identity(path.clone()) {
+                    eprintln!("{e}");
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::Set {
+                namespace,
+                vars,
+                noecho,
+                generate,
+                encoding,
+                show_generated,
+                prompt_format,
+                value_from_fd,
+                confirm,
+                raw,
+                edit,
+                show_current,
+                #[cfg(feature = "clipboard")]
+                from_clipboard,
+                #[cfg(feature = "clipboard")]
+                clear_clipboard,
+                allow_duplicates,
+                if_absent,
+                block,
+                canonicalize_json,
+                #[cfg(feature = "derived-values")]
+                derive,
+                quiet,
+            } => {
+                if *edit && vars.len() != 1 {
+                    eprintln!("--edit changes exactly one variable");
+                    ExitCode::Usage.exit();
+                }
+                if !*allow_duplicates && let Some(key) = first_duplicate(vars) {
+                    eprintln!(
+                        "`{key}` is given more than once; only the last value would be kept \
+                         (pass --allow-duplicates to prompt for it each time anyway)"
+                    );
+                    ExitCode::Usage.exit();
+                }
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+                let opts = SetOptions {
+                    noecho: *noecho,
+                    generate: *generate,
+                    encoding: *encoding,
+                    show_generated: *show_generated,
+                    dry_run: cli.dry_run,
+                    prompt_format: prompt_format.clone(),
+                    value_from_fd: *value_from_fd,
+                    confirm: *confirm,
+                    raw: *raw,
+                    edit: *edit,
+                    show_current: *show_current,
+                    #[cfg(feature = "clipboard")]
+                    from_clipboard: *from_clipboard,
+                    #[cfg(feature = "clipboard")]
+                    clear_clipboard: *clear_clipboard,
+                    if_absent: *if_absent,
+                    block: *block,
+                    canonicalize_json: *canonicalize_json,
+                    #[cfg(feature = "derived-values")]
+                    derive: *derive,
+                    quiet: *quiet,
+                };
+
+                if let Err(e) = set_values(backend.as_mut(), namespace, vars, &opts) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::List {
+                namespace,
+                show_value,
+                long,
+                changed_since,
+                format,
+                json_pretty,
+                machine,
+                output_fd,
+                group_by,
+                namespace_regex,
+                tree,
+                with_counts,
+                only_empty,
+                qualified,
+                nest,
+                exit_on_empty,
+                quiet,
+                redacted,
+                require,
+            } => {
+                let mut out: Box<dyn Write> = match output_fd.map(open_output_fd).transpose() {
+                    Ok(Some(out)) => out,
+                    Ok(None) => Box::new(std::io::stdout()),
+                    Err(e) => {
+                        eprintln!("{e}");
+                        ExitCode::Error.exit();
+                    }
+                };
+                if *redacted
+                    && matches!(
+                        format,
+                        ListFormat::Env | ListFormat::Export | ListFormat::Csv
+                    )
+                {
+                    let name = match format {
+                        ListFormat::Env => "env",
+                        ListFormat::Export => "export",
+                        _ => "csv",
+                    };
+                    eprintln!("--redacted cannot be combined with --format {name}");
+                    ExitCode::Usage.exit();
+                }
+                let format = if *machine {
+                    &ListFormat::Machine
+                } else {
+                    format
+                };
+                if *format == ListFormat::Machine
+                    && (*long
+                        || changed_since.is_some()
+                        || group_by.is_some()
+                        || nest.is_some()
+                        || *with_counts
+                        || *only_empty
+                        || *redacted)
+                {
+                    eprintln!(
+                        "--format machine prints names and values only; it cannot be combined with \
+                         --long, --changed-since, --group-by, --nest, --with-counts, --only-empty \
+                         or --redacted"
+                    );
+                    ExitCode::Usage.exit();
+                }
+                if *format == ListFormat::ShellArray
+                    && (*show_value
+                        || *long
+                        || changed_since.is_some()
+                        || group_by.is_some()
+                        || *json_pretty
+                        || *redacted)
+                {
+                    eprintln!(
+                        "--format shell-array lists names only; it cannot be combined with \
+                         --show-value, --long, --changed-since, --group-by, --json-pretty or --redacted"
+                    );
+                    ExitCode::Usage.exit();
+                }
+                if *with_counts && matches!(format, ListFormat::Csv | ListFormat::ShellArray) {
+                    eprintln!("--with-counts works with --format text or json only");
+                    ExitCode::Usage.exit();
+                }
+                if *only_empty && matches!(format, ListFormat::Csv | ListFormat::ShellArray) {
+                    eprintln!("--only-empty works with --format text or json only");
+                    ExitCode::Usage.exit();
+                }
+                if *qualified && *format != ListFormat::Env {
+                    eprintln!("--qualified works with --format env only");
+                    ExitCode::Usage.exit();
+                }
+                let backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+                let opts = ListOptions {
+                    show_value: *show_value,
+                    long: *long,
+                    changed_since: *changed_since,
+                    format: if *json_pretty || nest.is_some() {
+                        ListFormat::Json
+                    } else {
+                        *format
+                    },
+                    pretty: *json_pretty,
+                    ignore_case: cli.ignore_case,
+                    group_by: group_by.clone(),
+                    namespace_regex: namespace_regex.clone(),
+                    tree: *tree,
+                    with_counts: *with_counts,
+                    only_empty: *only_empty,
+                    qualified: *qualified,
+                    nest: nest.clone(),
+                    color: cli
+                        .color
+                        .enabled(output_fd.is_none() && std::io::stdout().is_terminal()),
+                    color_stderr: cli.color.enabled(std::io::stderr().is_terminal()),
+                    quiet: *quiet,
+                    precedence: cli.precedence,
+                };
+
+                if let (false, Some(ns)) = (require.is_empty(), namespace) {
+                    match list_required(backend.as_ref(), ns, require, &opts, out.as_mut()) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            drop(out);
+                            ExitCode::MissingVars.exit();
+                        }
+                        Err(e) => {
+                            eprintln!("{}", attribute_error(backend.as_ref(), e));
+                            ExitCode::Error.exit();
+                        }
+                    }
+                    return;
+                }
 
-    // SAFETY: This program is single-threaded at this point — the backend
-    // (and any threads it owns) has been dropped above.  No other thread can
-    // be reading the process environment concurrently, so set_var is sound.
-    for (key, val) in &env_pairs {
-        unsafe { env::set_var(key, val.as_str()) };
-    }
+                let res = if let (ListFormat::Env | ListFormat::Export, Some(ns)) =
+                    (opts.format, namespace)
+                {
+                    list_env(backend.as_ref(), ns, &opts, out.as_mut())
+                } else if opts.format == ListFormat::Csv {
+                    list_csv(backend.as_ref(), namespace.as_deref(), &opts, out.as_mut())
+                } else if opts.format == ListFormat::ShellArray {
+                    list_shell_array(backend.as_ref(), namespace.as_deref(), &opts, out.as_mut())
+                } else if opts.format == ListFormat::Machine {
+                    list_machine(backend.as_ref(), namespace.as_deref(), &opts, out.as_mut())
+                } else if *redacted {
+                    list_redacted(backend.as_ref(), namespace.as_deref(), &opts, out.as_mut())
+                        .map(|()| false)
+                } else if let Some(ns) = namespace {
+                    list_values(backend.as_ref(), ns, &opts, out.as_mut())
+                } else {
+                    list_namespaces(backend.as_ref(), &opts, out.as_mut()).map(|()| false)
+                };
 
-    // On Windows, append secret keys to WSLENV so they are forwarded
-    // across the WSL interop boundary when the child process is a WSL command.
-    #[cfg(target_os = "windows")]
-    if !keys.is_empty() {
-        let mut wslenv = env::var("WSLENV").unwrap_or_default();
-        for key in &keys {
-            if !wslenv.is_empty() {
-                wslenv.push(':');
+                match res {
+                    Ok(true) if *exit_on_empty => {
+                        drop(out);
+                        ExitCode::Empty.exit();
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("{}", attribute_error(backend.as_ref(), e));
+                        ExitCode::Error.exit();
+                    }
+                }
+                return;
             }
-            wslenv.push_str(key);
-        }
-        // SAFETY: same invariant as above — single-threaded after backend drop.
-        unsafe { env::set_var("WSLENV", &wslenv) };
-    }
-
-    let status = Command::new(cmd)
-        .args(args)
-        .status()
-        .map_err(|e| format!("exec failed: {e}"))?;
-    std::process::exit(status.code().unwrap_or(1));
-}
+            Commands::Rename { src, dst, force } => {
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
 
-fn main() {
-    let cli = Cli::parse();
+                if let Err(e) = rename_namespace(backend.as_mut(), src, dst, *force) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::Copy {
+                src,
+                dst,
+                force,
+                on_conflict,
+            } => {
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
 
-    // Handle get-completions subcommand first
-    if let Some(command) = &cli.command {
-        match command {
-            Commands::GetCompletions { shell } => {
-                let mut cmd = Cli::command();
-                print_completions(*shell, &mut cmd);
+                if let Err(e) = copy_namespace(backend.as_mut(), src, dst, *force, *on_conflict) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
                 return;
             }
-            Commands::Set {
+            Commands::Rotate {
                 namespace,
-                vars,
-                noecho,
+                key,
+                command,
             } => {
-                let (backend_type, age_identity) = parse_backend_options(&cli);
-                let mut backend = create_backend_or_exit(backend_type, age_identity);
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
 
-                if let Err(e) = set_values(backend.as_mut(), *noecho, namespace, vars) {
+                if let Err(e) = rotate(backend.as_mut(), namespace, key, command) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            #[cfg(feature = "watch")]
+            Commands::Watch { namespace, command } => {
+                let backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+                let Some(path) = backend.store_path().map(Path::to_path_buf) else {
+                    eprintln!(
+                        "watch needs a backend kept in a local file, such as age; {} is not",
+                        backend.backend_name()
+                    );
+                    ExitCode::Usage.exit();
+                };
+                drop(backend);
+                let (cmd, args) = command.split_first().expect("clap requires a command");
+                let load = || {
+                    let backend = parse_backend_options(&cli, &profile).and_then(create_backend)?;
+                    let collected = collect_env(
+                        backend.as_ref(),
+                        namespace,
+                        cli.ignore_case,
+                        cli.precedence,
+                        cli.respect_allowlist,
+                    )
+                    .map_err(|e| attribute_error(backend.as_ref(), e))?;
+                    let names: Vec<&str> =
+                        collected.namespaces.iter().map(String::as_str).collect();
+                    policy::check_command(&names, cmd)?;
+                    let keys: Vec<String> =
+                        collected.pairs.iter().map(|(key, _)| key.clone()).collect();
+                    audit::record_exec(backend.backend_name(), &names, &keys, cmd)?;
+                    Ok(collected.pairs)
+                };
+                if let Err(e) = watch::supervise(&path, load, cmd, args) {
                     eprintln!("{e}");
-                    std::process::exit(1);
+                    ExitCode::Error.exit();
                 }
                 return;
             }
-            Commands::List {
+            Commands::Import {
                 namespace,
-                show_value,
+                file,
+                flatten_sep,
+                null_input,
+                force,
+                on_conflict,
+            } => {
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+
+                if let Err(e) = import_structured(
+                    backend.as_mut(),
+                    namespace,
+                    file,
+                    flatten_sep,
+                    *null_input,
+                    conflict_mode(*force, *on_conflict),
+                ) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::ImportLegacy {
+                file,
+                force,
+                on_conflict,
+            } => {
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+
+                if let Err(e) =
+                    import_legacy(backend.as_mut(), file, conflict_mode(*force, *on_conflict))
+                {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::Unset {
+                namespace,
+                vars,
+                quiet,
             } => {
-                let (backend_type, age_identity) = parse_backend_options(&cli);
-                let backend = create_backend_or_exit(backend_type, age_identity);
+                let (namespace, vars) = match resolve_key_path(namespace, vars) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        ExitCode::Usage.exit();
+                    }
+                };
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+
+                if let Err(e) =
+                    unset_values(backend.as_mut(), namespace, &vars, cli.dry_run, *quiet)
+                {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::Lock | Commands::Unlock => {
+                let lock = matches!(command, Commands::Lock);
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
 
-                let res = if let Some(ns) = namespace {
-                    list_values(backend.as_ref(), ns, *show_value)
+                if let Err(e) = set_locked(backend.as_mut(), lock) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::Doctor { fix, yes, json } => {
+                let result = if *json {
+                    doctor_json(&cli, &profile)
                 } else {
-                    list_namespaces(backend.as_ref())
+                    run_doctor(&cli, &profile, *fix, *yes)
                 };
+                match result {
+                    Ok(true) => {}
+                    Ok(false) => ExitCode::Error.exit(),
+                    Err(e) => {
+                        eprintln!("{e}");
+                        ExitCode::Error.exit();
+                    }
+                }
+                return;
+            }
+            Commands::Env {
+                namespace,
+                key,
+                qualified,
+            } => {
+                let backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
 
-                if let Err(e) = res {
+                match print_env(
+                    backend.as_ref(),
+                    namespace,
+                    key.as_deref(),
+                    *qualified,
+                    cli.ignore_case,
+                    cli.precedence,
+                ) {
+                    Ok(true) => {}
+                    // Like printenv: a missing variable is exit status 1, silently.
+                    Ok(false) => ExitCode::Error.exit(),
+                    Err(e) => {
+                        eprintln!("{}", attribute_error(backend.as_ref(), e));
+                        ExitCode::Error.exit();
+                    }
+                }
+                return;
+            }
+            Commands::Export { namespace, format } => {
+                let backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+                let opts = ListOptions {
+                    show_value: false,
+                    long: false,
+                    changed_since: None,
+                    format: match format {
+                        ExportFormat::Shell => ListFormat::Export,
+                        ExportFormat::Dotenv => ListFormat::Env,
+                    },
+                    pretty: false,
+                    ignore_case: cli.ignore_case,
+                    group_by: None,
+                    namespace_regex: None,
+                    tree: false,
+                    with_counts: false,
+                    only_empty: false,
+                    qualified: false,
+                    nest: None,
+                    color: false,
+                    color_stderr: cli.color.enabled(std::io::stderr().is_terminal()),
+                    quiet: false,
+                    precedence: cli.precedence,
+                };
+                if let Err(e) = list_env(backend.as_ref(), namespace, &opts, &mut std::io::stdout())
+                {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
+                }
+                return;
+            }
+            Commands::Render {
+                namespace,
+                template,
+                output,
+                allow_missing,
+            } => {
+                let backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
+                if let Err(e) = render_template(
+                    backend.as_ref(),
+                    namespace,
+                    template,
+                    output.as_deref(),
+                    *allow_missing,
+                    cli.ignore_case,
+                    cli.precedence,
+                ) {
                     eprintln!("{e}");
-                    std::process::exit(1);
+                    ExitCode::Error.exit();
                 }
                 return;
             }
-            Commands::Unset { namespace, vars } => {
-                let (backend_type, age_identity) = parse_backend_options(&cli);
-                let mut backend = create_backend_or_exit(backend_type, age_identity);
+            Commands::Clear { namespace, yes } => {
+                if !yes && !cli.dry_run {
+                    eprintln!(
+                        "clear deletes every variable in `{namespace}`; pass --yes to confirm"
+                    );
+                    ExitCode::Usage.exit();
+                }
+                let mut backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
 
-                if let Err(e) = unset_values(backend.as_mut(), namespace, vars) {
-                    eprintln!("{e}");
-                    std::process::exit(1);
+                if let Err(e) = clear_namespace(backend.as_mut(), namespace, cli.dry_run) {
+                    eprintln!("{}", attribute_error(backend.as_ref(), e));
+                    ExitCode::Error.exit();
                 }
                 return;
             }
@@ -339,41 +4564,912 @@ fn main() {
     }
 
     // Default exec mode: envchain NAMESPACE COMMAND [ARGS...]
-    if let (Some(namespace), Some(command)) = (&cli.namespace, &cli.exec_command) {
-        let (backend_type, age_identity) = parse_backend_options(&cli);
-        let backend = create_backend_or_exit(backend_type, age_identity);
+    // When the profile or the git repository names a namespace, every
+    // positional is the command: envchain --profile NAME COMMAND [ARGS...]
+    let git_namespace = match cli.namespace_from_git {
+        Some(source) => match git_namespace(source, cli.chdir.as_deref()) {
+            Ok(namespace) => {
+                if cli.verbose {
+                    eprintln!("envchain: using namespace {namespace} from the git repository");
+                }
+                Some(namespace)
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::Error.exit();
+            }
+        },
+        None => None,
+    };
+    let exec = match (
+        git_namespace.as_ref().or(profile.namespace.as_ref()),
+        &cli.namespace,
+    ) {
+        (Some(namespace), Some(command)) => {
+            let args: Vec<String> = cli
+                .exec_command
+                .iter()
+                .chain(&cli.exec_args)
+                .cloned()
+                .collect();
+            Some((namespace, command, args))
+        }
+        (None, Some(namespace)) => cli
+            .exec_command
+            .as_ref()
+            .map(|command| (namespace, command, cli.exec_args.clone())),
+        _ => None,
+    };
+
+    if let Some((namespace, command, args)) = exec {
+        // `envchain NAMESPACE -` takes the command line from stdin.
+        let (command, args) = if command == "-" && args.is_empty() {
+            match read_stdin_command() {
+                Ok(argv) => argv,
+                Err(e) => {
+                    eprintln!("{e}");
+                    ExitCode::Usage.exit();
+                }
+            }
+        } else {
+            (command.clone(), args)
+        };
+        let backend = create_backend_or_exit(parse_backend_options(&cli, &profile));
 
-        if let Err(e) = exec_with(backend, namespace, command, &cli.exec_args) {
+        let opts = ExecOptions {
+            ignore_case: cli.ignore_case,
+            update_wslenv: !(cli.no_wslenv || env_flag("ENVCHAIN_NO_WSLENV")),
+            strict: cli.strict,
+            precedence: cli.precedence,
+            verbose: cli.verbose,
+            timings: cli.timings,
+            started,
+            pre_exec: cli.pre_exec.clone(),
+            post_exec: cli.post_exec.clone(),
+            retry_exec: cli.retry_exec,
+            retry_exec_delay: Duration::from_secs(cli.retry_exec_delay),
+            keep_existing: cli.no_override_existing,
+            inherit: cli.inherit,
+            respect_allowlist: cli.respect_allowlist,
+            set_env: cli.set_env.clone(),
+            chdir: cli.chdir.clone(),
+            setuid: cli.setuid.clone(),
+            setgid: cli.setgid.clone(),
+            #[cfg(unix)]
+            no_env_exposure: cli.no_env_exposure,
+            #[cfg(unix)]
+            stdin_key: cli.stdin_key.clone(),
+        };
+
+        if let Err(e) = exec_with(backend, namespace, &command, &args, &opts) {
             eprintln!("{e}");
-            std::process::exit(1);
+            ExitCode::Error.exit();
         }
     } else {
         // No valid subcommand or exec mode - show help
         eprintln!("Error: Missing subcommand or execution arguments\n");
         Cli::command().print_help().ok();
-        std::process::exit(2);
+        ExitCode::Usage.exit();
     }
 }
 
-fn parse_backend_options(cli: &Cli) -> (BackendType, Option<PathBuf>) {
+/// Append the active backend's name to an error from a backend operation.
+fn attribute_error(backend: &dyn Backend, e: String) -> String {
+    format!("{e} (backend: {})", backend.backend_name())
+}
+
+/// Whether a boolean environment switch such as `ENVCHAIN_NO_WSLENV=1` is on.
+fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+/// `ENVCHAIN_AGE_IDENTITY` when it holds the key itself rather than a path:
+/// an age secret key, a PEM/OpenSSH private key, or anything at all with
+/// `ENVCHAIN_AGE_IDENTITY_IS_INLINE=1`.
+fn inline_age_identity() -> Option<Zeroizing<String>> {
+    let value = Zeroizing::new(env::var("ENVCHAIN_AGE_IDENTITY").ok()?);
+    let key = value.trim_start();
+    let inline = env_flag("ENVCHAIN_AGE_IDENTITY_IS_INLINE")
+        || key.starts_with("AGE-SECRET-KEY-")
+        || key.starts_with("-----BEGIN");
+    inline.then_some(value)
+}
+
+/// Resolve backend options.  Precedence: flags, then `ENVCHAIN_*`
+/// environment variables, then the selected profile, then defaults.
+///
+/// With `--backend auto`, or with a backend order but no explicit backend,
+/// every backend in the order is tried until one opens.
+fn parse_backend_options(cli: &Cli, profile: &profile::Profile) -> Result<BackendOptions, String> {
     let backend_env = env::var("ENVCHAIN_BACKEND").ok();
-    let backend_str = cli.backend.as_deref().or_else(|| backend_env.as_deref());
-    let backend_type = backend_str
-        .and_then(BackendType::from_str)
-        .unwrap_or_else(BackendType::default);
+    let backend_str = cli
+        .backend
+        .as_deref()
+        .or(backend_env.as_deref())
+        .or(profile.backend.as_deref());
+    let order_env = env::var("ENVCHAIN_BACKEND_ORDER").ok();
+    let order_str = cli.backend_order.as_deref().or(order_env.as_deref());
 
-    (backend_type, cli.age_identity.clone())
+    let candidates = match (backend_str, order_str) {
+        (Some(b), order) if b.eq_ignore_ascii_case("auto") => match order {
+            Some(order) => BackendType::parse_order(order)?,
+            None => BackendType::all(),
+        },
+        (Some(b), _) => vec![BackendType::from_str(b)?],
+        (None, Some(order)) => BackendType::parse_order(order)?,
+        (None, None) => vec![BackendType::default()],
+    };
+
+    let age_identity_inline = cli
+        .age_identity
+        .is_none()
+        .then(inline_age_identity)
+        .flatten();
+    let age_identity = cli
+        .age_identity
+        .clone()
+        .or_else(|| {
+            env::var_os("ENVCHAIN_AGE_IDENTITY")
+                .filter(|_| age_identity_inline.is_none())
+                .map(PathBuf::from)
+        })
+        .or_else(|| profile.age_identity.clone());
+
+    // An explicit --age-identity beats a command inherited from the environment.
+    let age_identity_command = cli.age_identity_command.clone().or_else(|| {
+        cli.age_identity
+            .is_none()
+            .then(|| env::var("ENVCHAIN_AGE_IDENTITY_COMMAND").ok())
+            .flatten()
+    });
+
+    let retries = match cli.retry {
+        Some(n) => n,
+        None => match env::var("ENVCHAIN_RETRY") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| format!("Invalid ENVCHAIN_RETRY value `{v}`"))?,
+            Err(_) => 0,
+        },
+    };
+
+    let require = cli
+        .require_backend
+        .as_deref()
+        .map(|b| BackendType::from_str(b).map_err(|e| format!("--require-backend: {e}")))
+        .transpose()?;
+
+    // Opening must not write either: no generated identity, created
+    // collection or config directory.
+    let read_only = cli.read_only || env_flag("ENVCHAIN_READ_ONLY");
+    Ok(BackendOptions {
+        candidates,
+        retries,
+        require,
+        age_identity,
+        age_identity_inline,
+        age_identity_command,
+        age_identity_type: cli
+            .age_identity_type
+            .clone()
+            .or_else(|| env::var("ENVCHAIN_AGE_IDENTITY_TYPE").ok()),
+        auto_generate: cli.auto_generate && !read_only,
+        deferred: cli.deferred,
+        age_recipient: cli
+            .age_recipient
+            .clone()
+            .or_else(|| env::var_os("ENVCHAIN_AGE_RECIPIENT").map(PathBuf::from)),
+        age_recipients_file: cli
+            .age_recipients_file
+            .clone()
+            .or_else(|| env::var_os("ENVCHAIN_AGE_RECIPIENTS_FILE").map(PathBuf::from)),
+        age_recovery_recipient: cli
+            .age_recovery_recipient
+            .clone()
+            .or_else(|| env::var("ENVCHAIN_AGE_RECOVERY_RECIPIENT").ok()),
+        verify_fingerprint: cli
+            .verify_fingerprint
+            .clone()
+            .or_else(|| env::var("ENVCHAIN_AGE_FINGERPRINT").ok()),
+        use_ssh_key: cli.use_ssh_key || env_flag("ENVCHAIN_USE_SSH"),
+        no_create_config_dir: read_only
+            || cli.no_auto_create_config_dir
+            || env_flag("ENVCHAIN_NO_AUTO_CREATE_CONFIG_DIR"),
+        strict_perms: cli.strict_perms || env_flag("ENVCHAIN_STRICT_PERMS"),
+        age_compress: cli.age_compress || env_flag("ENVCHAIN_AGE_COMPRESS"),
+        read_only,
+        agent_socket: agent_socket(cli),
+        ss_collection_path: cli
+            .ss_collection_path
+            .clone()
+            .or_else(|| env::var("ENVCHAIN_SS_COLLECTION_PATH").ok()),
+        create_collection: cli.create_collection && !read_only,
+        ss_attributes: cli.ss_attributes.clone(),
+        ss_label_filter: cli.ss_label_filter || env_flag("ENVCHAIN_SS_LABEL_FILTER"),
+        probe: false,
+        timings: cli.timings,
+    })
 }
 
-fn create_backend_or_exit(
-    backend_type: BackendType,
-    age_identity: Option<PathBuf>,
-) -> Box<dyn Backend> {
-    match create_backend(backend_type, age_identity) {
+/// Parse a `list --namespace-regex` pattern.
+fn parse_namespace_regex(s: &str) -> Result<regex::Regex, String> {
+    regex::Regex::new(s).map_err(|e| format!("invalid regular expression: {e}"))
+}
+
+/// Parse a `--set-env KEY=VALUE`.  The value may itself contain `=`.
+fn parse_set_env(s: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(format!("expected KEY=VALUE, got `{s}`"));
+    };
+    if key.is_empty() {
+        return Err("variable name must not be empty".to_string());
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse a `--ss-attribute KEY=VALUE`.  `name` and `key` are reserved for
+/// envchain's own lookups.
+fn parse_ss_attribute(s: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = s.split_once('=') else {
+        return Err(format!("expected KEY=VALUE, got `{s}`"));
+    };
+    if name.is_empty() {
+        return Err("attribute name must not be empty".to_string());
+    }
+    if name == "name" || name == "key" || name == "application" {
+        return Err(format!("`{name}` is reserved for envchain's own lookups"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(unix)]
+fn agent_socket(cli: &Cli) -> Option<PathBuf> {
+    cli.agent_socket
+        .clone()
+        .or_else(|| env::var_os("ENVCHAIN_AGENT_SOCK").map(PathBuf::from))
+}
+
+#[cfg(not(unix))]
+fn agent_socket(_cli: &Cli) -> Option<PathBuf> {
+    None
+}
+
+/// Open the configured backend and serve it to `--backend agent` clients.
+#[cfg(unix)]
+fn run_agent(cli: &Cli, profile: &profile::Profile) -> Result<(), String> {
+    let opts = parse_backend_options(cli, profile)?;
+    if opts.candidates.contains(&BackendType::Agent) {
+        return Err("The agent cannot serve the `agent` backend itself".to_string());
+    }
+    let socket = match &opts.agent_socket {
+        Some(path) => path.clone(),
+        None => backend::agent::default_socket_path()?,
+    };
+    let backend = create_backend(opts)?;
+    backend::agent::serve(backend, &socket, cli.agent_timeout)
+}
+
+fn create_backend_or_exit(opts: Result<BackendOptions, String>) -> Box<dyn Backend> {
+    match opts.and_then(create_backend) {
         Ok(b) => b,
         Err(e) => {
             eprintln!("{e}");
-            std::process::exit(1);
+            ExitCode::Error.exit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::mock::MockBackend;
+
+    fn set_options() -> SetOptions {
+        SetOptions {
+            noecho: false,
+            generate: Some(16),
+            encoding: ValueEncoding::Hex,
+            show_generated: false,
+            dry_run: false,
+            prompt_format: "{ns}.{key}: ".to_string(),
+            value_from_fd: None,
+            confirm: false,
+            raw: false,
+            edit: false,
+            show_current: false,
+            #[cfg(feature = "clipboard")]
+            from_clipboard: false,
+            #[cfg(feature = "clipboard")]
+            clear_clipboard: false,
+            if_absent: false,
+            block: false,
+            canonicalize_json: false,
+            #[cfg(feature = "derived-values")]
+            derive: None,
+            quiet: true,
+        }
+    }
+
+    fn list_options() -> ListOptions {
+        ListOptions {
+            show_value: false,
+            long: false,
+            changed_since: None,
+            format: ListFormat::Text,
+            pretty: false,
+            ignore_case: false,
+            group_by: None,
+            namespace_regex: None,
+            tree: false,
+            with_counts: false,
+            only_empty: false,
+            qualified: false,
+            nest: None,
+            color: false,
+            color_stderr: false,
+            quiet: true,
+            precedence: Precedence::First,
+        }
+    }
+
+    fn keys(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn list(backend: &MockBackend, target: &str, opts: &ListOptions) -> (bool, String) {
+        let mut out = Vec::new();
+        let empty = list_values(backend, target, opts, &mut out).unwrap();
+        (empty, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn set_generates_values_and_flushes_once() {
+        let mut backend = MockBackend::default();
+        set_values(&mut backend, "aws", &keys(&["A", "B"]), &set_options()).unwrap();
+        for key in ["A", "B"] {
+            let value = backend.get("aws", key).unwrap();
+            assert_eq!(value.len(), 32);
+            assert!(value.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+        assert_ne!(backend.get("aws", "A"), backend.get("aws", "B"));
+        assert_eq!(backend.flushes, 1);
+    }
+
+    #[test]
+    fn set_dry_run_stores_nothing() {
+        let mut backend = MockBackend::default();
+        let opts = SetOptions {
+            dry_run: true,
+            ..set_options()
+        };
+        set_values(&mut backend, "aws", &keys(&["A"]), &opts).unwrap();
+        assert_eq!(backend.get("aws", "A"), None);
+        assert_eq!(backend.flushes, 0);
+    }
+
+    #[test]
+    fn set_if_absent_keeps_existing_values() {
+        let mut backend = MockBackend::with(&[("aws", "A", "old")]);
+        let opts = SetOptions {
+            if_absent: true,
+            ..set_options()
+        };
+        set_values(&mut backend, "aws", &keys(&["A", "B"]), &opts).unwrap();
+        assert_eq!(backend.get("aws", "A"), Some("old"));
+        assert!(backend.get("aws", "B").is_some());
+    }
+
+    /// A descriptor that reads `data` and then end of file.
+    #[cfg(unix)]
+    fn pipe_with(data: &[u8]) -> i32 {
+        use std::os::fd::FromRawFd;
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe() returns.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: the write end was just created and is owned here alone.
+        let mut writer = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+        writer.write_all(data).unwrap();
+        fds[0]
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn set_raw_keeps_the_trailing_newline() {
+        for (raw, stored) in [(true, "line\n"), (false, "line")] {
+            let mut backend = MockBackend::default();
+            let opts = SetOptions {
+                generate: None,
+                value_from_fd: Some(pipe_with(b"line\n")),
+                raw,
+                ..set_options()
+            };
+            set_values(&mut backend, "aws", &keys(&["A"]), &opts).unwrap();
+            assert_eq!(backend.get("aws", "A"), Some(stored));
         }
     }
+
+    #[test]
+    fn canonical_json_sorts_keys_and_drops_whitespace() {
+        let value =
+            canonical_json("{ \"b\": [1, 2],\n  \"a\": {\"y\": null, \"x\": \"s\"} }").unwrap();
+        assert_eq!(value.as_str(), r#"{"a":{"x":"s","y":null},"b":[1,2]}"#);
+        assert!(canonical_json("not json").is_err());
+    }
+
+    #[test]
+    fn set_reports_backend_errors() {
+        let mut backend = MockBackend::failing("keyring unavailable");
+        let err = set_values(&mut backend, "aws", &keys(&["A"]), &set_options()).unwrap_err();
+        assert_eq!(err, "keyring unavailable");
+    }
+
+    #[test]
+    fn list_prints_sorted_keys() {
+        let backend = MockBackend::with(&[("aws", "B", "2"), ("aws", "A", "1")]);
+        assert_eq!(
+            list(&backend, "aws", &list_options()),
+            (false, "A\nB\n".to_string())
+        );
+        let opts = ListOptions {
+            show_value: true,
+            ..list_options()
+        };
+        assert_eq!(
+            list(&backend, "aws", &opts),
+            (false, "A=1\nB=2\n".to_string())
+        );
+    }
+
+    #[test]
+    fn list_namespaces_with_counts() {
+        let backend = MockBackend::with(&[
+            ("team/api", "A", "1"),
+            ("team/api", "B", "2"),
+            ("aws", "A", "1"),
+        ]);
+        let opts = ListOptions {
+            with_counts: true,
+            ..list_options()
+        };
+        let mut out = Vec::new();
+        list_namespaces(&backend, &opts, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "aws (1 key)\nteam/api (2 keys)\n"
+        );
+        let opts = ListOptions {
+            group_by: Some("/".to_string()),
+            ..opts
+        };
+        let mut out = Vec::new();
+        list_namespaces(&backend, &opts, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "aws (1 key)\nteam/\n  api (2 keys)\n"
+        );
+    }
+
+    #[test]
+    fn copy_resolves_conflicts() {
+        let backend = || {
+            MockBackend::with(&[
+                ("src", "A", "new"),
+                ("src", "B", "same"),
+                ("src", "C", "3"),
+                ("dst", "A", "old"),
+                ("dst", "B", "same"),
+            ])
+        };
+
+        let mut fail = backend();
+        let err = copy_namespace(
+            &mut fail,
+            "src",
+            "dst",
+            false,
+            Some(import::OnConflict::Fail),
+        )
+        .unwrap_err();
+        assert!(err.starts_with("dst.A already exists"), "{err}");
+        assert_eq!(fail.get("dst", "C"), None);
+        assert_eq!(fail.flushes, 0);
+
+        let mut keep = backend();
+        copy_namespace(
+            &mut keep,
+            "src",
+            "dst",
+            false,
+            Some(import::OnConflict::Keep),
+        )
+        .unwrap();
+        assert_eq!(keep.get("dst", "A"), Some("old"));
+        assert_eq!(keep.get("dst", "C"), Some("3"));
+
+        let mut overwrite = backend();
+        let entries: Vec<import::ImportEntry> = [("A", "new"), ("B", "same"), ("C", "3")]
+            .into_iter()
+            .map(|(key, value)| import::ImportEntry {
+                namespace: "dst".to_string(),
+                key: key.to_string(),
+                value: Zeroizing::new(value.to_string()),
+            })
+            .collect();
+        let summary =
+            import::store(&mut overwrite, &entries, import::OnConflict::Overwrite).unwrap();
+        assert_eq!(overwrite.get("dst", "A"), Some("new"));
+        assert_eq!(
+            summary,
+            import::StoreSummary {
+                written: 2,
+                namespaces: 1,
+                overwritten: 1,
+                kept: 0,
+                unchanged: 1,
+            }
+        );
+        assert_eq!(summary.to_string(), " (1 overwritten, 1 unchanged)");
+    }
+
+    #[test]
+    fn inherited_namespaces_come_first_and_are_not_repeated() {
+        assert_eq!(inherit_namespaces("app", "base,aws"), "base,aws,app");
+        assert_eq!(inherit_namespaces("aws,app", "base,aws"), "base,aws,app");
+        assert_eq!(inherit_namespaces("app", ""), "app");
+    }
+
+    #[test]
+    fn set_summary_counts_new_updated_and_unchanged() {
+        let entries: Vec<(String, Zeroizing<String>)> = ["A", "B", "C", "D"]
+            .into_iter()
+            .map(|key| (key.to_string(), Zeroizing::new("v".to_string())))
+            .collect();
+        let existing = ["B", "C"].into_iter().map(String::from).collect();
+        assert_eq!(
+            set_summary("aws", &entries, &[true, true, false, true], &existing),
+            "Set 3 variables in aws (2 new, 1 updated, 1 unchanged)"
+        );
+        assert_eq!(
+            set_summary("aws", &entries[..1], &[true], &existing),
+            "Set 1 variable in aws (1 new)"
+        );
+    }
+
+    #[test]
+    fn rename_moves_variables_and_refuses_to_clobber() {
+        let mut backend = MockBackend::with(&[
+            ("staging", "A", "1"),
+            ("staging", "B", "2"),
+            ("stg", "A", "0"),
+        ]);
+        let err = rename_namespace(&mut backend, "staging", "stg", false).unwrap_err();
+        assert!(
+            err.starts_with("namespace `stg` already has variables"),
+            "{err}"
+        );
+        assert_eq!(backend.get("staging", "A"), Some("1"));
+
+        rename_namespace(&mut backend, "staging", "stg", true).unwrap();
+        assert_eq!(backend.get("stg", "A"), Some("1"));
+        assert_eq!(backend.get("stg", "B"), Some("2"));
+        assert!(backend.list_keys("staging").unwrap().is_empty());
+
+        let err = rename_namespace(&mut backend, "staging", "prod", false).unwrap_err();
+        assert_eq!(err, "namespace `staging` not defined");
+    }
+
+    #[test]
+    fn list_env_qualified_names_the_winning_namespace() {
+        let backend = MockBackend::with(&[
+            ("base", "A", "1"),
+            ("base", "B", "2"),
+            ("staging", "A", "3"),
+        ]);
+        let opts = ListOptions {
+            format: ListFormat::Env,
+            qualified: true,
+            ..list_options()
+        };
+        let mut out = Vec::new();
+        list_env(&backend, "base,staging", &opts, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "base@A=1\nbase@B=2\n");
+        let opts = ListOptions {
+            precedence: Precedence::Last,
+            ..opts
+        };
+        let mut out = Vec::new();
+        list_env(&backend, "base,staging", &opts, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "staging@A=3\nbase@B=2\n");
+    }
+
+    #[test]
+    fn machine_format_frames_every_field_with_nul() {
+        let backend =
+            MockBackend::with(&[("app", "B", "x=1\ny"), ("app", "A", ""), ("ops", "C", "z")]);
+        let opts = ListOptions {
+            format: ListFormat::Machine,
+            show_value: true,
+            ..list_options()
+        };
+        let mut out = Vec::new();
+        assert!(!list_machine(&backend, Some("app"), &opts, &mut out).unwrap());
+        assert_eq!(out, b"A\0\0B\0x=1\ny\0");
+        let mut out = Vec::new();
+        list_machine(&backend, None, &opts, &mut out).unwrap();
+        assert_eq!(out, b"app\0ops\0");
+
+        let backend = MockBackend::with(&[("app", "NUL", "a\0b")]);
+        let err = list_machine(&backend, Some("app"), &opts, &mut Vec::new()).unwrap_err();
+        assert_eq!(err, "The value of NUL contains a NUL byte");
+    }
+
+    #[test]
+    fn allowlist_limits_injection_and_is_never_injected() {
+        let backend = MockBackend::with(&[
+            ("app", "TOKEN", "t"),
+            ("app", "NOTES", "storage only"),
+            ("app", EXEC_ALLOWLIST_KEY, "TOKEN, MISSING"),
+            ("base", "REGION", "eu"),
+        ]);
+        let keys = |respect| {
+            let env = collect_env(&backend, "base,app", false, Precedence::First, respect).unwrap();
+            let mut keys: Vec<String> = env.pairs.into_iter().map(|(key, _)| key).collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(false), ["NOTES", "REGION", "TOKEN"]);
+        assert_eq!(keys(true), ["REGION", "TOKEN"]);
+
+        let mut out = Vec::new();
+        list_values(&backend, "app", &list_options(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "NOTES\nTOKEN\n");
+    }
+
+    #[test]
+    fn export_single_quotes_values() {
+        let backend =
+            MockBackend::with(&[("app", "QUOTE", "it's a $HOME"), ("app", "PLAIN", "x y")]);
+        let opts = ListOptions {
+            format: ListFormat::Export,
+            ..list_options()
+        };
+        let mut out = Vec::new();
+        list_env(&backend, "app", &opts, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "export PLAIN='x y'\nexport QUOTE='it'\\''s a $HOME'\n"
+        );
+    }
+
+    #[test]
+    fn list_namespaces_only_empty() {
+        let backend = MockBackend::with(&[("aws", "A", "1")])
+            .with_empty("old")
+            .with_empty("team/tmp");
+        let opts = ListOptions {
+            only_empty: true,
+            ..list_options()
+        };
+        let mut out = Vec::new();
+        list_namespaces(&backend, &opts, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "old\nteam/tmp\n");
+
+        let opts = ListOptions {
+            format: ListFormat::Json,
+            ..opts
+        };
+        let mut out = Vec::new();
+        list_namespaces(&backend, &opts, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["namespaces"], serde_json::json!(["old", "team/tmp"]));
+        assert!(json.get("counts").is_none());
+    }
+
+    #[test]
+    fn list_reports_empty_namespaces() {
+        let backend = MockBackend::with(&[("aws", "A", "1")]);
+        assert_eq!(
+            list(&backend, "gcp", &list_options()),
+            (true, String::new())
+        );
+    }
+
+    #[test]
+    fn list_json_includes_schema_version() {
+        let backend = MockBackend::with(&[("aws", "A", "1")]);
+        let opts = ListOptions {
+            format: ListFormat::Json,
+            show_value: true,
+            ..list_options()
+        };
+        let (_, out) = list(&backend, "aws", &opts);
+        let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(json["schema_version"], LIST_JSON_SCHEMA_VERSION);
+        assert_eq!(json["namespace"], "aws");
+        assert_eq!(json["secrets"][0]["key"], "A");
+        assert_eq!(json["secrets"][0]["value"], "1");
+    }
+
+    #[test]
+    fn csv_listings_import_back_unchanged() {
+        let backend = MockBackend::with(&[
+            ("app", "DSN", "host=db,port=5432"),
+            ("app", "CERT", "line 1\nline 2\n"),
+            ("other", "TOKEN", "t"),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.csv");
+        let mut out = Vec::new();
+        list_csv(&backend, None, &list_options(), &mut out).unwrap();
+        std::fs::write(&path, out).unwrap();
+
+        let entries = import::parse_csv(&path, "app").unwrap();
+        let rows: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|e| (e.key.as_str(), e.value.as_str()))
+            .collect();
+        assert_eq!(
+            rows,
+            [("CERT", "line 1\nline 2\n"), ("DSN", "host=db,port=5432")]
+        );
+    }
+
+    #[test]
+    fn namespace_list_json_includes_schema_version() {
+        let backend = MockBackend::with(&[("aws-dev", "A", "1"), ("aws-prod", "B", "2")]);
+        let json = |opts: &ListOptions| {
+            let mut out = Vec::new();
+            list_namespaces(&backend, opts, &mut out).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&out).unwrap()
+        };
+        let opts = ListOptions {
+            format: ListFormat::Json,
+            ..list_options()
+        };
+        assert_eq!(
+            json(&opts),
+            serde_json::json!({
+                "schema_version": LIST_JSON_SCHEMA_VERSION,
+                "namespaces": ["aws-dev", "aws-prod"],
+            })
+        );
+
+        let opts = ListOptions {
+            with_counts: true,
+            group_by: Some("-".to_string()),
+            ..opts
+        };
+        let json = json(&opts);
+        assert_eq!(json["schema_version"], LIST_JSON_SCHEMA_VERSION);
+        assert_eq!(
+            json["counts"],
+            serde_json::json!({"aws-dev": 1, "aws-prod": 1})
+        );
+        assert_eq!(json["groups"], serde_json::json!({"aws": ["dev", "prod"]}));
+    }
+
+    #[test]
+    fn list_matches_namespaces_ignoring_case() {
+        let backend = MockBackend::with(&[("AWS", "A", "1")]);
+        let opts = ListOptions {
+            ignore_case: true,
+            ..list_options()
+        };
+        assert_eq!(list(&backend, "aws", &opts), (false, "A\n".to_string()));
+    }
+
+    #[test]
+    fn unset_removes_only_named_keys() {
+        let mut backend = MockBackend::with(&[("aws", "A", "1"), ("aws", "B", "2")]);
+        unset_values(&mut backend, "aws", &keys(&["A", "MISSING"]), false, true).unwrap();
+        assert_eq!(backend.get("aws", "A"), None);
+        assert_eq!(backend.get("aws", "B"), Some("2"));
+        assert_eq!(backend.flushes, 1);
+    }
+
+    #[test]
+    fn unset_dry_run_removes_nothing() {
+        let mut backend = MockBackend::with(&[("aws", "A", "1")]);
+        unset_values(&mut backend, "aws", &keys(&["A"]), true, true).unwrap();
+        assert_eq!(backend.get("aws", "A"), Some("1"));
+    }
+
+    fn collected(env: &CollectedEnv) -> Vec<(&str, &str)> {
+        let mut pairs: Vec<_> = env
+            .pairs
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn exec_env_follows_precedence() {
+        let backend = MockBackend::with(&[
+            ("base", "REGION", "eu"),
+            ("base", "USER", "ci"),
+            ("prod", "REGION", "us"),
+        ]);
+        let first = collect_env(&backend, "base,prod", false, Precedence::First, false).unwrap();
+        assert_eq!(collected(&first), [("REGION", "eu"), ("USER", "ci")]);
+        assert_eq!(first.sources["REGION"], ["base", "prod"]);
+        let last = collect_env(&backend, "base,prod", false, Precedence::Last, false).unwrap();
+        assert_eq!(collected(&last), [("REGION", "us"), ("USER", "ci")]);
+    }
+
+    #[test]
+    fn exec_env_applies_set_env_overrides() {
+        let backend = MockBackend::with(&[("aws", "REGION", "eu")]);
+        let mut env = collect_env(&backend, "aws", false, Precedence::First, false).unwrap();
+        let overrides = [
+            ("REGION".to_string(), "us".to_string()),
+            ("DEBUG".to_string(), "1".to_string()),
+        ];
+        let overridden = apply_set_env(&mut env.pairs, &overrides);
+        assert_eq!(collected(&env), [("DEBUG", "1"), ("REGION", "us")]);
+        assert_eq!(overridden.len(), 2);
+    }
+
+    #[test]
+    fn exec_env_stays_out_of_envchain_environment() {
+        let opts = ExecOptions {
+            ignore_case: false,
+            update_wslenv: false,
+            strict: false,
+            precedence: Precedence::First,
+            verbose: false,
+            timings: false,
+            started: Instant::now(),
+            pre_exec: None,
+            post_exec: None,
+            retry_exec: 0,
+            retry_exec_delay: Duration::ZERO,
+            keep_existing: false,
+            inherit: false,
+            respect_allowlist: false,
+            set_env: Vec::new(),
+            chdir: None,
+            setuid: None,
+            setgid: None,
+            #[cfg(unix)]
+            no_env_exposure: false,
+            #[cfg(unix)]
+            stdin_key: None,
+        };
+        let backend = MockBackend::with(&[("test", "ENVCHAIN_TEST_SECRET", "s3cret")]);
+        let env = collect_env(&backend, "test", false, Precedence::First, false).unwrap();
+        let mut command = Command::new("true");
+        child_env(env.pairs, &keys(&["ENVCHAIN_TEST_SECRET"]), &opts)
+            .unwrap()
+            .apply(&mut command);
+        let injected: Vec<_> = command.get_envs().collect();
+        assert_eq!(
+            injected,
+            [(
+                std::ffi::OsStr::new("ENVCHAIN_TEST_SECRET"),
+                Some(std::ffi::OsStr::new("s3cret"))
+            )]
+        );
+        assert!(env::vars().all(|(key, value)| key != "ENVCHAIN_TEST_SECRET" && value != "s3cret"));
+    }
+
+    #[test]
+    fn exec_env_reports_backend_errors() {
+        let backend = MockBackend::failing("keyring unavailable");
+        assert!(collect_env(&backend, "aws", false, Precedence::First, false).is_err());
+    }
+
+    #[test]
+    fn utf8_value_names_the_first_bad_byte() {
+        let text = utf8_value(Zeroizing::new("héllo".as_bytes().to_vec()), "stdin").unwrap();
+        assert_eq!(text.as_str(), "héllo");
+
+        let err = utf8_value(
+            Zeroizing::new(b"ok\xff\xfe".to_vec()),
+            "Value read from fd 3",
+        )
+        .unwrap_err();
+        assert!(err.starts_with("Value read from fd 3 is not valid UTF-8 (byte 0xff at offset 2)"));
+    }
 }
@@ -0,0 +1,75 @@
+//! Per-namespace command allowlists for exec mode.
+//!
+//! Policies live in `~/.config/envchain/policy.toml`, one table per
+//! namespace:
+//!
+//! ```toml
+//! [aws]
+//! allowed_commands = ["aws", "terraform"]
+//! ```
+//!
+//! A namespace without a table may be used with any command.  This is a
+//! guard against mistakes, not a security boundary: anyone who can run
+//! envchain as the same user can edit the file or read the secrets another
+//! way.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Policy {
+    /// Basenames of the commands the namespace's secrets may be passed to
+    allowed_commands: Vec<String>,
+}
+
+fn policy_path() -> Result<PathBuf, String> {
+    Ok(dirs::config_dir()
+        .ok_or("Could not determine config directory")?
+        .join("envchain")
+        .join("policy.toml"))
+}
+
+/// Fail unless every namespace in `namespaces` that has a policy allows
+/// `command`, compared by basename.  Without a policy file nothing is
+/// restricted.
+pub fn check_command(namespaces: &[&str], command: &str) -> Result<(), String> {
+    let path = policy_path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(format!(
+                "Failed to read policy file {}: {e}",
+                path.display()
+            ));
+        }
+    };
+    let policies: HashMap<String, Policy> = toml::from_str(&content)
+        .map_err(|e| format!("Failed to parse policy file {}: {e}", path.display()))?;
+
+    let basename = Path::new(command)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(command);
+    for namespace in namespaces {
+        let Some(policy) = policies.get(*namespace) else {
+            continue;
+        };
+        if !policy.allowed_commands.iter().any(|c| c == basename) {
+            let allowed = if policy.allowed_commands.is_empty() {
+                "no commands".to_string()
+            } else {
+                format!("only {}", policy.allowed_commands.join(", "))
+            };
+            return Err(format!(
+                "`{basename}` may not be run with namespace `{namespace}`: {} allows {allowed}",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Named presets of backend, age identity and namespace.
+//!
+//! Profiles live in `~/.config/envchain/profiles.toml`, one table per profile:
+//!
+//! ```toml
+//! [work]
+//! backend = "age"
+//! age_identity = "~/.ssh/work_ed25519"
+//! namespace = "work"
+//! ```
+//!
+//! A profile only supplies defaults: `ENVCHAIN_*` environment variables
+//! override it, and command-line flags override both.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Backend type, as accepted by `--backend`
+    pub backend: Option<String>,
+    /// Path to the age identity file
+    pub age_identity: Option<PathBuf>,
+    /// Namespace(s) used in exec mode
+    pub namespace: Option<String>,
+}
+
+fn profiles_path() -> Result<PathBuf, String> {
+    Ok(dirs::config_dir()
+        .ok_or("Could not determine config directory")?
+        .join("envchain")
+        .join("profiles.toml"))
+}
+
+/// Expand a leading `~/` to the user's home directory.
+fn expand_home(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path,
+    }
+}
+
+/// Load the profile called `name` from `profiles.toml`.
+pub fn load(name: &str) -> Result<Profile, String> {
+    let path = profiles_path()?;
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read profiles file {}: {e}", path.display()))?;
+    let mut profiles: HashMap<String, Profile> = toml::from_str(&content)
+        .map_err(|e| format!("Failed to parse profiles file {}: {e}", path.display()))?;
+
+    let mut profile = profiles.remove(name).ok_or_else(|| {
+        let mut known: Vec<_> = profiles.keys().cloned().collect();
+        known.sort();
+        format!(
+            "Unknown profile `{name}` (defined in {}: {})",
+            path.display(),
+            if known.is_empty() {
+                "none".to_string()
+            } else {
+                known.join(", ")
+            }
+        )
+    })?;
+    profile.age_identity = profile.age_identity.map(expand_home);
+    Ok(profile)
+}
@@ -0,0 +1,68 @@
+//! `render`: fill `{{KEY}}` placeholders in a template with secrets.
+//!
+//! Deliberately not a templating engine: a placeholder is a variable name
+//! between double braces, optionally padded with spaces.  Other `{{...}}`
+//! sequences, such as another tool's template syntax, are copied as they
+//! are.
+
+use regex::{Captures, Regex};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Substitute the placeholders in `template` from `values`.  Placeholders
+/// naming no value are an error listing them all, or with `allow_missing`
+/// are left in the output unchanged.
+pub fn render(
+    template: &str,
+    values: &HashMap<&str, &str>,
+    allow_missing: bool,
+) -> Result<Zeroizing<String>, String> {
+    let placeholder =
+        Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").expect("placeholder regex is valid");
+    let mut missing = BTreeSet::new();
+    let rendered = placeholder.replace_all(template, |caps: &Captures| {
+        let key = &caps[1];
+        match values.get(key) {
+            Some(value) => value.to_string(),
+            None => {
+                missing.insert(key.to_string());
+                caps[0].to_string()
+            }
+        }
+    });
+    let rendered = Zeroizing::new(rendered.into_owned());
+    if !missing.is_empty() && !allow_missing {
+        return Err(format!(
+            "Template uses variables that are not set: {} (pass --allow-missing to leave them as they are)",
+            missing.into_iter().collect::<Vec<_>>().join(", ")
+        ));
+    }
+    Ok(rendered)
+}
+
+/// Write `content` to `path`, readable only by the owner.  An existing file
+/// is truncated and has its permissions tightened before anything is
+/// written to it.
+pub fn write_private(path: &Path, content: &str) -> Result<(), String> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Failed to set permissions on {}: {e}", path.display()))?;
+    }
+    file.write_all(content.as_bytes())
+        .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+}
@@ -0,0 +1,137 @@
+//! `watch`: run a command and restart it whenever the secrets file changes.
+//!
+//! Only backends kept in a single local file can be watched.  The file's
+//! directory is watched rather than the file itself, because a save
+//! replaces the file by renaming a new one over it.
+
+use notify::{RecursiveMode, Watcher};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// Changes closer together than this are handled as one.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+/// How often to check whether the command has exited on its own.
+const POLL: Duration = Duration::from_millis(200);
+/// How long a command may take to exit after SIGTERM before it is killed.
+#[cfg(unix)]
+const GRACE: Duration = Duration::from_secs(5);
+
+/// Run `cmd` with the variables `load` returns, and restart it with fresh
+/// ones each time `path` changes.  A failing `load`, or one returning the
+/// same variables as before, leaves the running command alone.  If the
+/// command exits by itself it is started again on the next change.  Runs
+/// until interrupted.
+pub fn supervise(
+    path: &Path,
+    mut load: impl FnMut() -> Result<Vec<(String, Zeroizing<String>)>, String>,
+    cmd: &str,
+    args: &[String],
+) -> Result<(), String> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| format!("Cannot watch {}: no parent directory", path.display()))?;
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading the file to reload it is an access event; ignore those.
+        if let Ok(event) = event
+            && !event.kind.is_access()
+            && event
+                .paths
+                .iter()
+                .any(|p| p.file_name() == file_name.as_deref())
+        {
+            let _ = tx.send(());
+        }
+    })
+    .map_err(|e| format!("Failed to start watching {}: {e}", dir.display()))?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("Failed to watch {}: {e}", dir.display()))?;
+
+    // Sorted, so that a reload can be compared with what is running.
+    let mut load = move || {
+        let mut vars = load()?;
+        vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok::<_, String>(vars)
+    };
+    let mut current = load()?;
+    let mut child = Some(spawn(&current, cmd, args)?);
+    loop {
+        match rx.recv_timeout(POLL) {
+            Ok(()) => {
+                while rx.recv_timeout(DEBOUNCE).is_ok() {}
+                let vars = match load() {
+                    Ok(vars) => vars,
+                    Err(e) => {
+                        eprintln!("envchain: secrets changed but could not be read: {e}");
+                        continue;
+                    }
+                };
+                if vars == current && child.is_some() {
+                    continue;
+                }
+                eprintln!("envchain: secrets changed; restarting {cmd}");
+                if let Some(running) = child.take() {
+                    stop(running)?;
+                }
+                child = Some(spawn(&vars, cmd, args)?);
+                current = vars;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(running) = &mut child
+                    && let Some(status) = running
+                        .try_wait()
+                        .map_err(|e| format!("Failed to wait for {cmd}: {e}"))?
+                {
+                    eprintln!(
+                        "envchain: {cmd} exited ({status}); waiting for the secrets to change"
+                    );
+                    child = None;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(format!("Stopped watching {}", path.display()));
+            }
+        }
+    }
+}
+
+fn spawn(
+    vars: &[(String, Zeroizing<String>)],
+    cmd: &str,
+    args: &[String],
+) -> Result<Child, String> {
+    Command::new(cmd)
+        .args(args)
+        .envs(vars.iter().map(|(key, value)| (key, value.as_str())))
+        .spawn()
+        .map_err(|e| format!("Failed to run {cmd}: {e}"))
+}
+
+/// Ask `child` to exit with SIGTERM, killing it if it is still running
+/// after `GRACE`.
+fn stop(mut child: Child) -> Result<(), String> {
+    #[cfg(unix)]
+    {
+        let pid = child.id() as libc::pid_t;
+        // SAFETY: kill only sends a signal; the child has not been reaped,
+        // so its pid still refers to it.
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+        let deadline = std::time::Instant::now() + GRACE;
+        while std::time::Instant::now() < deadline {
+            match child.try_wait() {
+                Ok(Some(_)) => return Ok(()),
+                Ok(None) => std::thread::sleep(POLL),
+                Err(e) => return Err(format!("Failed to wait for the command: {e}")),
+            }
+        }
+    }
+    child
+        .kill()
+        .and_then(|()| child.wait().map(drop))
+        .map_err(|e| format!("Failed to stop the command: {e}"))
+}
@@ -0,0 +1,55 @@
+//! Piping a value into `set --noecho`.
+#![cfg(all(unix, feature = "age-backend"))]
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+/// envchain with a private age store under `home`.
+fn envchain(home: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_envchain"));
+    command
+        .env_clear()
+        .env("HOME", home)
+        .env("ENVCHAIN_BACKEND", "age");
+    command
+}
+
+/// Wait for `child`, killing it if it has not exited within `timeout`.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> ExitStatus {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if start.elapsed() > timeout {
+            child.kill().unwrap();
+            panic!("envchain did not exit within {timeout:?}");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn noecho_reads_piped_stdin() {
+    let home = tempfile::tempdir().unwrap();
+    let mut set = envchain(home.path())
+        .args(["--auto-generate", "set", "--noecho", "myns", "SECRET"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Reading the terminal instead of the pipe would wait for input that
+    // never comes, until the timeout kills it.
+    set.stdin.take().unwrap().write_all(b"hunter2\n").unwrap();
+    assert!(wait_with_timeout(&mut set, Duration::from_secs(30)).success());
+
+    let output = envchain(home.path())
+        .args(["printenv", "myns", "SECRET"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hunter2\n");
+}
@@ -0,0 +1,74 @@
+//! Running a script whose `#!` line starts envchain.
+#![cfg(all(unix, feature = "age-backend"))]
+
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// envchain with a private age store under `home`.
+fn envchain(home: &Path) -> Command {
+    let exe = Path::new(env!("CARGO_BIN_EXE_envchain"));
+    let path = std::env::join_paths(std::iter::once(exe.parent().unwrap().to_path_buf()).chain(
+        std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default()),
+    ))
+    .unwrap();
+    let mut command = Command::new(exe);
+    command
+        .env_clear()
+        .env("PATH", path)
+        .env("HOME", home)
+        .env("ENVCHAIN_BACKEND", "age");
+    command
+}
+
+#[test]
+fn shebang_loads_the_namespace() {
+    let home = tempfile::tempdir().unwrap();
+    let mut set = envchain(home.path())
+        .args([
+            "--auto-generate",
+            "set",
+            "--value-from-fd",
+            "0",
+            "myns",
+            "SECRET",
+        ])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    set.stdin.take().unwrap().write_all(b"hunter2\n").unwrap();
+    assert!(set.wait().unwrap().success());
+
+    let script = home.path().join("script.sh");
+    std::fs::write(
+        &script,
+        "#!/usr/bin/env -S envchain myns\necho \"$SECRET $1 $2\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    // What the kernel runs for `script.sh arg1 --flag`, through env -S.
+    let mut run = envchain(home.path());
+    run.args(["myns", script.to_str().unwrap(), "arg1", "--flag"]);
+    let output = run.output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "hunter2 arg1 --flag\n"
+    );
+
+    // And the script run directly, with envchain found on PATH.
+    let mut direct = Command::new(&script);
+    direct.arg("arg1").arg("--flag");
+    direct
+        .env_clear()
+        .envs(run.get_envs().filter_map(|(k, v)| Some((k, v?))));
+    let output = direct.output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "hunter2 arg1 --flag\n"
+    );
+}